@@ -1,10 +1,16 @@
+import json
 import subprocess
 import pathlib
+from functools import lru_cache
+from typing import FrozenSet, List, Optional
+
+from aiohttp import ClientSession
 
 from fastapi import APIRouter, Body, Depends, Path, HTTPException, Query
 
 
-from app.dependencies import get_example_service, get_example_repo
+from app.dependencies import get_example_service, get_example_repo, get_client_session
+from app.settings import settings
 
 from app.usecases.schemas.collatz import (
     CollatzPostRequestBody,
@@ -18,6 +24,86 @@ from app.usecases.interfaces.services.example_manager import IExampleManager
 collatz_router = APIRouter(tags=["Collatz Data"])
 
 
+def image_id_hex(image_id: List[int]) -> Optional[str]:
+    """Renders an image ID given as eight u32 words as hex, as the verifier and allowlists do."""
+
+    if len(image_id) != 8 or not all(0 <= word < 2**32 for word in image_id):
+        return None
+    return b"".join(word.to_bytes(4, "little") for word in image_id).hex()
+
+
+@lru_cache
+def load_allowlist(path: str) -> FrozenSet[str]:
+    """Reads the hex image IDs listed in an allowlist, skipping comments."""
+
+    image_ids = set()
+    with open(path) as f:
+        for line in f:
+            words = line.split()
+            if words and not words[0].startswith("#"):
+                image_ids.add(words[0].lower())
+    return frozenset(image_ids)
+
+
+def check_image_id(body: CollatzPostRequestBody):
+    """Rejects uploads for guests other than the published builds.
+
+    The image ID comes from the client, so verifying against it alone only shows that some
+    guest ran.
+    """
+
+    if not settings.image_allowlist:
+        raise HTTPException(status_code=503, detail="No accepted image IDs are configured.")
+    image_id = image_id_hex(body.image_id)
+    if image_id is None:
+        raise HTTPException(status_code=400, detail="Invalid proof: malformed image ID.")
+    if image_id not in load_allowlist(settings.image_allowlist):
+        raise HTTPException(
+            status_code=400, detail=f"Invalid proof: image ID {image_id} is not an accepted build."
+        )
+
+
+async def verify_with_server(body: CollatzPostRequestBody, session: ClientSession) -> dict:
+    """Verifies the upload with a running verifier server, returning its report."""
+
+    upload = {
+        "input_value": body.claimed_input_value,
+        "proof": body.proof,
+        "image_id": body.image_id,
+    }
+    async with session.post(f"{settings.verifier_url}/verify", json=upload) as res:
+        if res.status not in (200, 422):
+            raise HTTPException(status_code=400, detail=f"Invalid proof: {await res.text()}")
+        return await res.json()
+
+
+def verify_with_cargo(body: CollatzPostRequestBody) -> dict:
+    """Verifies the upload by running the verifier binary, returning its report."""
+
+    path = pathlib.Path("receipts")
+    path.mkdir(exist_ok=True)
+    fname = path / pathlib.Path(f"{body.claimed_input_value}_receipt").with_suffix(".dat")
+    fname.touch()
+    with open(fname, 'wb') as f:
+        f.write(bytes(body.proof))
+
+    res = subprocess.run(
+        f"cargo run -- '{body.image_id}' {fname.absolute()} --input-value {body.claimed_input_value}",
+        shell=True, capture_output=True, text=True,
+        cwd='../verifier',
+    )
+
+    # the verifier prints a JSON report as the last line of stdout, and
+    # exits with a non-zero code if the receipt was rejected
+    try:
+        report = json.loads(res.stdout.strip().splitlines()[-1])
+    except (IndexError, json.JSONDecodeError) as e:
+        raise HTTPException(status_code=500, detail="Verifier did not produce a report.") from e
+    if res.returncode != 0:
+        report["verified"] = False
+    return report
+
+
 @collatz_router.post(
     "/actions/create",
     status_code=201,
@@ -32,34 +118,25 @@ async def add_sequence(
 
     if body.proof:
 
-        path = pathlib.Path("receipts")
-        path.mkdir(exist_ok=True)
-        fname = path / pathlib.Path(f"{body.input_value}_receipt").with_suffix(".dat")
-        fname.touch()
-        with open(fname, 'wb') as f:
-            f.write(bytes(body.proof))
-
-        # 1. Check that the proof is valid
-        print(body.output_sequence)
-        res = subprocess.run(
-            f"cargo run -- '{body.image_id}' {fname.absolute()}",
-            shell=True, check=True,
-            cwd='../verifier',
-        )
-        try:
-            res.check_returncode()
-        except subprocess.CalledProcessError as e:
-            raise HTTPException(status_code=400, detail="Invalid proof.") from e
+        # 1. Check that the proof is valid, for a published build of the guest
+        check_image_id(body)
+        if settings.verifier_url:
+            report = await verify_with_server(body, await get_client_session())
+        else:
+            report = verify_with_cargo(body)
+        if not report["verified"]:
+            raise HTTPException(status_code=400, detail=f"Invalid proof: {report['error']['message']}")
+
+        # replace the sequence with the one from the verified journal
+        journal = report["journal"]
+        if journal["kind"] != "trajectory" or journal["sequence"] is None:
+            raise HTTPException(status_code=400, detail="Proof does not contain a sequence.")
+        body.output_sequence = [int(value) for value in journal["sequence"]]
 
     else:
         # We by-pass the prover initially, just to fill up the DB for visualization sake
         pass
 
-    # replace the sequence with the one from the journal
-    # TODO: fix the verifier return of the sequence
-    # print(res.stdout)
-    # body.output_sequence = res.stdout
-
     # 2. If the proof is valid, insert the data into the database
     try:
         stored_data = await collatz_repo.create(data=body)
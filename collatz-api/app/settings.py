@@ -1,4 +1,5 @@
 from os import path
+from typing import Optional
 
 from pydantic import BaseSettings
 
@@ -20,6 +21,14 @@ class Settings(BaseSettings):
     # Database Settings
     db_url: str
 
+    # Verifier Settings
+    # URL of a running `verifier serve`; without one each upload spawns the
+    # verifier with `cargo run`
+    verifier_url: Optional[str] = None
+    # Allowlist of the image IDs of published guest builds, one hex ID per
+    # line; proofs for any other image ID are rejected
+    image_allowlist: Optional[str] = None
+
     class Config:
         env_file = DOTENV_FILE
 
@@ -1,6 +1,6 @@
 import json
 from datetime import datetime
-from typing import List, Dict
+from typing import List, Dict, Optional
 from pydantic import BaseModel, Field, validator
 
 
@@ -36,6 +36,17 @@ class CollatzPostRequestBody(CollatzBase):
         description="A binary representing the hash of the program that ran the collatz code.",
         example=[837, 12, 37827],
     )
+    claimed_input_value: int = Field(
+        ...,
+        alias="input_value",
+        description="The starting value the proof claims to be for; checked against the journal.",
+        example=4,
+    )
+    contributor: Optional[str] = Field(
+        None,
+        description="Identifier of the contributor committed in the journal.",
+        example="alice",
+    )
 
 
 class CollatzDataInDb(CollatzBase):
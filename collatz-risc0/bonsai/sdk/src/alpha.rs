@@ -12,12 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{fs::File, path::Path};
-
-use anyhow::{bail, Context, Result};
-use reqwest::{blocking::Client as BlockingClient, header};
-
-use self::responses::{CreateSessRes, ProofReq, SessionStatusRes, UploadRes};
+use std::{
+    fmt,
+    fs::File,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use reqwest::{
+    blocking::{Client as BlockingClient, Response},
+    header, StatusCode,
+};
+
+use self::responses::{
+    CreateSessRes, ProofReq, SessionInfo, SessionStatus, SessionStatusRes, UploadRes,
+};
 
 /// Collection of serialization object for the REST api
 pub mod responses {
@@ -48,19 +58,206 @@ pub mod responses {
         pub input: String,
     }
 
+    /// State of a proof Session
+    #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum SessionStatus {
+        /// The session is still executing or proving
+        Running,
+        /// The session finished and its receipt can be downloaded
+        Succeeded,
+        /// Execution or proving failed
+        Failed,
+        /// The session ran past the server's time limit
+        TimedOut,
+        /// The session was stopped, e.g. with [super::SessionId::stop]
+        Aborted,
+        /// A status this version of the SDK does not know about
+        #[serde(other)]
+        Unknown,
+    }
+
+    impl SessionStatus {
+        /// Whether the session has finished, successfully or not. An
+        /// [SessionStatus::Unknown] status is not taken to be final.
+        pub fn is_done(&self) -> bool {
+            matches!(
+                self,
+                SessionStatus::Succeeded
+                    | SessionStatus::Failed
+                    | SessionStatus::TimedOut
+                    | SessionStatus::Aborted
+            )
+        }
+    }
+
     /// Session Status response
-    #[derive(Deserialize, Serialize)]
+    #[derive(Debug, Deserialize, Serialize)]
     pub struct SessionStatusRes {
         /// Current status
-        ///
-        /// values: [RUNNING | SUCCEEDED | FAILED | TIMED_OUT | ABORTED |
-        /// SUCCEEDED]
-        pub status: String,
+        pub status: SessionStatus,
         /// Final receipt download URL
         ///
-        /// If the status == 'SUCCEEDED' then this should be present
+        /// If the status == [SessionStatus::Succeeded] then this should be
+        /// present
         pub receipt_url: Option<String>,
     }
+
+    /// Entry of the session list response
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct SessionInfo {
+        /// Session UUID
+        pub uuid: String,
+        /// Current status
+        pub status: SessionStatus,
+    }
+}
+
+/// Errors returned by the [Client]
+#[derive(Debug)]
+pub enum SdkErr {
+    /// The API key was rejected (HTTP 401 or 403)
+    Unauthorized(String),
+    /// The account's quota or rate limit was exceeded (HTTP 429)
+    QuotaExceeded(String),
+    /// The server failed to handle the request (HTTP 5xx)
+    ServerError {
+        /// HTTP status code
+        status: u16,
+        /// Response body
+        body: String,
+    },
+    /// The server rejected the request for any other reason (HTTP 4xx)
+    BadRequest {
+        /// HTTP status code
+        status: u16,
+        /// Response body
+        body: String,
+    },
+    /// The request could not be sent, or its response could not be read or
+    /// deserialized
+    Http(reqwest::Error),
+    /// A file to upload could not be opened
+    Io(std::io::Error),
+    /// A required environment variable is not set
+    MissingEnvVar(&'static str),
+    /// The API key contains characters not allowed in a header
+    InvalidApiKey,
+    /// A session was still running when [PollConfig::timeout] elapsed
+    Timeout {
+        /// Session UUID
+        uuid: String,
+    },
+}
+
+impl SdkErr {
+    /// Map an unsuccessful HTTP response to an error
+    pub(crate) fn from_status(status: StatusCode, body: String) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => SdkErr::Unauthorized(body),
+            StatusCode::TOO_MANY_REQUESTS => SdkErr::QuotaExceeded(body),
+            status if status.is_server_error() => SdkErr::ServerError {
+                status: status.as_u16(),
+                body,
+            },
+            status => SdkErr::BadRequest {
+                status: status.as_u16(),
+                body,
+            },
+        }
+    }
+}
+
+impl fmt::Display for SdkErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SdkErr::Unauthorized(body) => write!(f, "Unauthorized: '{body}'"),
+            SdkErr::QuotaExceeded(body) => write!(f, "Quota exceeded: '{body}'"),
+            SdkErr::ServerError { status, body } => {
+                write!(f, "Request failed - server error {status}: '{body}'")
+            }
+            SdkErr::BadRequest { status, body } => {
+                write!(f, "Request rejected with {status}: '{body}'")
+            }
+            SdkErr::Http(err) => write!(f, "HTTP error: {err}"),
+            SdkErr::Io(err) => write!(f, "Unable to open supplied file: {err}"),
+            SdkErr::MissingEnvVar(var) => write!(f, "Missing {var} env var"),
+            SdkErr::InvalidApiKey => write!(f, "API key is not a valid header value"),
+            SdkErr::Timeout { uuid } => write!(f, "Session {uuid} did not finish in time"),
+        }
+    }
+}
+
+impl std::error::Error for SdkErr {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SdkErr::Http(err) => Some(err),
+            SdkErr::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for SdkErr {
+    fn from(err: reqwest::Error) -> Self {
+        SdkErr::Http(err)
+    }
+}
+
+impl From<std::io::Error> for SdkErr {
+    fn from(err: std::io::Error) -> Self {
+        SdkErr::Io(err)
+    }
+}
+
+/// How often to poll a session's status while waiting for it to finish
+#[derive(Clone, Debug)]
+pub struct PollConfig {
+    /// Delay after the first poll, which is made straight away. The delay
+    /// doubles after each further poll that finds the session still running
+    pub interval: Duration,
+    /// Upper bound on the delay between polls
+    pub max_interval: Duration,
+    /// Give up with [SdkErr::Timeout] after this long
+    pub timeout: Duration,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Delays between polls, following a [PollConfig]
+pub(crate) struct Backoff {
+    deadline: Instant,
+    interval: Duration,
+    max_interval: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(config: &PollConfig) -> Self {
+        Self {
+            deadline: Instant::now() + config.timeout,
+            interval: config.interval,
+            max_interval: config.max_interval,
+        }
+    }
+
+    /// The delay before the next poll, or [None] if it would pass the
+    /// deadline
+    pub(crate) fn next_delay(&mut self) -> Option<Duration> {
+        if Instant::now() + self.interval > self.deadline {
+            return None;
+        }
+        let delay = self.interval;
+        self.interval = (self.interval * 2).min(self.max_interval);
+        Some(delay)
+    }
 }
 
 /// Proof Session representation
@@ -77,20 +274,46 @@ impl SessionId {
     }
 
     /// Retries the current status of the Session
-    pub fn status(&self, client: &Client) -> Result<SessionStatusRes> {
+    pub fn status(&self, client: &Client) -> Result<SessionStatusRes, SdkErr> {
         let url = format!("{}/sessions/status/{}", client.url, self.uuid);
-        let res = client
-            .client
-            .get(url)
-            .send()
-            .context("Failed to GEt session status")?;
+        Ok(check(client.client.get(url).send()?)?.json()?)
+    }
+
+    /// Stop the Session if it is still running
+    pub fn stop(&self, client: &Client) -> Result<(), SdkErr> {
+        let url = format!("{}/sessions/stop/{}", client.url, self.uuid);
+        check(client.client.get(url).send()?)?;
+        Ok(())
+    }
 
-        if !res.status().is_success() {
-            let body = res.text()?;
-            bail!("Request failed - server error: '{body}'");
+    /// Fetch the guest's execution logs, i.e. what it wrote to stdout and
+    /// stderr
+    pub fn logs(&self, client: &Client) -> Result<String, SdkErr> {
+        let url = format!("{}/sessions/logs/{}", client.url, self.uuid);
+        Ok(check(client.client.get(url).send()?)?.text()?)
+    }
+
+    /// Poll the status of the Session until it is done, as told by
+    /// [SessionStatus::is_done]
+    ///
+    /// The first poll is made straight away, and later ones are spaced as set
+    /// by `config`
+    pub fn wait_for_completion(
+        &self,
+        client: &Client,
+        config: &PollConfig,
+    ) -> Result<SessionStatusRes, SdkErr> {
+        let mut backoff = Backoff::new(config);
+        loop {
+            let res = self.status(client)?;
+            if res.status.is_done() {
+                return Ok(res);
+            }
+            let delay = backoff.next_delay().ok_or_else(|| SdkErr::Timeout {
+                uuid: self.uuid.clone(),
+            })?;
+            thread::sleep(delay);
         }
-        res.json::<SessionStatusRes>()
-            .context("Failed to deserialize Session status result")
     }
 }
 
@@ -100,16 +323,39 @@ pub struct Client {
     pub(crate) client: BlockingClient,
 }
 
-/// Creates a [reqwest::Client] for internal connection pooling
-fn construct_req_client(api_key: &str) -> Result<BlockingClient> {
+/// Read the API URL and key from the BONSAI_API_URL and BONSAI_API_KEY
+/// environment variables
+pub(crate) fn env_parts() -> Result<(String, String), SdkErr> {
+    let api_url =
+        std::env::var("BONSAI_API_URL").map_err(|_| SdkErr::MissingEnvVar("BONSAI_API_URL"))?;
+    let api_key =
+        std::env::var("BONSAI_API_KEY").map_err(|_| SdkErr::MissingEnvVar("BONSAI_API_KEY"))?;
+    Ok((api_url, api_key))
+}
+
+/// Headers sent with every request
+pub(crate) fn default_headers(api_key: &str) -> Result<header::HeaderMap, SdkErr> {
     let mut headers = header::HeaderMap::new();
-    headers.insert("x-api-key", header::HeaderValue::from_str(api_key)?);
+    let api_key = header::HeaderValue::from_str(api_key).map_err(|_| SdkErr::InvalidApiKey)?;
+    headers.insert("x-api-key", api_key);
+    Ok(headers)
+}
 
-    BlockingClient::builder()
-        .default_headers(headers)
+/// Creates a [reqwest::Client] for internal connection pooling
+fn construct_req_client(api_key: &str) -> Result<BlockingClient, SdkErr> {
+    Ok(BlockingClient::builder()
+        .default_headers(default_headers(api_key)?)
         .pool_max_idle_per_host(0)
-        .build()
-        .context("Failed to build reqwest client")
+        .build()?)
+}
+
+/// Turn an unsuccessful response into an error
+fn check(res: Response) -> Result<Response, SdkErr> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    Err(SdkErr::from_status(status, res.text()?))
 }
 
 impl Client {
@@ -117,53 +363,29 @@ impl Client {
     ///
     /// Uses the BONSAI_API_URL and BONSAI_API_KEY environment variables to
     /// construct a client
-    pub fn from_env() -> Result<Self> {
-        let api_url = std::env::var("BONSAI_API_URL").context("Missing BONSAI_API_URL env var")?;
-        let api_key = std::env::var("BONSAI_API_KEY").context("Missing BONSAI_API_KEY env var")?;
-
-        let client = construct_req_client(&api_key)?;
-
-        Ok(Self {
-            url: api_url,
-            client,
-        })
+    pub fn from_env() -> Result<Self, SdkErr> {
+        let (url, key) = env_parts()?;
+        Self::from_parts(url, key)
     }
 
     /// Construct a [Client] from url + api key strings
-    pub fn from_parts(url: String, key: String) -> Result<Self> {
+    pub fn from_parts(url: String, key: String) -> Result<Self, SdkErr> {
         let client = construct_req_client(&key)?;
         Ok(Self { url, client })
     }
 
     /// Fetch a upload presigned url for a given route
-    fn get_upload_url(&self, route: &str) -> Result<UploadRes> {
+    fn get_upload_url(&self, route: &str) -> Result<UploadRes, SdkErr> {
         let res = self
             .client
             .get(format!("{}/{}/upload", self.url, route))
-            .send()
-            .context("Failed to fetch upload location")?;
-
-        if !res.status().is_success() {
-            let body = res.text()?;
-            bail!("Request failed - server error: '{body}'");
-        }
-
-        res.json::<UploadRes>()
-            .context("Failed to deserialize upload response")
+            .send()?;
+        Ok(check(res)?.json()?)
     }
 
     /// Upload body to a given URL
-    fn put_data<T: Into<reqwest::blocking::Body>>(&self, url: &str, body: T) -> Result<()> {
-        let res = self
-            .client
-            .put(url)
-            .body(body)
-            .send()
-            .context("Failed to PUT data to destination")?;
-        if !res.status().is_success() {
-            bail!("Failed to PUT to provided URL");
-        }
-
+    fn put_data<T: Into<reqwest::blocking::Body>>(&self, url: &str, body: T) -> Result<(), SdkErr> {
+        check(self.client.put(url).body(body).send()?)?;
         Ok(())
     }
 
@@ -174,7 +396,7 @@ impl Client {
     /// The image data can be either:
     /// * ELF file bytes
     /// * bincode encoded MemoryImage
-    pub fn upload_img(&self, buf: Vec<u8>) -> Result<String> {
+    pub fn upload_img(&self, buf: Vec<u8>) -> Result<String, SdkErr> {
         let upload_data = self.get_upload_url("images")?;
         self.put_data(&upload_data.url, buf)?;
         Ok(upload_data.uuid)
@@ -185,10 +407,10 @@ impl Client {
     /// The image data can be either:
     /// * ELF file bytes
     /// * bincode encoded MemoryImage
-    pub fn upload_img_file(&self, path: &Path) -> Result<String> {
+    pub fn upload_img_file(&self, path: &Path) -> Result<String, SdkErr> {
         let upload_data = self.get_upload_url("images")?;
 
-        let fd = File::open(path).context("Unable to open supplied image file")?;
+        let fd = File::open(path)?;
         self.put_data(&upload_data.url, fd)?;
 
         Ok(upload_data.uuid)
@@ -197,17 +419,17 @@ impl Client {
     // - /inputs
 
     /// Upload a input buffer to the /inputs/ route
-    pub fn upload_input(&self, buf: Vec<u8>) -> Result<String> {
+    pub fn upload_input(&self, buf: Vec<u8>) -> Result<String, SdkErr> {
         let upload_data = self.get_upload_url("inputs")?;
         self.put_data(&upload_data.url, buf)?;
         Ok(upload_data.uuid)
     }
 
     /// Upload a input file to the /inputs/ route
-    pub fn upload_input_file(&self, path: &Path) -> Result<String> {
+    pub fn upload_input_file(&self, path: &Path) -> Result<String, SdkErr> {
         let upload_data = self.get_upload_url("inputs")?;
 
-        let fd = File::open(path).context("Unable to open supplied image file")?;
+        let fd = File::open(path)?;
         self.put_data(&upload_data.url, fd)?;
 
         Ok(upload_data.uuid)
@@ -219,7 +441,7 @@ impl Client {
     ///
     /// Supply the img_id and input_id created from uploading those files in
     /// previous steps
-    pub fn create_session(&self, img_id: String, input_id: String) -> Result<SessionId> {
+    pub fn create_session(&self, img_id: String, input_id: String) -> Result<SessionId, SdkErr> {
         let url = format!("{}/sessions/create", self.url);
 
         let req = ProofReq {
@@ -227,46 +449,31 @@ impl Client {
             input: input_id,
         };
 
-        let res = self
-            .client
-            .post(url)
-            .json(&req)
-            .send()
-            .context("Failed to submit session/create POST request")?;
-
-        if !res.status().is_success() {
-            let body = res.text()?;
-            bail!("Request failed - server error: '{body}'");
-        }
-
-        let res: CreateSessRes = res
-            .json()
-            .context("Failed to deserialize Session status result")?;
+        let res: CreateSessRes = check(self.client.post(url).json(&req).send()?)?.json()?;
 
         Ok(SessionId::new(res.uuid))
     }
 
+    /// List the sessions created with this API key
+    pub fn list_sessions(&self) -> Result<Vec<SessionInfo>, SdkErr> {
+        let url = format!("{}/sessions", self.url);
+        Ok(check(self.client.get(url).send()?)?.json()?)
+    }
+
     // Utilities
 
     /// Download a given url to a buffer
     ///
     /// Useful to download a [SessionId] receipt_url
-    pub fn download(&self, url: &str) -> Result<Vec<u8>> {
-        let data = self
-            .client
-            .get(url)
-            .send()
-            .context("Failed to download url to buffer")?
-            .bytes()
-            .context("Failed to get raw bytes from download")?;
-
+    pub fn download(&self, url: &str) -> Result<Vec<u8>, SdkErr> {
+        let data = check(self.client.get(url).send()?)?.bytes()?;
         Ok(data.into())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use httpmock::prelude::*;
+    use httpmock::{prelude::*, Mock};
     use uuid::Uuid;
 
     use super::*;
@@ -383,7 +590,7 @@ mod tests {
         let uuid = Uuid::new_v4().to_string();
         let session_id = SessionId::new(uuid);
         let response = SessionStatusRes {
-            status: "RUNNING".to_string(),
+            status: SessionStatus::Running,
             receipt_url: None,
         };
 
@@ -405,4 +612,127 @@ mod tests {
 
         create_mock.assert();
     }
+
+    fn session_mock<'a>(server: &'a MockServer, route: &str, uuid: &str, body: &str) -> Mock<'a> {
+        let path = format!("/sessions/{route}/{uuid}");
+        let body = body.to_string();
+        server.mock(|when, then| {
+            when.method(GET).path(path).header("x-api-key", TEST_KEY);
+            then.status(200).body(body);
+        })
+    }
+
+    #[test]
+    fn session_stop_and_logs() {
+        let server = MockServer::start();
+        let session_id = SessionId::new(Uuid::new_v4().to_string());
+
+        let stop_mock = session_mock(&server, "stop", &session_id.uuid, "");
+        let logs_mock = session_mock(&server, "logs", &session_id.uuid, "Hello from the guest\n");
+
+        let server_url = format!("http://{}", server.address());
+        let client = super::Client::from_parts(server_url, TEST_KEY.to_string()).unwrap();
+
+        session_id.stop(&client).unwrap();
+        assert_eq!(session_id.logs(&client).unwrap(), "Hello from the guest\n");
+
+        stop_mock.assert();
+        logs_mock.assert();
+    }
+
+    #[test]
+    fn session_list() {
+        let server = MockServer::start();
+
+        let list_mock = server.mock(|when, then| {
+            when.method(GET).path("/sessions").header("x-api-key", TEST_KEY);
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"[{"uuid": "a", "status": "SUCCEEDED"}, {"uuid": "b", "status": "TIMED_OUT"}, {"uuid": "c", "status": "QUEUED"}]"#);
+        });
+
+        let server_url = format!("http://{}", server.address());
+        let client = super::Client::from_parts(server_url, TEST_KEY.to_string()).unwrap();
+
+        let sessions = client.list_sessions().unwrap();
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions[0].uuid, "a");
+        assert_eq!(sessions[0].status, SessionStatus::Succeeded);
+        assert_eq!(sessions[1].status, SessionStatus::TimedOut);
+        assert_eq!(sessions[2].status, SessionStatus::Unknown);
+        assert!(!sessions[2].status.is_done());
+
+        list_mock.assert();
+    }
+
+    #[test]
+    fn session_wait_for_completion() {
+        let server = MockServer::start();
+        let session_id = SessionId::new(Uuid::new_v4().to_string());
+        let receipt_url = format!("http://{}/receipts/{}", server.address(), session_id.uuid);
+        let body = format!(r#"{{"status": "SUCCEEDED", "receipt_url": "{receipt_url}"}}"#);
+        let status_mock = session_mock(&server, "status", &session_id.uuid, &body);
+
+        let server_url = format!("http://{}", server.address());
+        let client = super::Client::from_parts(server_url, TEST_KEY.to_string()).unwrap();
+
+        let res = session_id
+            .wait_for_completion(&client, &PollConfig::default())
+            .unwrap();
+        assert_eq!(res.status, SessionStatus::Succeeded);
+        assert_eq!(res.receipt_url, Some(receipt_url));
+        status_mock.assert();
+    }
+
+    #[test]
+    fn session_wait_timeout() {
+        let server = MockServer::start();
+        let session_id = SessionId::new(Uuid::new_v4().to_string());
+        let body = r#"{"status": "RUNNING", "receipt_url": null}"#;
+        let status_mock = session_mock(&server, "status", &session_id.uuid, body);
+
+        let server_url = format!("http://{}", server.address());
+        let client = super::Client::from_parts(server_url, TEST_KEY.to_string()).unwrap();
+
+        let config = PollConfig {
+            interval: Duration::from_millis(10),
+            max_interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+        };
+        let err = session_id
+            .wait_for_completion(&client, &config)
+            .unwrap_err();
+        assert!(matches!(err, SdkErr::Timeout { uuid } if uuid == session_id.uuid));
+        assert!(status_mock.hits() > 1);
+    }
+
+    #[test]
+    fn typed_errors() {
+        let server = MockServer::start();
+        let server_url = format!("http://{}", server.address());
+        let client = super::Client::from_parts(server_url, TEST_KEY.to_string()).unwrap();
+
+        for (status, route) in [(401, "a"), (429, "b"), (503, "c"), (404, "d")] {
+            server.mock(|when, then| {
+                when.method(GET).path(format!("/sessions/status/{route}"));
+                then.status(status).body("nope");
+            });
+        }
+
+        let status = |uuid: &str| {
+            SessionId::new(uuid.to_string())
+                .status(&client)
+                .unwrap_err()
+        };
+        assert!(matches!(status("a"), SdkErr::Unauthorized(body) if body == "nope"));
+        assert!(matches!(status("b"), SdkErr::QuotaExceeded(_)));
+        assert!(matches!(
+            status("c"),
+            SdkErr::ServerError { status: 503, .. }
+        ));
+        assert!(matches!(
+            status("d"),
+            SdkErr::BadRequest { status: 404, .. }
+        ));
+    }
 }
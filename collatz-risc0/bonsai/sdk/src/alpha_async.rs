@@ -0,0 +1,335 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Async counterpart of the [crate::alpha] client, for use inside a tokio
+//! runtime where the blocking client must not be used.
+
+use std::path::Path;
+
+use reqwest::{Client as AsyncClient, Response};
+
+use crate::alpha::{
+    default_headers, env_parts,
+    responses::{CreateSessRes, ProofReq, SessionInfo, SessionStatusRes, UploadRes},
+    Backoff, PollConfig, SdkErr, SessionId,
+};
+
+/// Represents an async client of the REST api
+pub struct Client {
+    pub(crate) url: String,
+    pub(crate) client: AsyncClient,
+}
+
+/// Turn an unsuccessful response into an error
+async fn check(res: Response) -> Result<Response, SdkErr> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    Err(SdkErr::from_status(status, res.text().await?))
+}
+
+impl Client {
+    /// Construct a [Client] from env var
+    ///
+    /// Uses the BONSAI_API_URL and BONSAI_API_KEY environment variables to
+    /// construct a client
+    pub fn from_env() -> Result<Self, SdkErr> {
+        let (url, key) = env_parts()?;
+        Self::from_parts(url, key)
+    }
+
+    /// Construct a [Client] from url + api key strings
+    pub fn from_parts(url: String, key: String) -> Result<Self, SdkErr> {
+        let client = AsyncClient::builder()
+            .default_headers(default_headers(&key)?)
+            .pool_max_idle_per_host(0)
+            .build()?;
+        Ok(Self { url, client })
+    }
+
+    /// Fetch a upload presigned url for a given route
+    async fn get_upload_url(&self, route: &str) -> Result<UploadRes, SdkErr> {
+        let res = self
+            .client
+            .get(format!("{}/{}/upload", self.url, route))
+            .send()
+            .await?;
+        Ok(check(res).await?.json().await?)
+    }
+
+    /// Upload body to a given URL
+    async fn put_data(&self, url: &str, body: Vec<u8>) -> Result<(), SdkErr> {
+        check(self.client.put(url).body(body).send().await?).await?;
+        Ok(())
+    }
+
+    /// Upload a buffer to the given route, returning its UUID
+    async fn upload(&self, route: &str, buf: Vec<u8>) -> Result<String, SdkErr> {
+        let upload_data = self.get_upload_url(route).await?;
+        self.put_data(&upload_data.url, buf).await?;
+        Ok(upload_data.uuid)
+    }
+
+    // - /images
+
+    /// Upload a image buffer to the /images/ route
+    ///
+    /// The image data can be either:
+    /// * ELF file bytes
+    /// * bincode encoded MemoryImage
+    pub async fn upload_img(&self, buf: Vec<u8>) -> Result<String, SdkErr> {
+        self.upload("images", buf).await
+    }
+
+    /// Upload a image file to the /images/ route
+    pub async fn upload_img_file(&self, path: &Path) -> Result<String, SdkErr> {
+        self.upload("images", tokio::fs::read(path).await?).await
+    }
+
+    // - /inputs
+
+    /// Upload a input buffer to the /inputs/ route
+    pub async fn upload_input(&self, buf: Vec<u8>) -> Result<String, SdkErr> {
+        self.upload("inputs", buf).await
+    }
+
+    /// Upload a input file to the /inputs/ route
+    pub async fn upload_input_file(&self, path: &Path) -> Result<String, SdkErr> {
+        self.upload("inputs", tokio::fs::read(path).await?).await
+    }
+
+    // - /sessions
+
+    /// Create a new proof request Session
+    ///
+    /// Supply the img_id and input_id created from uploading those files in
+    /// previous steps
+    pub async fn create_session(
+        &self,
+        img_id: String,
+        input_id: String,
+    ) -> Result<SessionId, SdkErr> {
+        let url = format!("{}/sessions/create", self.url);
+        let req = ProofReq {
+            img: img_id,
+            input: input_id,
+        };
+        let res = self.client.post(url).json(&req).send().await?;
+        let res: CreateSessRes = check(res).await?.json().await?;
+        Ok(SessionId::new(res.uuid))
+    }
+
+    /// List the sessions created with this API key
+    pub async fn list_sessions(&self) -> Result<Vec<SessionInfo>, SdkErr> {
+        let url = format!("{}/sessions", self.url);
+        Ok(check(self.client.get(url).send().await?)
+            .await?
+            .json()
+            .await?)
+    }
+
+    /// Retries the current status of a Session
+    pub async fn session_status(&self, session: &SessionId) -> Result<SessionStatusRes, SdkErr> {
+        let url = format!("{}/sessions/status/{}", self.url, session.uuid);
+        Ok(check(self.client.get(url).send().await?)
+            .await?
+            .json()
+            .await?)
+    }
+
+    /// Stop a Session if it is still running
+    pub async fn stop_session(&self, session: &SessionId) -> Result<(), SdkErr> {
+        let url = format!("{}/sessions/stop/{}", self.url, session.uuid);
+        check(self.client.get(url).send().await?).await?;
+        Ok(())
+    }
+
+    /// Fetch the guest's execution logs of a Session
+    pub async fn session_logs(&self, session: &SessionId) -> Result<String, SdkErr> {
+        let url = format!("{}/sessions/logs/{}", self.url, session.uuid);
+        Ok(check(self.client.get(url).send().await?)
+            .await?
+            .text()
+            .await?)
+    }
+
+    /// Poll the status of a Session until it is done, as told by
+    /// [crate::alpha::responses::SessionStatus::is_done]
+    ///
+    /// The first poll is made straight away, and later ones are spaced as set
+    /// by `config`
+    pub async fn wait_for_completion(
+        &self,
+        session: &SessionId,
+        config: &PollConfig,
+    ) -> Result<SessionStatusRes, SdkErr> {
+        let mut backoff = Backoff::new(config);
+        loop {
+            let res = self.session_status(session).await?;
+            if res.status.is_done() {
+                return Ok(res);
+            }
+            let delay = backoff.next_delay().ok_or_else(|| SdkErr::Timeout {
+                uuid: session.uuid.clone(),
+            })?;
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // Utilities
+
+    /// Download a given url to a buffer
+    ///
+    /// Useful to download a [SessionId] receipt_url
+    pub async fn download(&self, url: &str) -> Result<Vec<u8>, SdkErr> {
+        let data = check(self.client.get(url).send().await?)
+            .await?
+            .bytes()
+            .await?;
+        Ok(data.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use httpmock::prelude::*;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::alpha::responses::SessionStatus;
+
+    const TEST_KEY: &str = "TESTKEY";
+
+    #[tokio::test]
+    async fn prove_session() {
+        let server = MockServer::start_async().await;
+        let session = Uuid::new_v4().to_string();
+        let put_url = format!("http://{}/upload/input", server.address());
+        let receipt_url = format!("http://{}/receipts/{session}", server.address());
+
+        let upload_mock = server
+            .mock_async(|when, then| {
+                when.method(GET)
+                    .path("/inputs/upload")
+                    .header("x-api-key", TEST_KEY);
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .json_body_obj(&UploadRes {
+                        url: put_url,
+                        uuid: "input".to_string(),
+                    });
+            })
+            .await;
+        let put_mock = server
+            .mock_async(|when, then| {
+                when.method(PUT).path("/upload/input").body("data");
+                then.status(200);
+            })
+            .await;
+        let create_mock = server
+            .mock_async(|when, then| {
+                when.method(POST).path("/sessions/create");
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .json_body_obj(&CreateSessRes {
+                        uuid: session.clone(),
+                    });
+            })
+            .await;
+        let status_mock = server
+            .mock_async(|when, then| {
+                when.method(GET).path(format!("/sessions/status/{session}"));
+                then.status(200)
+                    .header("content-type", "application/json")
+                    .json_body_obj(&SessionStatusRes {
+                        status: SessionStatus::Succeeded,
+                        receipt_url: Some(receipt_url.clone()),
+                    });
+            })
+            .await;
+        let receipt_mock = server
+            .mock_async(|when, then| {
+                when.method(GET).path(format!("/receipts/{session}"));
+                then.status(200).body("receipt");
+            })
+            .await;
+
+        let server_url = format!("http://{}", server.address());
+        let client = Client::from_parts(server_url, TEST_KEY.to_string()).unwrap();
+
+        let input = client.upload_input(b"data".to_vec()).await.unwrap();
+        let session_id = client
+            .create_session("image".to_string(), input)
+            .await
+            .unwrap();
+        assert_eq!(session_id.uuid, session);
+        let res = client
+            .wait_for_completion(&session_id, &PollConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(res.status, SessionStatus::Succeeded);
+        let receipt = client.download(&res.receipt_url.unwrap()).await.unwrap();
+        assert_eq!(receipt, b"receipt");
+
+        upload_mock.assert_async().await;
+        put_mock.assert_async().await;
+        create_mock.assert_async().await;
+        status_mock.assert_async().await;
+        receipt_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn stop_and_timeout() {
+        let server = MockServer::start_async().await;
+        let session_id = SessionId::new(Uuid::new_v4().to_string());
+
+        let status_mock = server
+            .mock_async(|when, then| {
+                when.method(GET)
+                    .path(format!("/sessions/status/{}", session_id.uuid));
+                then.status(200)
+                    .body(r#"{"status": "RUNNING", "receipt_url": null}"#);
+            })
+            .await;
+        let stop_mock = server
+            .mock_async(|when, then| {
+                when.method(GET)
+                    .path(format!("/sessions/stop/{}", session_id.uuid));
+                then.status(429).body("slow down");
+            })
+            .await;
+
+        let server_url = format!("http://{}", server.address());
+        let client = Client::from_parts(server_url, TEST_KEY.to_string()).unwrap();
+
+        let config = PollConfig {
+            interval: Duration::from_millis(10),
+            max_interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+        };
+        let err = client
+            .wait_for_completion(&session_id, &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SdkErr::Timeout { .. }));
+        assert!(status_mock.hits_async().await > 1);
+
+        let err = client.stop_session(&session_id).await.unwrap_err();
+        assert!(matches!(err, SdkErr::QuotaExceeded(body) if body == "slow down"));
+        stop_mock.assert_async().await;
+    }
+}
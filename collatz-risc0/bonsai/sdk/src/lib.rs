@@ -17,3 +17,6 @@
 
 /// Bonsai Alpha SDK
 pub mod alpha;
+/// Async Bonsai Alpha SDK, enabled with the `async` feature
+#[cfg(feature = "async")]
+pub mod alpha_async;
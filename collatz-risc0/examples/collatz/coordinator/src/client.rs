@@ -0,0 +1,130 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Blocking client for the [crate::server] API, used by workers.
+
+use std::fmt;
+
+use reqwest::{blocking, StatusCode};
+
+use crate::{
+    ledger::{InclusionProof, SignedFrontier},
+    server::WORKER_HEADER,
+    Status, WorkUnit,
+};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Request(reqwest::Error),
+
+    /// The coordinator refused the request.
+    Status(StatusCode, String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Request(err) => write!(f, "{err}"),
+            ClientError::Status(status, body) => write!(f, "{status}: {body}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        ClientError::Request(err)
+    }
+}
+
+pub struct Client {
+    url: String,
+    inner: blocking::Client,
+}
+
+impl Client {
+    /// A client for the coordinator at `url`, e.g. `http://localhost:8002`.
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            inner: blocking::Client::new(),
+        }
+    }
+
+    pub fn lease(&self) -> Result<WorkUnit, ClientError> {
+        let res = check(self.inner.post(format!("{}/lease", self.url)).send()?)?;
+        Ok(res.json()?)
+    }
+
+    /// Submit `receipt`, as written by [risc0_zkvm::SessionReceipt::encode],
+    /// as the proof of `unit` by `worker`.
+    pub fn submit(
+        &self,
+        unit: &WorkUnit,
+        worker: &str,
+        receipt: Vec<u8>,
+    ) -> Result<(), ClientError> {
+        check(
+            self.inner
+                .post(format!("{}/units/{}", self.url, unit.id))
+                .header(WORKER_HEADER, worker)
+                .body(receipt)
+                .send()?,
+        )?;
+        Ok(())
+    }
+
+    /// Give back the lease on `unit` without proving it.
+    pub fn release(&self, unit: &WorkUnit) -> Result<(), ClientError> {
+        check(
+            self.inner
+                .delete(format!("{}/units/{}", self.url, unit.id))
+                .send()?,
+        )?;
+        Ok(())
+    }
+
+    pub fn status(&self) -> Result<Status, ClientError> {
+        let res = check(self.inner.get(format!("{}/status", self.url)).send()?)?;
+        Ok(res.json()?)
+    }
+
+    /// The coordinator's signed frontier. Check it with
+    /// [SignedFrontier::verify] against a key obtained out of band.
+    pub fn frontier(&self) -> Result<SignedFrontier, ClientError> {
+        let res = check(self.inner.get(format!("{}/frontier", self.url)).send()?)?;
+        Ok(res.json()?)
+    }
+
+    /// Proof that the unit starting at `start` is under the root the signed
+    /// frontier gives for the proof's image ID.
+    pub fn inclusion_proof(&self, start: u128) -> Result<InclusionProof, ClientError> {
+        let res = check(
+            self.inner
+                .get(format!("{}/proof/{start}", self.url))
+                .send()?,
+        )?;
+        Ok(res.json()?)
+    }
+}
+
+fn check(res: blocking::Response) -> Result<blocking::Response, ClientError> {
+    let status = res.status();
+    if status.is_success() {
+        Ok(res)
+    } else {
+        Err(ClientError::Status(status, res.text().unwrap_or_default()))
+    }
+}
@@ -0,0 +1,625 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A persistent record of which starting values have been proven to reach 1.
+//!
+//! For each image ID the ledger keeps the proven ranges as a set of disjoint
+//! intervals, to answer "is every n below X verified?", and the completed
+//! units as the leaves of a Merkle tree. The root of that tree, signed along
+//! with the contiguous frontier, lets outsiders audit coverage one unit at a
+//! time with an [InclusionProof] instead of downloading every receipt.
+
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use k256::ecdsa::{
+    signature::{Signer, Verifier},
+    Signature, SigningKey, VerifyingKey,
+};
+use risc0_zkvm::sha::{Digest, Impl, Sha256};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of every leaf preimage. Inner nodes are SHA-256 compressions of
+/// their children, so they can never collide with a leaf.
+const LEAF_PREFIX: &[u8] = b"collatz-unit";
+
+/// Prefix of the bytes signed in a [FrontierStatement].
+const STATEMENT_PREFIX: &[u8] = b"collatz-frontier";
+
+/// A completed unit: every starting value in `[start, end)` was proven to
+/// reach 1 by a receipt whose journal has the SHA-256 `journal_digest`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct UnitRecord {
+    pub start: u128,
+    pub end: u128,
+    pub journal_digest: Digest,
+}
+
+impl UnitRecord {
+    /// The Merkle leaf committing to this record.
+    pub fn leaf(&self) -> Digest {
+        let mut bytes = LEAF_PREFIX.to_vec();
+        bytes.extend_from_slice(&self.start.to_le_bytes());
+        bytes.extend_from_slice(&self.end.to_le_bytes());
+        bytes.extend_from_slice(self.journal_digest.as_bytes());
+        *Impl::hash_bytes(&bytes)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.end.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.journal_digest.as_bytes());
+        bytes
+    }
+
+    fn decode(start: &[u8], value: &[u8]) -> Self {
+        let mut journal_digest = Digest::default();
+        journal_digest
+            .as_mut_bytes()
+            .copy_from_slice(&value[16..48]);
+        Self {
+            start: decode_key(start),
+            end: decode_key(&value[..16]),
+            journal_digest,
+        }
+    }
+}
+
+/// Proof that a [UnitRecord] is leaf `index` of a tree of `leaf_count`
+/// leaves, the tree of the units proven by `image_id`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct InclusionProof {
+    pub image_id: Digest,
+    pub record: UnitRecord,
+    pub index: u64,
+    pub leaf_count: u64,
+
+    /// Sibling of each node on the path to the root, from the leaf up. The
+    /// last node of a level with an odd number of nodes has no sibling and is
+    /// carried up unchanged.
+    pub siblings: Vec<Digest>,
+}
+
+impl InclusionProof {
+    /// The root this proof leads to.
+    pub fn root(&self) -> Digest {
+        let mut node = self.record.leaf();
+        let mut index = self.index;
+        let mut len = self.leaf_count;
+        let mut siblings = self.siblings.iter();
+        while len > 1 {
+            if index % 2 == 1 {
+                node = *Impl::hash_pair(siblings.next().unwrap_or(&node), &node);
+            } else if index + 1 < len {
+                node = *Impl::hash_pair(&node, siblings.next().unwrap_or(&node));
+            }
+            index /= 2;
+            len = (len + 1) / 2;
+        }
+        node
+    }
+
+    pub fn verify(&self, root: &Digest) -> bool {
+        self.index < self.leaf_count
+            && self.siblings.len() == path_len(self.index, self.leaf_count)
+            && self.root() == *root
+    }
+}
+
+/// Number of siblings on the path from leaf `index` to the root.
+fn path_len(mut index: u64, mut len: u64) -> usize {
+    let mut siblings = 0;
+    while len > 1 {
+        if index % 2 == 1 || index + 1 < len {
+            siblings += 1;
+        }
+        index /= 2;
+        len = (len + 1) / 2;
+    }
+    siblings
+}
+
+/// Every level of the Merkle tree over `leaves`, from the leaves up to the
+/// root.
+fn levels(leaves: Vec<Digest>) -> Vec<Vec<Digest>> {
+    let mut levels = vec![leaves];
+    while levels.last().unwrap().len() > 1 {
+        let level = levels.last().unwrap();
+        let next = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => *Impl::hash_pair(left, right),
+                [last] => *last,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(next);
+    }
+    levels
+}
+
+/// The Merkle tree over the units completed with one image ID.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TreeRoot {
+    pub image_id: Digest,
+
+    /// Root of the tree, including any units past the frontier.
+    pub root: Digest,
+    pub leaf_count: u64,
+}
+
+/// What the coordinator attests to about the coverage of the image IDs it
+/// accepts.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FrontierStatement {
+    /// Every starting value in `[first, frontier)` has been proven to reach
+    /// 1, each by one of the image IDs in `trees`.
+    pub first: u128,
+    pub frontier: u128,
+
+    pub trees: Vec<TreeRoot>,
+}
+
+impl FrontierStatement {
+    /// The root to check an [InclusionProof] for `image_id` against.
+    pub fn root(&self, image_id: &Digest) -> Option<&Digest> {
+        self.trees
+            .iter()
+            .find(|tree| tree.image_id == *image_id)
+            .map(|tree| &tree.root)
+    }
+
+    fn message(&self) -> Vec<u8> {
+        let mut bytes = STATEMENT_PREFIX.to_vec();
+        bytes.extend_from_slice(&self.first.to_le_bytes());
+        bytes.extend_from_slice(&self.frontier.to_le_bytes());
+        for tree in &self.trees {
+            bytes.extend_from_slice(tree.image_id.as_bytes());
+            bytes.extend_from_slice(tree.root.as_bytes());
+            bytes.extend_from_slice(&tree.leaf_count.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn sign(self, key: &SigningKey) -> SignedFrontier {
+        let signature: Signature = key.sign(&self.message());
+        SignedFrontier {
+            public_key: hex::encode(key.verifying_key().to_encoded_point(true).as_bytes()),
+            signature: hex::encode(signature.to_bytes()),
+            statement: self,
+        }
+    }
+}
+
+/// A [FrontierStatement] signed with the coordinator's secp256k1 key.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SignedFrontier {
+    pub statement: FrontierStatement,
+
+    /// SEC1 compressed public key, in hex.
+    pub public_key: String,
+
+    /// ECDSA signature, in hex.
+    pub signature: String,
+}
+
+impl SignedFrontier {
+    /// Check the signature, returning the key that made it.
+    pub fn verify(&self) -> Option<VerifyingKey> {
+        let key = VerifyingKey::from_sec1_bytes(&hex::decode(&self.public_key).ok()?).ok()?;
+        let signature = Signature::from_slice(&hex::decode(&self.signature).ok()?).ok()?;
+        key.verify(&self.statement.message(), &signature).ok()?;
+        Some(key)
+    }
+}
+
+/// The Merkle tree over an image ID's completed units, ordered by start.
+struct UnitTree {
+    records: Vec<UnitRecord>,
+    levels: Vec<Vec<Digest>>,
+}
+
+impl UnitTree {
+    fn new(records: Vec<UnitRecord>) -> Self {
+        let levels = levels(records.iter().map(UnitRecord::leaf).collect());
+        Self { records, levels }
+    }
+
+    /// Add `record` after the others, or in place of the one with the same
+    /// start, updating only the path to the root. Returns false if it belongs
+    /// between two others, which moves every leaf after it.
+    fn insert(&mut self, record: &UnitRecord) -> bool {
+        let index = match self
+            .records
+            .binary_search_by_key(&record.start, |record| record.start)
+        {
+            Ok(index) => index,
+            Err(index) if index == self.records.len() => {
+                self.records.push(record.clone());
+                self.levels[0].push(Digest::default());
+                index
+            }
+            Err(_) => return false,
+        };
+        self.records[index] = record.clone();
+        self.levels[0][index] = record.leaf();
+
+        let (mut level, mut index) = (0, index);
+        while self.levels[level].len() > 1 {
+            let parent = index / 2;
+            let left = self.levels[level][parent * 2];
+            let node = match self.levels[level].get(parent * 2 + 1) {
+                Some(right) => *Impl::hash_pair(&left, right),
+                None => left,
+            };
+            if self.levels.len() == level + 1 {
+                self.levels.push(Vec::new());
+            }
+            let next = &mut self.levels[level + 1];
+            if parent < next.len() {
+                next[parent] = node;
+            } else {
+                next.push(node);
+            }
+            (level, index) = (level + 1, parent);
+        }
+        true
+    }
+
+    fn root(&self) -> Digest {
+        self.levels
+            .last()
+            .and_then(|level| level.first().copied())
+            .unwrap_or_default()
+    }
+
+    fn inclusion_proof(&self, image_id: &Digest, start: u128) -> Option<InclusionProof> {
+        let index = self
+            .records
+            .binary_search_by_key(&start, |record| record.start)
+            .ok()?;
+
+        let mut siblings = Vec::new();
+        let mut i = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(i ^ 1) {
+                siblings.push(*sibling);
+            }
+            i /= 2;
+        }
+
+        Some(InclusionProof {
+            image_id: *image_id,
+            record: self.records[index].clone(),
+            index: index as u64,
+            leaf_count: self.records.len() as u64,
+            siblings,
+        })
+    }
+}
+
+pub struct Ledger {
+    db: sled::Db,
+
+    /// Serializes updates, which read and rewrite neighbouring intervals.
+    write: Mutex<()>,
+
+    /// The tree of each image ID read so far, kept up to date by
+    /// [Ledger::record] so that roots and proofs do not reread every unit.
+    trees: Mutex<HashMap<Digest, UnitTree>>,
+}
+
+impl Ledger {
+    pub fn open(path: &Path) -> sled::Result<Self> {
+        Ok(Self::new(sled::open(path)?))
+    }
+
+    /// A ledger that is deleted when dropped.
+    pub fn temporary() -> sled::Result<Self> {
+        Ok(Self::new(sled::Config::new().temporary(true).open()?))
+    }
+
+    fn new(db: sled::Db) -> Self {
+        Self {
+            db,
+            write: Mutex::new(()),
+            trees: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn ranges(&self, image_id: &Digest) -> sled::Result<sled::Tree> {
+        self.db.open_tree(format!("ranges/{image_id}"))
+    }
+
+    fn units(&self, image_id: &Digest) -> sled::Result<sled::Tree> {
+        self.db.open_tree(format!("units/{image_id}"))
+    }
+
+    /// Record a completed unit, merging its range with any it touches.
+    pub fn record(&self, image_id: &Digest, record: &UnitRecord) -> sled::Result<()> {
+        let _guard = self.write.lock().unwrap();
+        let ranges = self.ranges(image_id)?;
+
+        let (mut start, mut end) = (record.start, record.end);
+        if let Some((key, value)) = ranges.range(..=encode_key(start)).next_back().transpose()? {
+            let prev_end = decode_key(&value);
+            if prev_end >= start {
+                start = decode_key(&key);
+                end = end.max(prev_end);
+                ranges.remove(key)?;
+            }
+        }
+        while let Some((key, value)) = ranges.range(encode_key(start)..).next().transpose()? {
+            if decode_key(&key) > end {
+                break;
+            }
+            end = end.max(decode_key(&value));
+            ranges.remove(key)?;
+        }
+        ranges.insert(encode_key(start), encode_key(end).to_vec())?;
+
+        self.units(image_id)?
+            .insert(encode_key(record.start), record.encode())?;
+        self.db.flush()?;
+
+        let mut trees = self.trees.lock().unwrap();
+        if let Some(tree) = trees.get_mut(image_id) {
+            if !tree.insert(record) {
+                // Rebuilt from the ledger when next read.
+                trees.remove(image_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether every starting value in `[start, end)` has been proven.
+    pub fn covers(&self, image_id: &Digest, start: u128, end: u128) -> sled::Result<bool> {
+        Ok(self.frontier(image_id, start)? >= end)
+    }
+
+    /// The end of the proven interval containing `from`: every starting value
+    /// in `[from, frontier)` has been proven. This is `from` itself if `from`
+    /// has not been proven.
+    pub fn frontier(&self, image_id: &Digest, from: u128) -> sled::Result<u128> {
+        let ranges = self.ranges(image_id)?;
+        Ok(
+            match ranges.range(..=encode_key(from)).next_back().transpose()? {
+                Some((_, end)) if decode_key(&end) > from => decode_key(&end),
+                _ => from,
+            },
+        )
+    }
+
+    /// The end of the interval from `from` proven by `image_ids` together:
+    /// every starting value in `[from, frontier)` has been proven by one of
+    /// them, though not necessarily all by the same one.
+    pub fn joint_frontier(&self, image_ids: &[Digest], from: u128) -> sled::Result<u128> {
+        let mut frontier = from;
+        loop {
+            let mut next = frontier;
+            for image_id in image_ids {
+                next = next.max(self.frontier(image_id, frontier)?);
+            }
+            if next == frontier {
+                return Ok(frontier);
+            }
+            frontier = next;
+        }
+    }
+
+    /// The completed units starting at `from` or later, ordered by start.
+    pub fn records_from(&self, image_id: &Digest, from: u128) -> sled::Result<Vec<UnitRecord>> {
+        self.units(image_id)?
+            .range(encode_key(from)..)
+            .map(|entry| entry.map(|(key, value)| UnitRecord::decode(&key, &value)))
+            .collect()
+    }
+
+    /// Call `f` with the tree of `image_id`, reading it from the ledger the
+    /// first time.
+    fn with_tree<T>(&self, image_id: &Digest, f: impl FnOnce(&UnitTree) -> T) -> sled::Result<T> {
+        let mut trees = self.trees.lock().unwrap();
+        let tree = match trees.get(image_id) {
+            Some(tree) => tree,
+            None => {
+                let tree = UnitTree::new(self.records_from(image_id, 0)?);
+                trees.entry(*image_id).or_insert(tree)
+            }
+        };
+        Ok(f(tree))
+    }
+
+    /// The root of the Merkle tree over every completed unit, ordered by
+    /// start, and the number of leaves.
+    pub fn root(&self, image_id: &Digest) -> sled::Result<(Digest, u64)> {
+        self.with_tree(image_id, |tree| (tree.root(), tree.records.len() as u64))
+    }
+
+    /// Prove that the unit starting at `start` is in the tree under
+    /// [Ledger::root].
+    pub fn inclusion_proof(
+        &self,
+        image_id: &Digest,
+        start: u128,
+    ) -> sled::Result<Option<InclusionProof>> {
+        self.with_tree(image_id, |tree| tree.inclusion_proof(image_id, start))
+    }
+
+    /// The current [FrontierStatement] for coverage by `image_ids` starting
+    /// at `first`.
+    pub fn statement(&self, image_ids: &[Digest], first: u128) -> sled::Result<FrontierStatement> {
+        let trees = image_ids
+            .iter()
+            .map(|image_id| {
+                let (root, leaf_count) = self.root(image_id)?;
+                Ok(TreeRoot {
+                    image_id: *image_id,
+                    root,
+                    leaf_count,
+                })
+            })
+            .collect::<sled::Result<_>>()?;
+        Ok(FrontierStatement {
+            first,
+            frontier: self.joint_frontier(image_ids, first)?,
+            trees,
+        })
+    }
+}
+
+/// Keys are big-endian so that sled orders them numerically.
+fn encode_key(n: u128) -> [u8; 16] {
+    n.to_be_bytes()
+}
+
+fn decode_key(bytes: &[u8]) -> u128 {
+    u128::from_be_bytes(bytes.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::OsRng;
+
+    use super::*;
+
+    fn record(start: u128, end: u128) -> UnitRecord {
+        UnitRecord {
+            start,
+            end,
+            journal_digest: *Impl::hash_bytes(&start.to_le_bytes()),
+        }
+    }
+
+    /// The root of the Merkle tree over `leaves`, built from scratch.
+    fn merkle_root(leaves: Vec<Digest>) -> Digest {
+        levels(leaves)
+            .last()
+            .and_then(|level| level.first().copied())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn intervals() {
+        let ledger = Ledger::temporary().unwrap();
+        let image_id = Digest::default();
+
+        ledger.record(&image_id, &record(1, 11)).unwrap();
+        ledger.record(&image_id, &record(21, 31)).unwrap();
+        assert_eq!(ledger.frontier(&image_id, 1).unwrap(), 11);
+        assert_eq!(ledger.frontier(&image_id, 5).unwrap(), 11);
+        assert_eq!(ledger.frontier(&image_id, 11).unwrap(), 11);
+        assert!(ledger.covers(&image_id, 21, 31).unwrap());
+        assert!(!ledger.covers(&image_id, 1, 21).unwrap());
+
+        // Filling the gap merges all three.
+        ledger.record(&image_id, &record(11, 21)).unwrap();
+        assert_eq!(ledger.frontier(&image_id, 1).unwrap(), 31);
+        assert!(ledger.covers(&image_id, 1, 31).unwrap());
+        assert_eq!(ledger.ranges(&image_id).unwrap().len(), 1);
+
+        // Coverage is per image ID...
+        let other = Digest::new([1; 8]);
+        assert_eq!(ledger.frontier(&other, 1).unwrap(), 1);
+
+        // ...but builds can take over from one another.
+        ledger.record(&other, &record(31, 41)).unwrap();
+        ledger.record(&image_id, &record(41, 51)).unwrap();
+        assert_eq!(ledger.frontier(&image_id, 1).unwrap(), 31);
+        let both = [image_id, other];
+        assert_eq!(ledger.joint_frontier(&both, 1).unwrap(), 51);
+        assert_eq!(ledger.joint_frontier(&both, 51).unwrap(), 51);
+    }
+
+    #[test]
+    fn inclusion_proofs() {
+        let ledger = Ledger::temporary().unwrap();
+        let image_id = Digest::default();
+        assert_eq!(ledger.root(&image_id).unwrap(), (Digest::default(), 0));
+
+        for (i, start) in (1..=61).step_by(10).enumerate() {
+            ledger
+                .record(&image_id, &record(start, start + 10))
+                .unwrap();
+            let (root, count) = ledger.root(&image_id).unwrap();
+            assert_eq!(count, i as u64 + 1);
+            let leaves = (1..=start)
+                .step_by(10)
+                .map(|start| record(start, start + 10).leaf())
+                .collect();
+            assert_eq!(root, merkle_root(leaves));
+
+            for start in (1..=start).step_by(10) {
+                let proof = ledger.inclusion_proof(&image_id, start).unwrap().unwrap();
+                assert_eq!(proof.record, record(start, start + 10));
+                assert!(proof.verify(&root));
+
+                let mut forged = proof.clone();
+                forged.record.end += 1;
+                assert!(!forged.verify(&root));
+            }
+        }
+        assert_eq!(ledger.inclusion_proof(&image_id, 2).unwrap(), None);
+
+        // Units completed out of order land in the same tree as if the ledger
+        // were read afresh.
+        ledger.record(&image_id, &record(0, 1)).unwrap();
+        ledger.record(&image_id, &record(81, 91)).unwrap();
+        let leaves = ledger
+            .records_from(&image_id, 0)
+            .unwrap()
+            .iter()
+            .map(UnitRecord::leaf)
+            .collect();
+        let (root, count) = ledger.root(&image_id).unwrap();
+        assert_eq!((root, count), (merkle_root(leaves), 9));
+        for start in [0, 41, 81] {
+            let proof = ledger.inclusion_proof(&image_id, start).unwrap().unwrap();
+            assert!(proof.verify(&root));
+        }
+    }
+
+    #[test]
+    fn signed_frontier() {
+        let ledger = Ledger::temporary().unwrap();
+        let image_id = Digest::default();
+        ledger.record(&image_id, &record(1, 11)).unwrap();
+        ledger.record(&image_id, &record(21, 31)).unwrap();
+
+        let other = Digest::new([1; 8]);
+        ledger.record(&other, &record(11, 21)).unwrap();
+
+        let key = SigningKey::random(&mut OsRng);
+        let signed = ledger.statement(&[image_id, other], 1).unwrap().sign(&key);
+        assert_eq!(signed.statement.frontier, 31);
+        let leaf_counts: Vec<_> = signed
+            .statement
+            .trees
+            .iter()
+            .map(|tree| (tree.image_id, tree.leaf_count))
+            .collect();
+        assert_eq!(leaf_counts, [(image_id, 2), (other, 1)]);
+        assert_eq!(signed.verify().as_ref(), Some(key.verifying_key()));
+
+        // Each image ID's units are proven against its own root.
+        let proof = ledger.inclusion_proof(&other, 11).unwrap().unwrap();
+        assert_eq!(proof.image_id, other);
+        assert!(proof.verify(signed.statement.root(&other).unwrap()));
+        assert!(!proof.verify(signed.statement.root(&image_id).unwrap()));
+
+        let mut forged = signed.clone();
+        forged.statement.frontier = 41;
+        assert_eq!(forged.verify(), None);
+        let mut forged = signed.clone();
+        forged.statement.trees[1].image_id = image_id;
+        assert_eq!(forged.verify(), None);
+    }
+}
@@ -0,0 +1,561 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hands out non-overlapping ranges of starting values to untrusted workers
+//! and accepts their receipts once they verify.
+//!
+//! With a [Ledger], completed units survive restarts and can be audited
+//! through inclusion proofs and a signed frontier.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use collatz_core::{decode_receipt, receipt_image_id, ImageAllowlist, Journal, RangeSummary};
+use k256::ecdsa::SigningKey;
+use ledger::{InclusionProof, Ledger, SignedFrontier, UnitRecord};
+use risc0_zkvm::{
+    serde::from_slice,
+    sha::{Digest, Impl, Sha256},
+    SessionReceipt, VerificationError,
+};
+use serde::{Deserialize, Serialize};
+
+pub mod client;
+pub mod ledger;
+pub mod server;
+
+/// A range of starting values for one worker to prove with a single
+/// receipt.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct WorkUnit {
+    pub id: u64,
+    pub start: u128,
+    pub end: u128,
+    pub step_bound: u64,
+}
+
+pub struct Config {
+    /// The first starting value to hand out.
+    pub first: u128,
+
+    /// Number of starting values in each unit.
+    pub unit_size: u128,
+
+    pub step_bound: u64,
+
+    /// How long a worker has to submit a unit before it is handed to someone
+    /// else.
+    pub lease_duration: Duration,
+
+    /// Only receipts of these guests are accepted, each recorded in the
+    /// ledger under its own image ID. Units proven by any of them count
+    /// towards the frontier.
+    pub image_ids: ImageAllowlist,
+}
+
+/// Why a submitted receipt was not accepted.
+#[derive(Debug)]
+pub enum SubmitError {
+    /// No unit with this ID has been handed out.
+    UnknownUnit(u64),
+
+    /// The unit has already been completed by someone.
+    AlreadyComplete(u64),
+
+    DecodeReceipt(risc0_zkvm::serde::Error),
+
+    /// The receipt is for a guest that is not on the allowlist.
+    UnlistedImageId(Digest),
+
+    Verification(VerificationError),
+
+    DecodeJournal(risc0_zkvm::serde::Error),
+
+    /// The receipt verified but is for a single trajectory.
+    NotARange,
+
+    /// The receipt verified but does not summarize the unit's range.
+    WrongRange {
+        expected: (u128, u128),
+        got: (u128, u128),
+    },
+
+    /// The receipt walked each starting value for a different number of
+    /// steps than the unit asks for.
+    WrongStepBound {
+        expected: u64,
+        got: u64,
+    },
+
+    /// The receipt does not commit to the submitting worker.
+    WrongContributor {
+        expected: String,
+        got: Option<String>,
+    },
+
+    /// The receipt was valid but could not be recorded in the ledger.
+    Ledger(sled::Error),
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::UnknownUnit(id) => write!(f, "Unknown work unit {id}"),
+            SubmitError::AlreadyComplete(id) => write!(f, "Work unit {id} is already complete"),
+            SubmitError::DecodeReceipt(err) => write!(f, "Failed to decode receipt: {err}"),
+            SubmitError::UnlistedImageId(image_id) => {
+                write!(f, "Image ID {image_id} is not an accepted build")
+            }
+            SubmitError::Verification(err) => write!(f, "Receipt did not verify: {err}"),
+            SubmitError::DecodeJournal(err) => write!(f, "Failed to decode journal: {err}"),
+            SubmitError::NotARange => write!(f, "Expected a range receipt"),
+            SubmitError::WrongRange { expected, got } => write!(
+                f,
+                "Expected a summary of {}..{}, got {}..{}",
+                expected.0, expected.1, got.0, got.1
+            ),
+            SubmitError::WrongStepBound { expected, got } => {
+                write!(f, "Expected a step bound of {expected}, got {got}")
+            }
+            SubmitError::WrongContributor { expected, got } => write!(
+                f,
+                "Expected a receipt committing to {expected:?}, got {got:?}"
+            ),
+            SubmitError::Ledger(err) => write!(f, "Failed to record unit: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {}
+
+/// Progress across all units handed out so far.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Status {
+    /// Every starting value below this has been proven to reach 1.
+    pub frontier: u128,
+
+    pub completed: usize,
+    pub leased: usize,
+
+    /// Completed units in which some trajectory did not reach 1 within the
+    /// step bound. These hold the frontier back until looked into.
+    pub anomalies: Vec<u64>,
+}
+
+struct Lease {
+    unit: WorkUnit,
+    expires_at: Instant,
+}
+
+struct Completed {
+    unit: WorkUnit,
+    all_reached_one: bool,
+}
+
+#[derive(Default)]
+struct State {
+    /// Start of unit 0, past whatever the ledger already covers.
+    base: u128,
+
+    /// The lowest id that may not have been cut yet. Ids of units restored
+    /// from the ledger are skipped.
+    next_id: u64,
+    leased: BTreeMap<u64, Lease>,
+    completed: BTreeMap<u64, Completed>,
+}
+
+pub struct Coordinator {
+    config: Config,
+    state: Mutex<State>,
+    ledger: Option<(Ledger, SigningKey)>,
+}
+
+impl Coordinator {
+    pub fn new(config: Config) -> Self {
+        assert!(config.first != 0, "0 has no Collatz trajectory");
+        assert!(config.unit_size != 0, "Work units must not be empty");
+        assert!(
+            !config.image_ids.is_empty(),
+            "At least one image ID must be accepted"
+        );
+        let state = State {
+            base: config.first,
+            ..Default::default()
+        };
+        Self {
+            config,
+            state: Mutex::new(state),
+            ledger: None,
+        }
+    }
+
+    /// Record completed units in `ledger`, resuming from its frontier, and
+    /// sign frontier statements with `key`.
+    ///
+    /// Units the ledger holds past the frontier are restored as completed, so
+    /// they are not leased out again. Both come from the units of every
+    /// accepted image ID.
+    pub fn with_ledger(mut self, ledger: Ledger, key: SigningKey) -> sled::Result<Self> {
+        let image_ids = self.config.image_ids.image_ids();
+        let frontier = ledger.joint_frontier(image_ids, self.config.first)?;
+        let mut records = Vec::new();
+        for image_id in image_ids {
+            records.extend(ledger.records_from(image_id, frontier)?);
+        }
+        let unit_size = self.config.unit_size;
+        let state = self.state.get_mut().unwrap();
+        state.base = frontier;
+        for record in records {
+            let offset = record.start - frontier;
+            // Units cut with another size no longer line up; prove them again.
+            if offset % unit_size != 0 || record.end - record.start != unit_size {
+                continue;
+            }
+            let id = (offset / unit_size) as u64;
+            let unit = WorkUnit {
+                id,
+                start: record.start,
+                end: record.end,
+                step_bound: self.config.step_bound,
+            };
+            state.completed.insert(
+                id,
+                Completed {
+                    unit,
+                    all_reached_one: true,
+                },
+            );
+        }
+        self.ledger = Some((ledger, key));
+        Ok(self)
+    }
+
+    /// Lease a unit, reissuing the oldest expired lease before cutting a new
+    /// unit. Leases are not tied to a worker: whoever submits a valid receipt
+    /// first completes the unit.
+    pub fn lease(&self) -> WorkUnit {
+        let now = Instant::now();
+        let expires_at = now + self.config.lease_duration;
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(lease) = state
+            .leased
+            .values_mut()
+            .find(|lease| lease.expires_at <= now)
+        {
+            lease.expires_at = expires_at;
+            return lease.unit.clone();
+        }
+
+        let mut id = state.next_id;
+        while state.completed.contains_key(&id) {
+            id += 1;
+        }
+        let start = state.base + self.config.unit_size * id as u128;
+        let unit = WorkUnit {
+            id,
+            start,
+            end: start + self.config.unit_size,
+            step_bound: self.config.step_bound,
+        };
+        state.next_id = id + 1;
+        state.leased.insert(
+            id,
+            Lease {
+                unit: unit.clone(),
+                expires_at,
+            },
+        );
+        unit
+    }
+
+    /// Give back the lease on unit `id`, e.g. because the worker holding it
+    /// cannot afford to prove it, so that it is reissued by the next
+    /// [Coordinator::lease]. Returns false if the unit is not leased.
+    pub fn release(&self, id: u64) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match state.leased.get_mut(&id) {
+            Some(lease) => {
+                lease.expires_at = lease.expires_at.min(now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Accept `receipt` as the proof of unit `id` by `worker`, who must be the
+    /// contributor committed in the receipt.
+    ///
+    /// A receipt is accepted even if its lease has expired or been reissued,
+    /// as long as nobody has completed the unit first.
+    pub fn submit(&self, id: u64, worker: &str, receipt: &[u8]) -> Result<(), SubmitError> {
+        let unit = {
+            let state = self.state.lock().unwrap();
+            if state.completed.contains_key(&id) {
+                return Err(SubmitError::AlreadyComplete(id));
+            }
+            match state.leased.get(&id) {
+                Some(lease) => lease.unit.clone(),
+                None => return Err(SubmitError::UnknownUnit(id)),
+            }
+        };
+
+        // Verification is slow, so it happens without holding the lock.
+        let (summary, image_id, journal_digest) = self.check(&unit, worker, receipt)?;
+
+        let mut state = self.state.lock().unwrap();
+        if state.completed.contains_key(&id) {
+            return Err(SubmitError::AlreadyComplete(id));
+        }
+        if let Some((ledger, _)) = &self.ledger {
+            // Units with a counterexample are anomalies, not coverage.
+            if summary.all_reached_one {
+                let record = UnitRecord {
+                    start: unit.start,
+                    end: unit.end,
+                    journal_digest,
+                };
+                ledger
+                    .record(&image_id, &record)
+                    .map_err(SubmitError::Ledger)?;
+            }
+        }
+        state.leased.remove(&id);
+        state.completed.insert(
+            id,
+            Completed {
+                unit,
+                all_reached_one: summary.all_reached_one,
+            },
+        );
+        Ok(())
+    }
+
+    fn check(
+        &self,
+        unit: &WorkUnit,
+        worker: &str,
+        receipt: &[u8],
+    ) -> Result<(RangeSummary, Digest, Digest), SubmitError> {
+        let receipt = decode_receipt(receipt).map_err(SubmitError::DecodeReceipt)?;
+        let image_id = receipt_image_id(&receipt).map_err(SubmitError::Verification)?;
+        if !self.config.image_ids.contains(&image_id) {
+            return Err(SubmitError::UnlistedImageId(image_id));
+        }
+        receipt
+            .verify(image_id)
+            .map_err(SubmitError::Verification)?;
+        let journal: Journal =
+            from_slice(receipt.get_journal()).map_err(SubmitError::DecodeJournal)?;
+
+        let Journal::RangeV1(summary) = journal else {
+            return Err(SubmitError::NotARange);
+        };
+        if (summary.start, summary.end) != (unit.start, unit.end) {
+            return Err(SubmitError::WrongRange {
+                expected: (unit.start, unit.end),
+                got: (summary.start, summary.end),
+            });
+        }
+        // A lower bound would turn values into anomalies that hold the
+        // frontier back.
+        if summary.step_bound != unit.step_bound {
+            return Err(SubmitError::WrongStepBound {
+                expected: unit.step_bound,
+                got: summary.step_bound,
+            });
+        }
+        if summary.contributor.as_deref() != Some(worker) {
+            return Err(SubmitError::WrongContributor {
+                expected: worker.to_string(),
+                got: summary.contributor,
+            });
+        }
+        Ok((summary, image_id, *Impl::hash_bytes(receipt.get_journal())))
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.state.lock().unwrap();
+
+        let mut frontier = state.base;
+        for completed in state.completed.values() {
+            if completed.unit.start != frontier || !completed.all_reached_one {
+                break;
+            }
+            frontier = completed.unit.end;
+        }
+
+        Status {
+            frontier,
+            completed: state.completed.len(),
+            leased: state.leased.len(),
+            anomalies: state
+                .completed
+                .iter()
+                .filter(|(_, completed)| !completed.all_reached_one)
+                .map(|(id, _)| *id)
+                .collect(),
+        }
+    }
+
+    /// Proof that the unit starting at `start` is in the ledger under the
+    /// first accepted image ID that proved it, or `None` if none did or there
+    /// is no ledger.
+    pub fn inclusion_proof(&self, start: u128) -> sled::Result<Option<InclusionProof>> {
+        let Some((ledger, _)) = &self.ledger else {
+            return Ok(None);
+        };
+        for image_id in self.config.image_ids.image_ids() {
+            if let Some(proof) = ledger.inclusion_proof(image_id, start)? {
+                return Ok(Some(proof));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The ledger's frontier from [Config::first], signed, or `None` if there
+    /// is no ledger.
+    pub fn signed_frontier(&self) -> sled::Result<Option<SignedFrontier>> {
+        let Some((ledger, key)) = &self.ledger else {
+            return Ok(None);
+        };
+        let statement = ledger.statement(self.config.image_ids.image_ids(), self.config.first)?;
+        Ok(Some(statement.sign(key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(lease_duration: Duration) -> Config {
+        Config {
+            first: 1,
+            unit_size: 100,
+            step_bound: 1000,
+            lease_duration,
+            image_ids: ImageAllowlist::new(vec![Digest::default()]),
+        }
+    }
+
+    fn coordinator(lease_duration: Duration) -> Coordinator {
+        Coordinator::new(config(lease_duration))
+    }
+
+    #[test]
+    fn units_do_not_overlap() {
+        let coordinator = coordinator(Duration::from_secs(60));
+        let a = coordinator.lease();
+        let b = coordinator.lease();
+        assert_eq!((a.id, a.start, a.end), (0, 1, 101));
+        assert_eq!((b.id, b.start, b.end), (1, 101, 201));
+        assert_eq!(a.step_bound, 1000);
+
+        let status = coordinator.status();
+        assert_eq!(status.frontier, 1);
+        assert_eq!(status.leased, 2);
+        assert_eq!(status.completed, 0);
+    }
+
+    #[test]
+    fn expired_leases_are_reissued() {
+        let coordinator = coordinator(Duration::ZERO);
+        let a = coordinator.lease();
+        let b = coordinator.lease();
+        assert_eq!(a, b);
+        assert_eq!(coordinator.status().leased, 1);
+    }
+
+    #[test]
+    fn released_leases_are_reissued() {
+        let coordinator = coordinator(Duration::from_secs(60));
+        let a = coordinator.lease();
+        let b = coordinator.lease();
+        assert!(coordinator.release(b.id));
+        assert!(!coordinator.release(7));
+        assert_eq!(coordinator.lease(), b);
+        assert_eq!(coordinator.lease().id, 2);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn rejects_bad_submissions() {
+        let coordinator = coordinator(Duration::from_secs(60));
+        let unit = coordinator.lease();
+        assert!(matches!(
+            coordinator.submit(7, "alice", &[]),
+            Err(SubmitError::UnknownUnit(7))
+        ));
+        assert!(matches!(
+            coordinator.submit(unit.id, "alice", &[0; 3]),
+            Err(SubmitError::DecodeReceipt(_))
+        ));
+        assert_eq!(coordinator.status().completed, 0);
+    }
+
+    #[test]
+    fn resumes_from_ledger() {
+        let ledger = Ledger::temporary().unwrap();
+        // A gap at [201, 301), then a unit completed before the restart. The
+        // second unit was proven by an older build.
+        let old_image_id = Digest::new([1; 8]);
+        for (image_id, start) in [
+            (Digest::default(), 1),
+            (old_image_id, 101),
+            (Digest::default(), 301),
+        ] {
+            let record = UnitRecord {
+                start,
+                end: start + 100,
+                journal_digest: Digest::default(),
+            };
+            ledger.record(&image_id, &record).unwrap();
+        }
+
+        let key = SigningKey::random(&mut rand_core::OsRng);
+        let coordinator = Coordinator::new(Config {
+            image_ids: ImageAllowlist::new(vec![Digest::default(), old_image_id]),
+            ..config(Duration::from_secs(60))
+        })
+        .with_ledger(ledger, key)
+        .unwrap();
+        let unit = coordinator.lease();
+        assert_eq!((unit.id, unit.start, unit.end), (0, 201, 301));
+        let unit = coordinator.lease();
+        assert_eq!((unit.id, unit.start, unit.end), (2, 401, 501));
+        assert!(matches!(
+            coordinator.submit(1, "alice", &[]),
+            Err(SubmitError::AlreadyComplete(1))
+        ));
+        let status = coordinator.status();
+        assert_eq!((status.frontier, status.completed), (201, 1));
+
+        let signed = coordinator.signed_frontier().unwrap().unwrap();
+        assert!(signed.verify().is_some());
+        assert_eq!(signed.statement.frontier, 201);
+        for start in [1, 101, 301] {
+            let proof = coordinator.inclusion_proof(start).unwrap().unwrap();
+            let root = signed.statement.root(&proof.image_id).unwrap();
+            assert!(proof.verify(root));
+        }
+        let proof = coordinator.inclusion_proof(101).unwrap().unwrap();
+        assert_eq!(proof.image_id, old_image_id);
+        assert_eq!(coordinator.inclusion_proof(201).unwrap(), None);
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::Parser;
+use collatz_coordinator::{ledger::Ledger, server, Config, Coordinator};
+use collatz_core::{ImageAllowlist, DEFAULT_STEP_BOUND};
+use collatz_methods::COLLATZ_ID;
+use k256::ecdsa::SigningKey;
+use rand_core::OsRng;
+use tiny_http::Server;
+
+/// Hand out ranges of starting values to workers and accept their receipts.
+#[derive(Parser)]
+#[command(about, version)]
+struct Args {
+    #[arg(long, default_value = "127.0.0.1:8002")]
+    addr: String,
+
+    /// The first starting value to hand out.
+    #[arg(long, default_value_t = 1)]
+    first: u128,
+
+    /// Number of starting values in each work unit.
+    #[arg(long, default_value_t = 1000)]
+    unit_size: u128,
+
+    #[arg(long, default_value_t = DEFAULT_STEP_BOUND)]
+    step_bound: u64,
+
+    /// Seconds a worker has to submit a unit before it is handed out again.
+    #[arg(long, default_value_t = 3600)]
+    lease_secs: u64,
+
+    /// Number of requests handled at once.
+    #[arg(long, default_value_t = 4)]
+    threads: usize,
+
+    /// Directory of the coverage ledger. Without one, progress is lost on
+    /// restart and no frontier is signed.
+    #[arg(long)]
+    ledger: Option<PathBuf>,
+
+    /// File holding the hex secp256k1 key that signs the frontier, created
+    /// if missing.
+    #[arg(long, default_value = "coordinator.key", requires = "ledger")]
+    signing_key: PathBuf,
+
+    /// File listing the hex image IDs of the published guest builds to
+    /// accept, current build first. Defaults to the image ID of this build.
+    #[arg(long)]
+    allowlist: Option<PathBuf>,
+}
+
+fn load_or_create_key(path: &Path) -> SigningKey {
+    if let Ok(key) = fs::read_to_string(path) {
+        let bytes = hex::decode(key.trim()).expect("Signing key is not hex");
+        return SigningKey::from_slice(&bytes).expect("Invalid signing key");
+    }
+    let key = SigningKey::random(&mut OsRng);
+    fs::write(path, hex::encode(key.to_bytes())).expect("Failed to write signing key");
+    key
+}
+
+fn load_allowlist(path: &Path) -> ImageAllowlist {
+    let text = fs::read_to_string(path).expect("Failed to read allowlist");
+    let allowlist: ImageAllowlist = text.parse().expect("Invalid allowlist");
+    assert!(
+        !allowlist.is_empty(),
+        "{} lists no image IDs",
+        path.display()
+    );
+    allowlist
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let image_ids = match &args.allowlist {
+        Some(path) => load_allowlist(path),
+        None => ImageAllowlist::new(vec![COLLATZ_ID.into()]),
+    };
+    for image_id in image_ids.image_ids() {
+        println!("Accepting receipts of image {image_id}");
+    }
+
+    let mut coordinator = Coordinator::new(Config {
+        first: args.first,
+        unit_size: args.unit_size,
+        step_bound: args.step_bound,
+        lease_duration: Duration::from_secs(args.lease_secs),
+        image_ids,
+    });
+    if let Some(path) = &args.ledger {
+        let ledger = Ledger::open(path).expect("Failed to open ledger");
+        let key = load_or_create_key(&args.signing_key);
+        let public_key = key.verifying_key().to_encoded_point(true);
+        println!("Signing frontiers with {}", hex::encode(public_key));
+        coordinator = coordinator
+            .with_ledger(ledger, key)
+            .expect("Failed to read ledger");
+    }
+    let coordinator = Arc::new(coordinator);
+
+    let server = Server::http(&args.addr).expect("Failed to bind");
+    println!("Listening on {}", server.server_addr());
+    server::serve(coordinator, server, args.threads).unwrap();
+}
@@ -0,0 +1,160 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP front end of a [Coordinator].
+//!
+//! * `POST /lease` responds with a [crate::WorkUnit] as JSON.
+//! * `POST /units/<id>` takes a receipt as written by
+//!   [risc0_zkvm::SessionReceipt::encode], with the submitting worker in the
+//!   `X-Worker` header.
+//! * `DELETE /units/<id>` gives back the lease on a unit, responding with 404
+//!   if it is not leased.
+//! * `GET /status` responds with a [crate::Status] as JSON.
+//! * `GET /frontier` responds with a [crate::ledger::SignedFrontier] as JSON.
+//! * `GET /proof/<start>` responds with the [crate::ledger::InclusionProof] of
+//!   the unit starting at `start` as JSON.
+//!
+//! The last two respond with 404 if the coordinator has no ledger.
+
+use std::{
+    io::{self, Read},
+    sync::Arc,
+    thread,
+};
+
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{Coordinator, SubmitError};
+
+/// Largest receipt accepted, in bytes.
+pub const MAX_RECEIPT_SIZE: usize = 64 << 20;
+
+/// Header naming the worker submitting a receipt.
+pub const WORKER_HEADER: &str = "X-Worker";
+
+/// Serve requests on `threads` threads until the listener fails.
+pub fn serve(coordinator: Arc<Coordinator>, server: Server, threads: usize) -> io::Result<()> {
+    let server = Arc::new(server);
+    let handles: Vec<_> = (0..threads.max(1))
+        .map(|_| {
+            let (coordinator, server) = (coordinator.clone(), server.clone());
+            thread::spawn(move || -> io::Result<()> {
+                loop {
+                    let request = server.recv()?;
+                    if let Err(err) = handle(&coordinator, request) {
+                        eprintln!("Failed to respond: {err}");
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+fn handle(coordinator: &Coordinator, mut request: Request) -> io::Result<()> {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let json_header = Header::from_bytes("Content-Type", "application/json").unwrap();
+
+    match (method, url.as_str()) {
+        (Method::Post, "/lease") => {
+            let unit = serde_json::to_string(&coordinator.lease()).unwrap();
+            request.respond(Response::from_string(unit).with_header(json_header))
+        }
+        (Method::Get, "/status") => {
+            let status = serde_json::to_string(&coordinator.status()).unwrap();
+            request.respond(Response::from_string(status).with_header(json_header))
+        }
+        (Method::Get, "/frontier") => match coordinator.signed_frontier() {
+            Ok(Some(signed)) => {
+                let signed = serde_json::to_string(&signed).unwrap();
+                request.respond(Response::from_string(signed).with_header(json_header))
+            }
+            Ok(None) => request.respond(Response::empty(404)),
+            Err(err) => {
+                request.respond(Response::from_string(err.to_string()).with_status_code(500))
+            }
+        },
+        (Method::Get, path) if path.starts_with("/proof/") => {
+            let Ok(start) = path["/proof/".len()..].parse() else {
+                return request.respond(Response::empty(404));
+            };
+            match coordinator.inclusion_proof(start) {
+                Ok(Some(proof)) => {
+                    let proof = serde_json::to_string(&proof).unwrap();
+                    request.respond(Response::from_string(proof).with_header(json_header))
+                }
+                Ok(None) => request.respond(Response::empty(404)),
+                Err(err) => {
+                    request.respond(Response::from_string(err.to_string()).with_status_code(500))
+                }
+            }
+        }
+        (Method::Delete, path) if path.starts_with("/units/") => {
+            let Ok(id) = path["/units/".len()..].parse() else {
+                return request.respond(Response::empty(404));
+            };
+            let status = if coordinator.release(id) { 200 } else { 404 };
+            request.respond(Response::empty(status))
+        }
+        (Method::Post, path) if path.starts_with("/units/") => {
+            let Ok(id) = path["/units/".len()..].parse() else {
+                return request.respond(Response::empty(404));
+            };
+            let Some(worker) = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv(WORKER_HEADER))
+                .map(|header| header.value.to_string())
+            else {
+                return request.respond(
+                    Response::from_string(format!("Missing {WORKER_HEADER} header"))
+                        .with_status_code(400),
+                );
+            };
+            if request
+                .body_length()
+                .map_or(false, |len| len > MAX_RECEIPT_SIZE)
+            {
+                return request.respond(Response::empty(413));
+            }
+            let mut receipt = Vec::new();
+            request
+                .as_reader()
+                .take(MAX_RECEIPT_SIZE as u64 + 1)
+                .read_to_end(&mut receipt)?;
+            if receipt.len() > MAX_RECEIPT_SIZE {
+                return request.respond(Response::empty(413));
+            }
+
+            match coordinator.submit(id, &worker, &receipt) {
+                Ok(()) => request.respond(Response::empty(200)),
+                Err(err) => {
+                    let status = match err {
+                        SubmitError::UnknownUnit(_) => 404,
+                        SubmitError::AlreadyComplete(_) => 409,
+                        SubmitError::Ledger(_) => 500,
+                        _ => 422,
+                    };
+                    request.respond(Response::from_string(err.to_string()).with_status_code(status))
+                }
+            }
+        }
+        (_, "/lease" | "/status" | "/frontier") => request.respond(Response::empty(405)),
+        _ => request.respond(Response::empty(404)),
+    }
+}
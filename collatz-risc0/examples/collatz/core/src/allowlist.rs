@@ -0,0 +1,155 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The image IDs of published guest builds.
+//!
+//! A receipt only shows that *some* guest ran. Checking it against the image
+//! ID that came with it proves nothing, so verifiers instead accept the image
+//! IDs of hermetic builds whose manifests have been published, listed one per
+//! line as hex:
+//!
+//! ```text
+//! # Anything after the image ID is a comment, e.g. the build it came from.
+//! 5a7f...e1c0  collatz 0.1.0, rustc 1.70.0-nightly
+//! ```
+
+use std::{fmt, str::FromStr};
+
+use risc0_zkvm::{receipt::compute_image_id, sha::Digest, SessionFlatReceipt, VerificationError};
+
+/// The image IDs a verifier accepts, in the order they were listed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ImageAllowlist {
+    image_ids: Vec<Digest>,
+}
+
+/// A line of an allowlist that is not a hex image ID.
+#[derive(Debug, Eq, PartialEq)]
+pub struct AllowlistError {
+    /// The 1-based line number.
+    pub line: usize,
+    pub text: String,
+}
+
+impl fmt::Display for AllowlistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Line {} is not a hex image ID: {:?}",
+            self.line, self.text
+        )
+    }
+}
+
+impl std::error::Error for AllowlistError {}
+
+impl ImageAllowlist {
+    pub fn new(image_ids: Vec<Digest>) -> Self {
+        Self { image_ids }
+    }
+
+    pub fn image_ids(&self) -> &[Digest] {
+        &self.image_ids
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.image_ids.is_empty()
+    }
+
+    pub fn contains(&self, image_id: &Digest) -> bool {
+        self.image_ids.contains(image_id)
+    }
+}
+
+impl FromStr for ImageAllowlist {
+    type Err = AllowlistError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut image_ids = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let Some(word) = line.split_whitespace().next() else {
+                continue;
+            };
+            if word.starts_with('#') {
+                continue;
+            }
+            let image_id = parse_hex(word).ok_or_else(|| AllowlistError {
+                line: index + 1,
+                text: line.to_string(),
+            })?;
+            image_ids.push(image_id);
+        }
+        Ok(Self { image_ids })
+    }
+}
+
+/// Parse a digest written as by its `Display` impl.
+fn parse_hex(hex: &str) -> Option<Digest> {
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Digest::try_from(bytes).ok()
+}
+
+/// The image ID `receipt` claims to start from, as checked by
+/// [risc0_zkvm::SessionReceipt::verify]. Only meaningful once the receipt
+/// has verified against it.
+pub fn receipt_image_id(receipt: &SessionFlatReceipt) -> Result<Digest, VerificationError> {
+    let first = receipt
+        .segments
+        .first()
+        .ok_or(VerificationError::ReceiptFormatError)?;
+    let pre = first.get_metadata()?.pre;
+    Ok(compute_image_id(&pre.merkle_root, pre.pc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let a = Digest::new([1, 2, 3, 4, 5, 6, 7, 8]);
+        let b = Digest::new([8, 7, 6, 5, 4, 3, 2, 1]);
+        let text = format!("# Published builds\n\n{a}  collatz 0.1.0\n  {b}\n");
+        let allowlist: ImageAllowlist = text.parse().unwrap();
+        assert_eq!(allowlist.image_ids(), &[a, b]);
+        assert!(allowlist.contains(&b));
+        assert!(!allowlist.contains(&Digest::default()));
+        assert!("".parse::<ImageAllowlist>().unwrap().is_empty());
+
+        let err = format!("{a}\n[1, 2, 3, 4, 5, 6, 7, 8]\n")
+            .parse::<ImageAllowlist>()
+            .unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!("abc".parse::<ImageAllowlist>().is_err());
+        assert!("abcd".parse::<ImageAllowlist>().is_err());
+    }
+
+    #[test]
+    fn empty_receipt() {
+        let receipt = SessionFlatReceipt {
+            segments: vec![],
+            journal: vec![],
+        };
+        assert_eq!(
+            receipt_image_id(&receipt),
+            Err(VerificationError::ReceiptFormatError)
+        );
+    }
+}
@@ -0,0 +1,606 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use risc0_zkvm::{
+    sha::{Digest, Impl, Sha256},
+    SessionFlatReceipt,
+};
+use serde::{Deserialize, Serialize};
+
+pub use self::{
+    allowlist::{receipt_image_id, AllowlistError, ImageAllowlist},
+    sieve::{Jump, Sieve, MAX_SIEVE_K},
+};
+
+pub mod allowlist;
+pub mod sieve;
+
+/// Number of little-endian 32-bit words in a trajectory value committed by the
+/// guest. This matches the width of the zkVM bigint accelerator.
+pub const VALUE_WORDS: usize = 8;
+
+/// A trajectory value as committed by the guest.
+pub type Value = [u32; VALUE_WORDS];
+
+/// Default number of steps the guest takes from a single starting value
+/// before giving up on it. Every starting value below 2^68 reaches 1 in far
+/// fewer steps than this.
+pub const DEFAULT_STEP_BOUND: u64 = 100_000;
+
+/// What the host asks the guest to prove.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Request {
+    /// Commit the trajectory of a single starting value as a
+    /// [Journal::TrajectoryV1].
+    Sequence {
+        #[serde(with = "words")]
+        start: u128,
+        schema: JournalSchema,
+        contributor: Option<String>,
+
+        /// Steps to take before reporting [Outcome::StepBoundExceeded].
+        step_bound: u64,
+
+        /// Pause the guest after every this many steps, so that a very long
+        /// trajectory is proven as a chain of receipts, one per chunk.
+        pause_every: Option<u64>,
+    },
+
+    /// Commit a [Journal::RangeV1] summarizing every starting value in
+    /// `[start, end)`.
+    Range {
+        #[serde(with = "words")]
+        start: u128,
+        #[serde(with = "words")]
+        end: u128,
+        contributor: Option<String>,
+
+        /// Steps to take from each starting value before reporting
+        /// [Outcome::StepBoundExceeded].
+        step_bound: u64,
+    },
+
+    /// Commit a [Journal::SieveRangeV1] showing that every starting value in
+    /// `[start, end)` drops below itself, walking only those that survive
+    /// `sieve`.
+    SieveRange {
+        #[serde(with = "words")]
+        start: u128,
+        #[serde(with = "words")]
+        end: u128,
+        contributor: Option<String>,
+
+        /// Steps to take from each starting value before reporting
+        /// [Outcome::StepBoundExceeded].
+        step_bound: u64,
+
+        sieve: Sieve,
+    },
+}
+
+/// Which parts of a trajectory the guest commits to the journal.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum JournalSchema {
+    /// Only the full sequence of values.
+    Sequence,
+
+    /// Only the [TrajectoryStats], so the journal has a fixed size.
+    Stats,
+
+    /// Both the full sequence and the [TrajectoryStats].
+    #[default]
+    Full,
+}
+
+impl JournalSchema {
+    pub fn has_sequence(&self) -> bool {
+        matches!(self, JournalSchema::Sequence | JournalSchema::Full)
+    }
+
+    pub fn has_stats(&self) -> bool {
+        matches!(self, JournalSchema::Stats | JournalSchema::Full)
+    }
+}
+
+/// How a trajectory ended.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Outcome {
+    /// The trajectory reached 1.
+    ReachedOne,
+
+    /// The trajectory entered a cycle that does not contain 1. `members` lists
+    /// the cycle once, starting from where it was detected.
+    NontrivialCycle { members: Vec<Value> },
+
+    /// The step bound of the [Request] was reached first.
+    StepBoundExceeded { last_value: Value },
+
+    /// The next value would not fit in a [Value]. `last_value` is the last one
+    /// that did.
+    Overflow { last_value: Value },
+}
+
+/// Statistics of a single trajectory.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TrajectoryStats {
+    /// Number of steps taken to reach 1, or until the walk was stopped if it
+    /// did not.
+    pub total_stopping_time: u64,
+
+    /// Number of steps taken to first drop below the starting value, or 0 if
+    /// the trajectory never does (as for 1 and 2).
+    pub glide: u64,
+
+    /// The largest value reached.
+    pub max_value: Value,
+
+    /// SHA-256 of the parity vector, see [ParityVector].
+    pub parity_vector_hash: Digest,
+}
+
+/// The trajectory of a single starting value, as committed by the guest.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TrajectoryJournal {
+    #[serde(with = "words")]
+    pub start: u128,
+
+    /// Who asked for this proof, as given in the [Request].
+    pub contributor: Option<String>,
+
+    /// Steps taken before giving up on the trajectory, as given in the
+    /// [Request].
+    pub step_bound: u64,
+
+    pub outcome: Outcome,
+
+    /// Present when the [JournalSchema] includes the sequence.
+    pub sequence: Option<Vec<Value>>,
+
+    /// Present when the [JournalSchema] includes statistics.
+    pub stats: Option<TrajectoryStats>,
+}
+
+/// Summary of the trajectories of every starting value in `[start, end)`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RangeSummary {
+    #[serde(with = "words")]
+    pub start: u128,
+    #[serde(with = "words")]
+    pub end: u128,
+
+    /// Who asked for this proof, as given in the [Request].
+    pub contributor: Option<String>,
+
+    /// Steps taken from each starting value before giving up on it, as given
+    /// in the [Request].
+    pub step_bound: u64,
+
+    /// The largest number of steps any starting value took to reach 1.
+    pub max_stopping_time: u64,
+    /// The first starting value that took `max_stopping_time` steps.
+    #[serde(with = "words")]
+    pub max_stopping_time_arg: u128,
+
+    /// The largest value reached by any trajectory.
+    pub max_excursion: Value,
+    /// The first starting value whose trajectory reached `max_excursion`.
+    #[serde(with = "words")]
+    pub max_excursion_arg: u128,
+
+    /// Whether every trajectory in the range reached 1. The maxima above
+    /// only cover the trajectories that did.
+    pub all_reached_one: bool,
+
+    /// The first starting value whose trajectory did not reach 1.
+    pub counterexample: Option<Counterexample>,
+}
+
+/// Shows that every starting value in `[start, end)` drops below itself, so
+/// that the whole range reaches 1 once every smaller value is known to.
+///
+/// Starting values below `2^sieve_k` are walked in full. Above it, only the
+/// survivors of the sieve are walked, from where its jump table takes them.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SieveSummary {
+    #[serde(with = "words")]
+    pub start: u128,
+    #[serde(with = "words")]
+    pub end: u128,
+
+    /// Who asked for this proof, as given in the [Request].
+    pub contributor: Option<String>,
+
+    /// Steps taken from each walked starting value before giving up on it,
+    /// as given in the [Request].
+    pub step_bound: u64,
+
+    /// The size of the [Sieve] used and its [Sieve::digest]. The proof only
+    /// means something if the digest matches `Sieve::new(sieve_k).digest()`.
+    pub sieve_k: u32,
+    pub sieve_digest: Digest,
+
+    /// Number of starting values walked rather than skipped by the sieve.
+    pub walked: u64,
+
+    /// The largest number of steps any walked starting value took to drop
+    /// below itself.
+    pub max_glide: u64,
+    /// The first starting value that took `max_glide` steps.
+    #[serde(with = "words")]
+    pub max_glide_arg: u128,
+
+    /// Whether every starting value in the range dropped below itself or is
+    /// 1.
+    pub all_dropped: bool,
+
+    /// The first walked starting value that did not drop below itself.
+    pub counterexample: Option<Counterexample>,
+}
+
+impl SieveSummary {
+    /// Whether `sieve_digest` is that of `Sieve::new(sieve_k)`. The guest
+    /// walks whatever table the host gives it, so without this check the
+    /// skipped starting values are not covered.
+    pub fn has_valid_sieve(&self) -> bool {
+        Sieve::digest_for(self.sieve_k) == Some(self.sieve_digest)
+    }
+}
+
+/// A starting value whose trajectory did not reach 1.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Counterexample {
+    #[serde(with = "words")]
+    pub start: u128,
+    pub outcome: Outcome,
+}
+
+/// The journal committed by the guest.
+///
+/// Each variant is a kind of result at a given layout version. New layouts
+/// are added as new variants so that receipts proven by older guests keep
+/// decoding.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Journal {
+    TrajectoryV1(TrajectoryJournal),
+    RangeV1(RangeSummary),
+    SieveRangeV1(SieveSummary),
+}
+
+impl Journal {
+    /// The starting value this journal was proven for, or the first one of a
+    /// range.
+    pub fn start(&self) -> u128 {
+        match self {
+            Journal::TrajectoryV1(trajectory) => trajectory.start,
+            Journal::RangeV1(summary) => summary.start,
+            Journal::SieveRangeV1(summary) => summary.start,
+        }
+    }
+
+    pub fn contributor(&self) -> Option<&str> {
+        match self {
+            Journal::TrajectoryV1(trajectory) => trajectory.contributor.as_deref(),
+            Journal::RangeV1(summary) => summary.contributor.as_deref(),
+            Journal::SieveRangeV1(summary) => summary.contributor.as_deref(),
+        }
+    }
+}
+
+/// Accumulates the parity of each step of a trajectory, 1 for a `3n + 1` step
+/// and 0 for a halving step, packed least significant bit first.
+#[derive(Clone, Debug, Default)]
+pub struct ParityVector {
+    bytes: Vec<u8>,
+    len: u64,
+}
+
+impl ParityVector {
+    pub fn push(&mut self, odd: bool) {
+        let bit = (self.len % 8) as u8;
+        if bit == 0 {
+            self.bytes.push(0);
+        }
+        if odd {
+            *self.bytes.last_mut().unwrap() |= 1 << bit;
+        }
+        self.len += 1;
+    }
+
+    /// The SHA-256 of the packed parity bits.
+    pub fn digest(&self) -> Digest {
+        *Impl::hash_bytes(&self.bytes)
+    }
+}
+
+/// Narrow a trajectory value to a `u128`, if it fits.
+pub fn value_to_u128(value: &Value) -> Option<u128> {
+    if value[4..].iter().any(|w| *w != 0) {
+        return None;
+    }
+    Some(
+        value[..4]
+            .iter()
+            .rev()
+            .fold(0u128, |acc, word| (acc << 32) | *word as u128),
+    )
+}
+
+/// Format a trajectory value in decimal.
+pub fn value_to_string(value: &Value) -> String {
+    let mut words = *value;
+    let mut digits = Vec::new();
+    loop {
+        // Long division by 10^9, from the most significant word down.
+        let mut rem = 0u64;
+        for word in words.iter_mut().rev() {
+            let acc = (rem << 32) | *word as u64;
+            *word = (acc / 1_000_000_000) as u32;
+            rem = acc % 1_000_000_000;
+        }
+        if words.iter().all(|w| *w == 0) {
+            digits.push(rem.to_string());
+            break;
+        }
+        digits.push(format!("{rem:09}"));
+    }
+    digits.reverse();
+    digits.concat()
+}
+
+/// Decode a receipt from the bytes written by
+/// [risc0_zkvm::SessionReceipt::encode], as stored on disk and uploaded to the
+/// API.
+pub fn decode_receipt(data: &[u8]) -> Result<SessionFlatReceipt, risc0_zkvm::serde::Error> {
+    if data.len() % 4 != 0 {
+        return Err(risc0_zkvm::serde::Error::DeserializeUnexpectedEnd);
+    }
+    let words: Vec<u32> = data
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+    risc0_zkvm::serde::from_slice(&words)
+}
+
+/// The zkVM serde format has no 128-bit integers, so the guest exchanges them
+/// as little-endian 32-bit words.
+pub mod words {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(n: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        let n = *n;
+        [n as u32, (n >> 32) as u32, (n >> 64) as u32, (n >> 96) as u32].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        let words = <[u32; 4]>::deserialize(deserializer)?;
+        Ok(words
+            .iter()
+            .rev()
+            .fold(0, |acc, word| (acc << 32) | *word as u128))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use risc0_zkvm::serde::{from_slice, to_vec};
+
+    use super::*;
+
+    fn round_trip<T>(value: &T) -> T
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        from_slice(&to_vec(value).unwrap()).unwrap()
+    }
+
+    fn value(n: u128) -> Value {
+        let mut words = [0; VALUE_WORDS];
+        for (i, word) in words[..4].iter_mut().enumerate() {
+            *word = (n >> (32 * i)) as u32;
+        }
+        words
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let sequence = Request::Sequence {
+            start: u128::MAX - 1,
+            schema: JournalSchema::Stats,
+            contributor: Some("alice".to_string()),
+            step_bound: DEFAULT_STEP_BOUND,
+            pause_every: Some(1000),
+        };
+        assert_eq!(round_trip(&sequence), sequence);
+
+        let range = Request::Range {
+            start: 1,
+            end: 1 << 68,
+            contributor: None,
+            step_bound: 1000,
+        };
+        assert_eq!(round_trip(&range), range);
+
+        let sieve_range = Request::SieveRange {
+            start: 1 << 20,
+            end: 1 << 21,
+            contributor: None,
+            step_bound: 1000,
+            sieve: Sieve::new(10),
+        };
+        assert_eq!(round_trip(&sieve_range), sieve_range);
+    }
+
+    #[test]
+    fn u128_as_words() {
+        let start = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210u128;
+        let request = Request::Range {
+            start,
+            end: start,
+            contributor: None,
+            step_bound: 7,
+        };
+        let words = to_vec(&request).unwrap();
+        assert_eq!(
+            words,
+            vec![
+                1, 0x7654_3210, 0xfedc_ba98, 0x89ab_cdef, 0x0123_4567, 0x7654_3210, 0xfedc_ba98,
+                0x89ab_cdef, 0x0123_4567, 0, 7, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn trajectory_journal_round_trip() {
+        let mut parity = ParityVector::default();
+        for odd in [false, true, false, false, false, false, false] {
+            parity.push(odd);
+        }
+        let journal = Journal::TrajectoryV1(TrajectoryJournal {
+            start: 6,
+            contributor: Some("alice".to_string()),
+            step_bound: DEFAULT_STEP_BOUND,
+            outcome: Outcome::ReachedOne,
+            sequence: Some([6, 3, 10, 5, 16, 8, 4, 2, 1].map(value).to_vec()),
+            stats: Some(TrajectoryStats {
+                total_stopping_time: 8,
+                glide: 1,
+                max_value: value(16),
+                parity_vector_hash: parity.digest(),
+            }),
+        });
+        assert_eq!(round_trip(&journal), journal);
+        assert_eq!(journal.start(), 6);
+        assert_eq!(journal.contributor(), Some("alice"));
+
+        let empty = Journal::TrajectoryV1(TrajectoryJournal {
+            start: 6,
+            contributor: None,
+            step_bound: 2,
+            outcome: Outcome::StepBoundExceeded {
+                last_value: value(3),
+            },
+            sequence: None,
+            stats: None,
+        });
+        assert_eq!(round_trip(&empty), empty);
+    }
+
+    #[test]
+    fn range_journal_round_trip() {
+        let journal = Journal::RangeV1(RangeSummary {
+            start: 1,
+            end: 10,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+            max_stopping_time: 19,
+            max_stopping_time_arg: 9,
+            max_excursion: value(52),
+            max_excursion_arg: 7,
+            all_reached_one: true,
+            counterexample: None,
+        });
+        assert_eq!(round_trip(&journal), journal);
+
+        // With a step bound of 100, 27 is the only value in [1, 30) that
+        // does not reach 1.
+        let journal = Journal::RangeV1(RangeSummary {
+            start: 1,
+            end: 30,
+            contributor: None,
+            step_bound: 100,
+            max_stopping_time: 23,
+            max_stopping_time_arg: 25,
+            max_excursion: value(160),
+            max_excursion_arg: 15,
+            all_reached_one: false,
+            counterexample: Some(Counterexample {
+                start: 27,
+                outcome: Outcome::StepBoundExceeded {
+                    last_value: value(53),
+                },
+            }),
+        });
+        assert_eq!(round_trip(&journal), journal);
+    }
+
+    #[test]
+    fn sieve_digest() {
+        let mut summary = SieveSummary {
+            start: 1 << 10,
+            end: 1 << 11,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+            sieve_k: 8,
+            sieve_digest: Sieve::new(8).digest(),
+            walked: 0,
+            max_glide: 0,
+            max_glide_arg: 0,
+            all_dropped: true,
+            counterexample: None,
+        };
+        assert!(summary.has_valid_sieve());
+
+        let mut sieve = Sieve::new(8);
+        sieve.survivors.pop();
+        summary.sieve_digest = sieve.digest();
+        assert!(!summary.has_valid_sieve());
+
+        summary.sieve_k = MAX_SIEVE_K + 1;
+        assert!(!summary.has_valid_sieve());
+    }
+
+    #[test]
+    fn receipt_bytes() {
+        let receipt = SessionFlatReceipt {
+            segments: vec![],
+            journal: to_vec(&Journal::RangeV1(RangeSummary {
+                start: 1,
+                end: 2,
+                contributor: None,
+                step_bound: DEFAULT_STEP_BOUND,
+                max_stopping_time: 0,
+                max_stopping_time_arg: 1,
+                max_excursion: value(1),
+                max_excursion_arg: 1,
+                all_reached_one: true,
+                counterexample: None,
+            }))
+            .unwrap()
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect(),
+        };
+        let data: Vec<u8> = to_vec(&receipt)
+            .unwrap()
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        assert_eq!(decode_receipt(&data).unwrap(), receipt);
+        assert!(decode_receipt(&data[1..]).is_err());
+    }
+
+    #[test]
+    fn decimal_values() {
+        assert_eq!(value_to_string(&value(0)), "0");
+        assert_eq!(value_to_string(&value(9232)), "9232");
+        assert_eq!(value_to_string(&value(u128::MAX)), u128::MAX.to_string());
+
+        let mut big = [0; VALUE_WORDS];
+        big[4] = 1;
+        assert_eq!(
+            value_to_string(&big),
+            "340282366920938463463374607431768211456"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Residue-class sieves for skipping starting values.
+//!
+//! Write `n = 2^k m + r` with `m >= 1`. The parity of the first `k` steps of
+//! `T(n) = n / 2` or `(3n + 1) / 2` depends only on `r`, and after them
+//! `T^k(n) = 3^c m + T^k(r)`, where `c` counts the odd steps. For most
+//! residues some prefix of those steps already drops below `n` for every
+//! `m >= 1`, so such `n` need no further checking once every smaller value is
+//! known to reach 1. The residues left over, the survivors, are walked from
+//! `T^k(n)` onwards instead of from `n`.
+
+use risc0_zkvm::sha::{Digest, Impl, Sha256};
+use serde::{Deserialize, Serialize};
+
+/// The largest supported `k`. Larger sieves save little more, and would let
+/// anyone make verifiers rebuild a table of billions of residues.
+pub const MAX_SIEVE_K: u32 = 20;
+
+/// The digest of `Sieve::new(k)` for each `k` from 1 to [MAX_SIEVE_K], so
+/// that verifiers need not rebuild the sieve to check a journal.
+#[rustfmt::skip]
+const DIGESTS: [Digest; MAX_SIEVE_K as usize] = [
+    Digest::new([0xba728c0d, 0x11ed51bd, 0xe5e96b4d, 0xd8e003a2, 0xc2869c14, 0x5425a3f6, 0x0938f3f4, 0x5d8d2b9c]),
+    Digest::new([0xd9417daf, 0x202ae8c3, 0xa6b2b409, 0x17f2ddf4, 0x3dc37cfb, 0xd096fb0d, 0xe445f59e, 0x26799247]),
+    Digest::new([0x9d7e3fdb, 0xccf2caf0, 0x03346f24, 0xf4d5e06b, 0xc8b254e1, 0x061ac4d8, 0x46e91d64, 0xc7618c55]),
+    Digest::new([0xeac48fa1, 0xbfb412c6, 0x85d11477, 0xd1858eab, 0xa6b500cc, 0xc11b2f68, 0x21a936d0, 0x5a14f161]),
+    Digest::new([0xff09edf2, 0x9f8b7a49, 0x692049fc, 0xbbed60da, 0xfe115cb1, 0x6d3c451d, 0xc5821548, 0xfeb7f662]),
+    Digest::new([0x7f27c19a, 0x4eeb18e8, 0x2829acfa, 0x38498c22, 0x8e039152, 0xa00a38ac, 0x9d76c962, 0x8a2c6cef]),
+    Digest::new([0x1afcd72d, 0xf2bc3d6c, 0xcd9361fd, 0xadc86640, 0x09a036af, 0x95459afe, 0x1f688c90, 0x8680c166]),
+    Digest::new([0xc7014fce, 0xc238ece1, 0xdcac5a33, 0xdcb1f672, 0x8da4e7a6, 0x7a42f00c, 0x370585e7, 0xe4896398]),
+    Digest::new([0x1a222de6, 0x7a28af0a, 0x2d55ebe1, 0xf4cf3941, 0x92286a46, 0x4d3f3e74, 0x70c9204c, 0xd8883a83]),
+    Digest::new([0x3e52d438, 0x4adf3fd1, 0x5f3772d1, 0x96de177d, 0x99e0e03b, 0x4122b95a, 0xe57de26d, 0xcc4fd244]),
+    Digest::new([0xbb073c50, 0x85d76469, 0xb834f9dc, 0x673f9734, 0x02851466, 0x796165c0, 0x314552c4, 0x0488b027]),
+    Digest::new([0xa1d0c54b, 0xe2d95b04, 0xa456e8fc, 0xa091198c, 0x81840308, 0xc06dfc48, 0x9472ac2f, 0xdc2d6132]),
+    Digest::new([0x4db476c4, 0x44b1a3fd, 0xf5ca3473, 0x4f56b412, 0x9414d375, 0x5118d613, 0x74f12a05, 0x8afeafe6]),
+    Digest::new([0xa6190ba3, 0xab1586fa, 0xcb855bd6, 0x0fccd82a, 0xfdaf7afa, 0x516e7891, 0x59c3fb28, 0xffe59a61]),
+    Digest::new([0x6acc140a, 0xfa6f1f27, 0xf15f1b7a, 0x9ce46554, 0x722818f6, 0x1e326e52, 0x33881106, 0x56757630]),
+    Digest::new([0x670ee90f, 0x8323a21e, 0xb73755fc, 0x6e1c30f9, 0x5d5b0579, 0x743ffa9a, 0xc9d84560, 0x25b6d110]),
+    Digest::new([0x029ac2c2, 0x9422526d, 0x8d4ad64a, 0x55d1d0be, 0xc1e3f640, 0x31144091, 0xb197c165, 0xbcb36acd]),
+    Digest::new([0xef9afb61, 0xc38d7c64, 0xf499a39f, 0xc2a46e66, 0xfec251e5, 0x9fc849c7, 0x54bab219, 0x4576e879]),
+    Digest::new([0xb78158d3, 0xbf5a6e49, 0xf3f85727, 0xf42427ff, 0x63cb0498, 0xa08c3883, 0xa66f3a62, 0xeb749175]),
+    Digest::new([0x977a1cc9, 0xf4f0cbfc, 0x567c0ed4, 0xf716c87e, 0xffa10ec6, 0x63d8cc75, 0xbd5ce3fc, 0x069475c5]),
+];
+
+/// A `2^k` sieve: the residues that survive it, in increasing order, with
+/// the jump over their first `k` steps.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Sieve {
+    pub k: u32,
+    pub survivors: Vec<Jump>,
+}
+
+/// The first `k` steps of `n = 2^k m + residue`, which take `n` to
+/// `3^odd_steps * m + value`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Jump {
+    pub residue: u64,
+    pub odd_steps: u32,
+    pub value: u64,
+}
+
+impl Jump {
+    /// The jump for `residue` if no prefix of its first `k` steps drops below
+    /// every `n >= 2^k` in its class.
+    fn survivor(residue: u64, k: u32) -> Option<Self> {
+        let n_min = (1u128 << k) + residue as u128;
+        // After j steps, T^j(n) = (3^odd_steps n + offset) / 2^j.
+        let mut value = residue as u128;
+        let mut odd_steps = 0;
+        let mut offset = 0u128;
+        for j in 0..k {
+            if value % 2 == 1 {
+                value = (3 * value + 1) / 2;
+                odd_steps += 1;
+                offset = 3 * offset + (1 << j);
+            } else {
+                value /= 2;
+            }
+            let pow2 = 1u128 << (j + 1);
+            let pow3 = 3u128.pow(odd_steps);
+            if pow3 < pow2 && (pow2 - pow3) * n_min > offset {
+                return None;
+            }
+        }
+        Some(Jump {
+            residue,
+            odd_steps,
+            value: value as u64,
+        })
+    }
+
+    /// Standard steps, counting `n / 2` and `3n + 1` separately, that the
+    /// jump stands for.
+    pub fn steps(&self, k: u32) -> u64 {
+        (k + self.odd_steps) as u64
+    }
+}
+
+impl Sieve {
+    /// Compute the `2^k` sieve. This walks every residue, so it takes time
+    /// proportional to `k 2^k`.
+    pub fn new(k: u32) -> Self {
+        assert!(
+            (1..=MAX_SIEVE_K).contains(&k),
+            "Sieve size must be between 2^1 and 2^{MAX_SIEVE_K}"
+        );
+        let survivors = (0..1u64 << k)
+            .filter_map(|residue| Jump::survivor(residue, k))
+            .collect();
+        Self { k, survivors }
+    }
+
+    /// The SHA-256 of `k` and every survivor, committed by the guest so that
+    /// verifiers can tell which table a proof relied on.
+    pub fn digest(&self) -> Digest {
+        let mut bytes = Vec::with_capacity(4 + 20 * self.survivors.len());
+        bytes.extend_from_slice(&self.k.to_le_bytes());
+        for jump in &self.survivors {
+            bytes.extend_from_slice(&jump.residue.to_le_bytes());
+            bytes.extend_from_slice(&jump.odd_steps.to_le_bytes());
+            bytes.extend_from_slice(&jump.value.to_le_bytes());
+        }
+        *Impl::hash_bytes(&bytes)
+    }
+
+    /// The digest of `Sieve::new(k)`, or [None] if `k` is not supported.
+    /// Unlike building the sieve, this takes no time.
+    pub fn digest_for(k: u32) -> Option<Digest> {
+        k.checked_sub(1)
+            .and_then(|index| DIGESTS.get(index as usize))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The shortcut map, for checking jumps against.
+    fn t(n: u128) -> u128 {
+        if n % 2 == 1 {
+            (3 * n + 1) / 2
+        } else {
+            n / 2
+        }
+    }
+
+    #[test]
+    fn survivors() {
+        // Only 7, 11 and 15 survive mod 16, and 19 of 256 residues mod 2^8.
+        let sieve = Sieve::new(4);
+        let residues: Vec<u64> = sieve.survivors.iter().map(|jump| jump.residue).collect();
+        assert_eq!(residues, vec![7, 11, 15]);
+        assert_eq!(Sieve::new(8).survivors.len(), 19);
+        assert_eq!(Sieve::new(16).survivors.len(), 2114);
+
+        for k in [4, 8, 10] {
+            let sieve = Sieve::new(k);
+            for jump in &sieve.survivors {
+                for m in 1..4u128 {
+                    let n = (m << k) + jump.residue as u128;
+                    let jumped = (0..k).fold(n, |n, _| t(n));
+                    assert_eq!(jumped, 3u128.pow(jump.odd_steps) * m + jump.value as u128);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn eliminated_residues_drop() {
+        let k = 8;
+        let sieve = Sieve::new(k);
+        for residue in 0..1u64 << k {
+            if sieve.survivors.iter().any(|jump| jump.residue == residue) {
+                continue;
+            }
+            for m in 1..8u128 {
+                let n = (m << k) + residue as u128;
+                let mut value = n;
+                assert!(
+                    (0..k).any(|_| {
+                        value = t(value);
+                        value < n
+                    }),
+                    "{n} should drop below itself within {k} steps"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn digest() {
+        assert_eq!(Sieve::new(8).digest(), Sieve::new(8).digest());
+        assert_ne!(Sieve::new(8).digest(), Sieve::new(9).digest());
+    }
+
+    #[test]
+    fn digest_table() {
+        for k in 1..=MAX_SIEVE_K {
+            assert_eq!(
+                Sieve::digest_for(k),
+                Some(Sieve::new(k).digest()),
+                "k = {k}"
+            );
+        }
+        assert_eq!(Sieve::digest_for(0), None);
+        assert_eq!(Sieve::digest_for(MAX_SIEVE_K + 1), None);
+    }
+}
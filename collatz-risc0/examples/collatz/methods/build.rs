@@ -12,6 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{collections::HashMap, fs, path::Path};
+
+use risc0_build::{embed_methods_with_options, GuestOptions, HermeticOptions};
+
+/// The toolchain file at the root of the repository. The guest is always
+/// built with the channel it pins, so that every contributor gets the same
+/// image ID.
+const TOOLCHAIN_FILE: &str = "../../../rust-toolchain.toml";
+
+/// The `channel` of the `[toolchain]` table in `rust-toolchain.toml`.
+fn toolchain_channel(path: &Path) -> String {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {err}", path.display()));
+    contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == "channel")
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .unwrap_or_else(|| panic!("{} does not set a toolchain channel", path.display()))
+}
+
 fn main() {
-    risc0_build::embed_methods();
+    println!("cargo:rerun-if-changed={TOOLCHAIN_FILE}");
+    let toolchain = toolchain_channel(Path::new(TOOLCHAIN_FILE));
+    embed_methods_with_options(HashMap::from([(
+        "collatz",
+        GuestOptions {
+            hermetic: Some(HermeticOptions { toolchain }),
+            ..Default::default()
+        },
+    )]));
 }
@@ -14,40 +14,438 @@
 
 #![no_main]
 
+use core::cmp::Ordering;
+
+use collatz_core::{
+    value_to_u128, Counterexample, Journal, JournalSchema, Outcome, ParityVector, RangeSummary,
+    Request, Sieve, SieveSummary, TrajectoryJournal, TrajectoryStats, MAX_SIEVE_K,
+};
 use risc0_zkvm::guest::env;
+use risc0_zkvm_platform::syscall::{bigint, sys_bigint};
 
 risc0_zkvm::guest::entry!(main);
 
+/// A trajectory value as little-endian 32-bit words, the width handled by the
+/// bigint accelerator.
+type Words = [u32; bigint::WIDTH_WORDS];
+
+const THREE: Words = [3, 0, 0, 0, 0, 0, 0, 0];
+const ZERO: Words = [0; bigint::WIDTH_WORDS];
+
 pub fn main() {
-    let n: i32 = env::read();
+    match env::read() {
+        Request::Sequence {
+            start,
+            schema,
+            contributor,
+            step_bound,
+            pause_every,
+        } => {
+            let journal =
+                Journal::TrajectoryV1(collatz(start, schema, contributor, step_bound, pause_every));
+            env::commit(&journal);
+        }
+        Request::Range {
+            start,
+            end,
+            contributor,
+            step_bound,
+        } => {
+            let journal = Journal::RangeV1(summarize(start, end, contributor, step_bound));
+            env::commit(&journal);
+        }
+        Request::SieveRange {
+            start,
+            end,
+            contributor,
+            step_bound,
+            sieve,
+        } => {
+            let journal =
+                Journal::SieveRangeV1(sieve_range(start, end, contributor, step_bound, &sieve));
+            env::commit(&journal);
+        }
+    }
+}
+
+fn summarize(
+    start: u128,
+    end: u128,
+    contributor: Option<String>,
+    step_bound: u64,
+) -> RangeSummary {
+    assert!(start != 0, "0 has no Collatz trajectory");
+
+    let mut summary = RangeSummary {
+        start,
+        end,
+        contributor,
+        step_bound,
+        max_stopping_time: 0,
+        max_stopping_time_arg: start,
+        max_excursion: ZERO,
+        max_excursion_arg: start,
+        all_reached_one: true,
+        counterexample: None,
+    };
+
+    let mut max_excursion = Value::Small(0);
+    for n in start..end {
+        let mut excursion = Value::Small(n);
+        let (stopping_time, outcome) = walk(Value::Small(n), step_bound, |_, next| {
+            if next > excursion {
+                excursion = next;
+            }
+        });
+        if outcome != Outcome::ReachedOne {
+            summary.all_reached_one = false;
+            summary.counterexample.get_or_insert(Counterexample { start: n, outcome });
+            continue;
+        }
+        if stopping_time > summary.max_stopping_time {
+            summary.max_stopping_time = stopping_time;
+            summary.max_stopping_time_arg = n;
+        }
+        if excursion > max_excursion {
+            max_excursion = excursion;
+            summary.max_excursion_arg = n;
+        }
+    }
+    summary.max_excursion = max_excursion.to_words();
+
+    summary
+}
+
+fn sieve_range(
+    start: u128,
+    end: u128,
+    contributor: Option<String>,
+    step_bound: u64,
+    sieve: &Sieve,
+) -> SieveSummary {
+    assert!(start != 0, "0 has no Collatz trajectory");
+    assert!(
+        (1..=MAX_SIEVE_K).contains(&sieve.k),
+        "Sieve size must be between 2^1 and 2^{MAX_SIEVE_K}"
+    );
+
+    let mut summary = SieveSummary {
+        start,
+        end,
+        contributor,
+        step_bound,
+        sieve_k: sieve.k,
+        sieve_digest: sieve.digest(),
+        walked: 0,
+        max_glide: 0,
+        max_glide_arg: start,
+        all_dropped: true,
+        counterexample: None,
+    };
+
+    let mut check = |n: u128, value: Value, steps: u64| {
+        summary.walked += 1;
+        match descend(n, value, steps, step_bound) {
+            Ok(glide) if glide > summary.max_glide => {
+                summary.max_glide = glide;
+                summary.max_glide_arg = n;
+            }
+            Ok(_) => {}
+            Err(outcome) => {
+                summary.all_dropped = false;
+                summary.counterexample.get_or_insert(Counterexample { start: n, outcome });
+            }
+        }
+    };
+
+    // The sieve only speaks for starting values of at least 2^k.
+    let k = sieve.k;
+    let modulus = 1u128 << k;
+    for n in start..end.min(modulus) {
+        check(n, Value::Small(n), 0);
+    }
+    if end > modulus {
+        for m in start.max(modulus) >> k..=(end - 1) >> k {
+            for jump in &sieve.survivors {
+                let n = (m << k) | jump.residue as u128;
+                if n < start {
+                    continue;
+                }
+                if n >= end {
+                    break;
+                }
+                let jumped = 3u128
+                    .pow(jump.odd_steps)
+                    .checked_mul(m)
+                    .and_then(|value| value.checked_add(jump.value as u128));
+                match jumped {
+                    Some(value) => check(n, Value::Small(value), jump.steps(k)),
+                    None => check(n, Value::Small(n), 0),
+                }
+            }
+        }
+    }
+
+    summary
+}
+
+/// Continue the trajectory of `n` from `value`, reached after `steps` steps,
+/// until it drops below `n` or reaches 1. Returns the total number of steps,
+/// or how the trajectory failed to drop.
+///
+/// Cycles are detected with Brent's algorithm, as in [walk], so that one
+/// which stays above `n` without passing through it is caught too.
+fn descend(n: u128, mut value: Value, mut steps: u64, step_bound: u64) -> Result<u64, Outcome> {
+    let start = Value::Small(n);
+    let mut saved = value;
+    let mut power = 1;
+    let mut lambda = 0;
+    while value >= start && !value.is_one() {
+        if steps >= step_bound {
+            return Err(Outcome::StepBoundExceeded {
+                last_value: value.to_words(),
+            });
+        }
+        value = match value.step() {
+            Some(next) => next,
+            None => {
+                return Err(Outcome::Overflow {
+                    last_value: value.to_words(),
+                })
+            }
+        };
+        steps += 1;
+        lambda += 1;
+
+        if value == saved {
+            // `value` is on a cycle of length `lambda` that never drops below
+            // `n`.
+            let mut members = Vec::new();
+            for _ in 0..lambda {
+                members.push(value.to_words());
+                value = value.step().expect("cycle members were stepped before");
+            }
+            return Err(Outcome::NontrivialCycle { members });
+        }
+        if lambda == power {
+            saved = value;
+            power *= 2;
+            lambda = 0;
+        }
+    }
+    Ok(steps)
+}
+
+fn collatz(
+    start: u128,
+    schema: JournalSchema,
+    contributor: Option<String>,
+    step_bound: u64,
+    pause_every: Option<u64>,
+) -> TrajectoryJournal {
+    assert!(start != 0, "0 has no Collatz trajectory");
+
+    let mut sequence = schema.has_sequence().then(|| vec![Value::Small(start).to_words()]);
+    let mut parity = ParityVector::default();
+    let mut taken = 0;
+    let mut glide = 0;
+    let mut max = Value::Small(start);
+
+    let (steps, outcome) = walk(Value::Small(start), step_bound, |n, next| {
+        taken += 1;
+        parity.push(n.is_odd());
+        if glide == 0 && next < Value::Small(start) {
+            glide = taken;
+        }
+        if next > max {
+            max = next;
+        }
+        if let Some(sequence) = sequence.as_mut() {
+            sequence.push(next.to_words());
+        }
+        if pause_every.map_or(false, |k| taken % k == 0) {
+            // Everything needed to continue is in memory, so the host can
+            // prove this chunk and resume from the paused image.
+            env::pause();
+        }
+    });
+
+    let stats = schema.has_stats().then(|| TrajectoryStats {
+        total_stopping_time: steps,
+        glide,
+        max_value: max.to_words(),
+        parity_vector_hash: parity.digest(),
+    });
+
+    TrajectoryJournal {
+        start,
+        contributor,
+        step_bound,
+        outcome,
+        sequence,
+        stats,
+    }
+}
+
+/// Walk the trajectory of `n` until it reaches 1, enters a cycle that does not
+/// contain 1, grows too large for a [Value], or `step_bound` steps have been
+/// taken, calling `visit` with each step's value and its successor. Returns
+/// the number of steps taken.
+///
+/// Cycles are detected with Brent's algorithm, which needs no memory beyond
+/// a single saved value.
+fn walk(mut n: Value, step_bound: u64, mut visit: impl FnMut(Value, Value)) -> (u64, Outcome) {
+    let mut steps = 0;
+    let mut saved = n;
+    let mut power = 1;
+    let mut lambda = 0;
+
+    while !n.is_one() {
+        if steps == step_bound {
+            return (
+                steps,
+                Outcome::StepBoundExceeded {
+                    last_value: n.to_words(),
+                },
+            );
+        }
+
+        let Some(next) = n.step() else {
+            return (
+                steps,
+                Outcome::Overflow {
+                    last_value: n.to_words(),
+                },
+            );
+        };
+        visit(n, next);
+        n = next;
+        steps += 1;
+        lambda += 1;
 
-    let seq = collatz(n);
+        if n == saved {
+            // `n` is on a cycle of length `lambda` that never reached 1.
+            let mut members = Vec::new();
+            for _ in 0..lambda {
+                members.push(n.to_words());
+                n = n.step().expect("cycle members were stepped before");
+            }
+            return (steps, Outcome::NontrivialCycle { members });
+        }
+        if lambda == power {
+            saved = n;
+            power *= 2;
+            lambda = 0;
+        }
+    }
 
-    env::commit(&seq);
+    (steps, Outcome::ReachedOne)
 }
 
-fn collatz(mut n: i32) -> Vec<i32> {
-    let mut output: Vec<i32> = Vec::new();
-    output.push(n);
+/// A value in a Collatz trajectory.
+///
+/// Most of a trajectory fits in a native `u128`, so that is the fast path.
+/// Excursions that overflow it fall back to 256-bit words, where `3n + 1` is
+/// computed with the bigint accelerator. Past 256 bits, the trajectory ends
+/// with [Outcome::Overflow].
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Small(u128),
+    Big(Words),
+}
 
-    while n != 1 {
-        if n % 2 == 0 {
-            n /= 2;
-        } else {
-            n = 3 * n + 1;
+impl Value {
+    fn from_words(words: &Words) -> Self {
+        match value_to_u128(words) {
+            Some(n) => Value::Small(n),
+            None => Value::Big(*words),
         }
+    }
 
-        output.push(n);
+    fn to_words(self) -> Words {
+        match self {
+            Value::Small(n) => {
+                let mut words = ZERO;
+                for (i, word) in words[..4].iter_mut().enumerate() {
+                    *word = (n >> (32 * i)) as u32;
+                }
+                words
+            }
+            Value::Big(words) => words,
+        }
     }
 
-    // TODO cylic detection
-    // TODO divergence detection (through the VM crash)
+    fn is_one(&self) -> bool {
+        matches!(self, Value::Small(1))
+    }
 
-    // two cases:
-    // - one is a cycle that is not 1 2 4
-    // - sequence diverges
-    // or something else??? cosmic rays or zk bugs otherwise
-    //
+    fn is_odd(&self) -> bool {
+        match self {
+            Value::Small(n) => n % 2 == 1,
+            Value::Big(words) => words[0] % 2 == 1,
+        }
+    }
 
-    output
+    /// The next value of the trajectory, or [None] if it does not fit in 256
+    /// bits.
+    fn step(self) -> Option<Self> {
+        match self {
+            Value::Small(n) if !self.is_odd() => Some(Value::Small(n / 2)),
+            Value::Small(n) => match n.checked_mul(3).and_then(|n| n.checked_add(1)) {
+                Some(n) => Some(Value::Small(n)),
+                None => Value::Big(self.to_words()).step(),
+            },
+            Value::Big(words) if !self.is_odd() => Some(Value::from_words(&halve(&words))),
+            Value::Big(words) => triple_plus_one(&words).map(|words| Value::from_words(&words)),
+        }
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Small(a), Value::Small(b)) => a.cmp(b),
+            (Value::Small(_), Value::Big(_)) => Ordering::Less,
+            (Value::Big(_), Value::Small(_)) => Ordering::Greater,
+            (Value::Big(a), Value::Big(b)) => a.iter().rev().cmp(b.iter().rev()),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn halve(words: &Words) -> Words {
+    let mut out = ZERO;
+    for i in 0..bigint::WIDTH_WORDS {
+        let carry = words.get(i + 1).map_or(0, |hi| hi << 31);
+        out[i] = (words[i] >> 1) | carry;
+    }
+    out
+}
+
+/// `3n + 1`, or [None] if it does not fit in 256 bits.
+fn triple_plus_one(words: &Words) -> Option<Words> {
+    // 3n + 1 stays below 2^256 as long as n < 0x55555555 * 2^224.
+    if words[bigint::WIDTH_WORDS - 1] >= 0x5555_5555 {
+        return None;
+    }
+
+    // A zero modulus makes the accelerator perform a plain multiplication.
+    let mut out = ZERO;
+    unsafe {
+        sys_bigint(&mut out, bigint::OP_MULTIPLY, words, &THREE, &ZERO);
+    }
+
+    for word in out.iter_mut() {
+        let (sum, overflow) = word.overflowing_add(1);
+        *word = sum;
+        if !overflow {
+            break;
+        }
+    }
+    Some(out)
 }
@@ -0,0 +1,312 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Estimating what a proof will cost before proving it.
+//!
+//! Executing the guest is cheap compared to proving it, and fixes the number
+//! and size of the segments to be proven. Proving time and memory both grow
+//! with the padded size `2^po2` of each segment, so they are estimated from
+//! per-cycle figures for each kind of prover.
+
+use std::{fmt, time::Duration};
+
+use risc0_zkvm::{prove::ParallelConfig, ExitCode, Session};
+
+use crate::{execute, ProveError, Request};
+
+/// Rough proving cost per padded cycle on one kind of prover.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Profile {
+    pub time_per_cycle: Duration,
+
+    /// Memory used on this machine while proving a segment.
+    pub bytes_per_cycle: usize,
+}
+
+impl Profile {
+    /// The profile of the prover named `name` by
+    /// [risc0_zkvm::prove::Prover::get_name], or of a remote prover for
+    /// `"bonsai"`.
+    ///
+    /// These are ballpark figures for a recent workstation or GPU; what
+    /// matters for budgets is that they scale with the work.
+    pub fn for_prover(name: &str) -> Self {
+        let (nanos, bytes_per_cycle) = match name {
+            "cpu" => (25_000, 8 << 10),
+            "cpu:poseidon" => (40_000, 8 << 10),
+            "metal" | "metal:poseidon" => (8_000, 8 << 10),
+            "cuda" | "cuda:poseidon" => (3_000, 8 << 10),
+            "bonsai" => (3_000, 0),
+            _ => (25_000, 8 << 10),
+        };
+        Profile {
+            time_per_cycle: Duration::from_nanos(nanos),
+            bytes_per_cycle,
+        }
+    }
+}
+
+/// The size of one segment to be proven.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentEstimate {
+    /// The segment is padded to `2^po2` cycles for proving.
+    pub po2: usize,
+
+    /// Cycles spent executing instructions, before padding.
+    pub insn_cycles: usize,
+}
+
+/// What proving a [Request] is expected to cost.
+#[derive(Clone, Debug)]
+pub struct Estimate {
+    /// Name of the prover the estimate is for.
+    pub prover: String,
+    pub profile: Profile,
+    pub segments: Vec<SegmentEstimate>,
+
+    /// How many segments are to be proven at once, and within what memory.
+    pub parallel: ParallelConfig,
+
+    /// How the execution ended. A paused guest only ran its first chunk.
+    pub exit_code: ExitCode,
+
+    /// The journal a proof would commit.
+    pub journal: Vec<u8>,
+}
+
+impl Estimate {
+    pub fn insn_cycles(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.insn_cycles)
+            .sum()
+    }
+
+    /// Cycles to be proven, after padding each segment.
+    pub fn total_cycles(&self) -> usize {
+        self.segments.iter().map(|segment| 1 << segment.po2).sum()
+    }
+
+    pub fn proving_time(&self) -> Duration {
+        self.profile
+            .time_per_cycle
+            .mul_f64(self.total_cycles() as f64)
+    }
+
+    /// An upper bound on the memory used while proving. Up to
+    /// `parallel.threads` segments are proven at once, so the largest that
+    /// many set the peak, except that no more are started than fit in
+    /// `parallel.max_memory` unless one alone exceeds it.
+    pub fn peak_memory(&self) -> usize {
+        let mut costs: Vec<usize> = self
+            .segments
+            .iter()
+            .map(|segment| self.profile.bytes_per_cycle << segment.po2)
+            .collect();
+        costs.sort_unstable_by(|a, b| b.cmp(a));
+        let largest = costs.first().copied().unwrap_or_default();
+        let together: usize = costs.iter().take(self.parallel.threads.max(1)).sum();
+        self.parallel
+            .max_memory
+            .map_or(together, |max| together.min(max.max(largest)))
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let po2s: Vec<usize> = self.segments.iter().map(|segment| segment.po2).collect();
+        writeln!(f, "exit code = {:?}", self.exit_code)?;
+        writeln!(f, "segments = {}, po2 = {po2s:?}", self.segments.len())?;
+        writeln!(f, "proving threads = {}", self.parallel.threads)?;
+        writeln!(
+            f,
+            "instruction cycles = {}, padded cycles = {}",
+            self.insn_cycles(),
+            self.total_cycles()
+        )?;
+        write!(
+            f,
+            "estimated on {}: {:.0?} proving, {} MiB peak memory",
+            self.prover,
+            self.proving_time(),
+            self.peak_memory() >> 20
+        )
+    }
+}
+
+/// Execute `request` without proving it and estimate what proving it on the
+/// prover named `prover` would cost. Names are those of
+/// [risc0_zkvm::prove::get_prover], or `"bonsai"`; see [crate::Prover::name].
+pub fn estimate(
+    prover: &str,
+    request: &Request,
+    parallel: &ParallelConfig,
+) -> Result<Estimate, ProveError> {
+    estimate_session(prover, &execute(request)?, parallel)
+}
+
+/// Estimate what proving the already executed `session` would cost, so that
+/// it can be proven afterwards without executing it again.
+pub fn estimate_session(
+    prover: &str,
+    session: &Session,
+    parallel: &ParallelConfig,
+) -> Result<Estimate, ProveError> {
+    let segments = session
+        .resolve()
+        .map_err(ProveError::Other)?
+        .iter()
+        .map(|segment| SegmentEstimate {
+            po2: segment.po2,
+            insn_cycles: segment.insn_cycles,
+        })
+        .collect();
+    Ok(Estimate {
+        prover: prover.to_string(),
+        profile: Profile::for_prover(prover),
+        segments,
+        parallel: parallel.clone(),
+        exit_code: session.exit_code,
+        journal: session.journal.clone(),
+    })
+}
+
+/// Limits on the cost of a unit a worker is willing to prove.
+#[derive(Clone, Debug, Default)]
+pub struct Budget {
+    pub max_cycles: Option<usize>,
+    pub max_time: Option<Duration>,
+    pub max_memory: Option<usize>,
+}
+
+impl Budget {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_cycles.is_none() && self.max_time.is_none() && self.max_memory.is_none()
+    }
+
+    /// How to prove a unit within this budget: one thread per core, with no
+    /// more segments at once than fit in `max_memory`.
+    pub fn parallel_config(&self) -> ParallelConfig {
+        ParallelConfig {
+            max_memory: self.max_memory,
+            ..Default::default()
+        }
+    }
+
+    /// Whether `estimate` fits, or else which limit it exceeds.
+    pub fn check(&self, estimate: &Estimate) -> Result<(), String> {
+        if let Some(max) = self.max_cycles {
+            if estimate.total_cycles() > max {
+                return Err(format!(
+                    "{} cycles exceeds the budget of {max}",
+                    estimate.total_cycles()
+                ));
+            }
+        }
+        if let Some(max) = self.max_time {
+            if estimate.proving_time() > max {
+                return Err(format!(
+                    "{:.0?} of proving exceeds the budget of {max:?}",
+                    estimate.proving_time()
+                ));
+            }
+        }
+        if let Some(max) = self.max_memory {
+            if estimate.peak_memory() > max {
+                return Err(format!(
+                    "{} MiB of memory exceeds the budget of {} MiB",
+                    estimate.peak_memory() >> 20,
+                    max >> 20
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_STEP_BOUND;
+
+    #[test]
+    fn test_estimate() {
+        let request = Request::Range {
+            start: 1,
+            end: 100,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+        };
+        let parallel = ParallelConfig {
+            threads: 1,
+            max_memory: None,
+        };
+        let estimate = estimate("cpu", &request, &parallel).unwrap();
+        assert_eq!(estimate.prover, "cpu");
+        assert_eq!(estimate.exit_code, ExitCode::Halted(0));
+        assert!(!estimate.segments.is_empty());
+        assert!(estimate.insn_cycles() <= estimate.total_cycles());
+        assert_eq!(estimate.profile, Profile::for_prover(&estimate.prover));
+        assert!(estimate.proving_time() > Duration::ZERO);
+
+        assert!(Budget::default().is_unlimited());
+        assert_eq!(Budget::default().check(&estimate), Ok(()));
+        let budget = Budget {
+            max_cycles: Some(estimate.total_cycles() - 1),
+            ..Default::default()
+        };
+        assert!(budget.check(&estimate).is_err());
+        let budget = Budget {
+            max_time: Some(estimate.proving_time()),
+            max_memory: Some(estimate.peak_memory()),
+            ..Default::default()
+        };
+        assert_eq!(budget.check(&estimate), Ok(()));
+    }
+
+    #[test]
+    fn test_peak_memory() {
+        let segment = |po2| SegmentEstimate {
+            po2,
+            insn_cycles: 1 << po2,
+        };
+        let mut estimate = Estimate {
+            prover: "cpu".to_string(),
+            profile: Profile {
+                time_per_cycle: Duration::ZERO,
+                bytes_per_cycle: 1,
+            },
+            segments: vec![segment(20), segment(18), segment(19)],
+            parallel: ParallelConfig {
+                threads: 1,
+                max_memory: None,
+            },
+            exit_code: ExitCode::Halted(0),
+            journal: Vec::new(),
+        };
+        assert_eq!(estimate.peak_memory(), 1 << 20);
+
+        estimate.parallel.threads = 2;
+        assert_eq!(estimate.peak_memory(), (1 << 20) + (1 << 19));
+        estimate.parallel.threads = 4;
+        assert_eq!(estimate.peak_memory(), (1 << 20) + (1 << 19) + (1 << 18));
+
+        // The prover holds back segments that would not fit together...
+        estimate.parallel.max_memory = Some(1 << 20);
+        assert_eq!(estimate.peak_memory(), 1 << 20);
+        // ...but still proves one that does not fit alone.
+        estimate.parallel.max_memory = Some(1 << 10);
+        assert_eq!(estimate.peak_memory(), 1 << 20);
+    }
+}
@@ -11,48 +11,558 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use std::fmt;
+
+pub use collatz_core::{
+    decode_receipt, value_to_string, value_to_u128, Counterexample, Journal, JournalSchema,
+    Outcome, RangeSummary, Request, Sieve, SieveSummary, TrajectoryJournal, TrajectoryStats, Value,
+    DEFAULT_STEP_BOUND, VALUE_WORDS,
+};
 use collatz_methods::COLLATZ_ELF;
+use remote::{RemoteError, RemoteProver};
 use risc0_zkvm::{
+    prove::default_prover,
     serde::{from_slice, to_vec},
-    Executor, ExecutorEnv, SessionReceipt,
+    Executor, ExecutorEnv, Session, SessionLimitExceeded, SessionReceipt,
 };
 
 // #[doc = include_str!("../README.md")]
 
-pub fn do_collatz(n: i32) -> (Box<dyn SessionReceipt>, i32) {
+pub mod estimate;
+pub mod pause;
+pub mod remote;
+pub mod worker;
+
+/// Cycles allowed for a session before any steps are taken, covering guest
+/// startup and committing the journal.
+const BASE_CYCLES: usize = 1 << 20;
+
+/// Generous upper estimate of the cycles a single step takes, including
+/// recording it in the journal.
+const CYCLES_PER_STEP: usize = 1 << 12;
+
+/// Why a proof could not be produced.
+#[derive(Debug)]
+pub enum ProveError {
+    /// The guest ran past the session cycle limit derived from the step bound.
+    /// This only happens if the step bound failed to stop the guest first,
+    /// for instance because the estimate of cycles per step is too low.
+    SessionLimitExceeded { limit: usize },
+
+    /// The request cannot be proven, such as a range that is empty or starts
+    /// at 0.
+    InvalidRequest(String),
+
+    /// Proving on a remote prover failed.
+    Remote(RemoteError),
+
+    /// Executing or proving failed for any other reason.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for ProveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProveError::SessionLimitExceeded { limit } => {
+                write!(f, "Session limit of {limit} cycles exceeded")
+            }
+            ProveError::InvalidRequest(reason) => write!(f, "Invalid request: {reason}"),
+            ProveError::Remote(err) => write!(f, "{err}"),
+            ProveError::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ProveError {}
+
+impl ProveError {
+    /// Classify an error from running the executor with a session `limit`.
+    fn from_run(err: anyhow::Error, limit: usize) -> Self {
+        if err.is::<SessionLimitExceeded>() {
+            ProveError::SessionLimitExceeded { limit }
+        } else {
+            ProveError::Other(err)
+        }
+    }
+}
+
+/// Where proofs are generated.
+pub enum Prover {
+    /// On this machine.
+    Local,
+
+    /// On a Bonsai-compatible remote prover. Receipts are verified locally
+    /// before they are returned.
+    Remote(RemoteProver),
+}
+
+impl Prover {
+    pub fn prove(&self, request: &Request) -> Result<Box<dyn SessionReceipt>, ProveError> {
+        check_request(request)?;
+        match self {
+            Prover::Local => execute(request)?.prove().map_err(ProveError::Other),
+            Prover::Remote(remote) => remote
+                .prove(request)
+                .map(|receipt| Box::new(receipt) as Box<dyn SessionReceipt>)
+                .map_err(ProveError::Remote),
+        }
+    }
+
+    /// The name of the prover proofs are generated on, as used by
+    /// [estimate::Profile::for_prover]. Local proofs use the zkVM's default
+    /// prover, which `RISC0_PROVER` can override.
+    pub fn name(&self) -> String {
+        match self {
+            Prover::Local => default_prover().get_name(),
+            Prover::Remote(_) => "bonsai".to_string(),
+        }
+    }
+}
+
+/// Prove the trajectory of `n`, committing the parts selected by `schema`.
+///
+/// The `contributor`, if any, is committed alongside `n` so that the receipt
+/// cannot be claimed under a different starting value or identity. The guest
+/// stops after `step_bound` steps, recording the outcome in the journal.
+pub fn do_collatz(
+    prover: &Prover,
+    n: u128,
+    schema: JournalSchema,
+    contributor: Option<String>,
+    step_bound: u64,
+) -> Result<(Box<dyn SessionReceipt>, TrajectoryJournal), ProveError> {
+    let receipt = prover.prove(&Request::Sequence {
+        start: n,
+        schema,
+        contributor,
+        step_bound,
+        pause_every: None,
+    })?;
+
+    let journal: Journal = from_slice(receipt.get_journal()).expect(
+        "Journal output should deserialize into the same types (& order) that it was written",
+    );
+    let Journal::TrajectoryV1(journal) = journal else {
+        panic!("Expected a trajectory journal, got {journal:?}");
+    };
+
+    Ok((receipt, journal))
+}
+
+/// Prove every starting value in `[start, end)` with a single receipt, taking
+/// at most `step_bound` steps from each.
+pub fn do_collatz_range(
+    prover: &Prover,
+    start: u128,
+    end: u128,
+    contributor: Option<String>,
+    step_bound: u64,
+) -> Result<(Box<dyn SessionReceipt>, RangeSummary), ProveError> {
+    let receipt = prover.prove(&Request::Range {
+        start,
+        end,
+        contributor,
+        step_bound,
+    })?;
+
+    let journal: Journal = from_slice(receipt.get_journal()).expect(
+        "Journal output should deserialize into the same types (& order) that it was written",
+    );
+    let Journal::RangeV1(summary) = journal else {
+        panic!("Expected a range journal, got {journal:?}");
+    };
+
+    Ok((receipt, summary))
+}
+
+/// Prove that every starting value in `[start, end)` drops below itself,
+/// walking only the survivors of `sieve` with a single receipt.
+///
+/// Together with a proof that every value below `start` reaches 1, this
+/// shows that the whole range does, at a fraction of the cycles of
+/// [do_collatz_range].
+pub fn do_collatz_sieve(
+    prover: &Prover,
+    start: u128,
+    end: u128,
+    contributor: Option<String>,
+    step_bound: u64,
+    sieve: Sieve,
+) -> Result<(Box<dyn SessionReceipt>, SieveSummary), ProveError> {
+    let receipt = prover.prove(&Request::SieveRange {
+        start,
+        end,
+        contributor,
+        step_bound,
+        sieve,
+    })?;
+
+    let journal: Journal = from_slice(receipt.get_journal()).expect(
+        "Journal output should deserialize into the same types (& order) that it was written",
+    );
+    let Journal::SieveRangeV1(summary) = journal else {
+        panic!("Expected a sieve range journal, got {journal:?}");
+    };
+
+    Ok((receipt, summary))
+}
+
+/// Run the guest on `request` without proving it, e.g. to measure how many
+/// cycles and segments a proof would take.
+pub fn execute(request: &Request) -> Result<Session, ProveError> {
+    check_request(request)?;
+    run(request, session_limit(request))
+}
+
+/// Reject requests the guest would refuse to run.
+///
+/// 0 is a fixed point of the Collatz map, so a trajectory or range that
+/// includes it would report a cycle that says nothing about the conjecture.
+/// A guest told to pause every 0 steps would never get past its first step.
+pub(crate) fn check_request(request: &Request) -> Result<(), ProveError> {
+    match request {
+        Request::Sequence { start: 0, .. } => Err(ProveError::InvalidRequest(
+            "0 has no Collatz trajectory".to_string(),
+        )),
+        Request::Sequence {
+            pause_every: Some(0),
+            ..
+        } => Err(ProveError::InvalidRequest(
+            "cannot pause every 0 steps".to_string(),
+        )),
+        Request::Sequence { .. } => Ok(()),
+        Request::Range { start, end, .. } | Request::SieveRange { start, end, .. } => {
+            if *start == 0 {
+                Err(ProveError::InvalidRequest(format!(
+                    "range [{start}, {end}) starts at 0, which has no Collatz trajectory"
+                )))
+            } else if start >= end {
+                Err(ProveError::InvalidRequest(format!(
+                    "range [{start}, {end}) is empty"
+                )))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The session cycle limit for walking the trajectories of `request`, each
+/// at most its step bound long.
+///
+/// A paused guest starts a new session every `pause_every` steps, so only
+/// that many need to fit, unless the sequence is committed at the end.
+fn session_limit(request: &Request) -> usize {
+    let (step_bound, count) = match request {
+        Request::Sequence {
+            schema,
+            step_bound,
+            pause_every: Some(pause_every),
+            ..
+        } if !schema.has_sequence() => (*step_bound.min(pause_every), 1),
+        Request::Sequence { step_bound, .. } => (*step_bound, 1),
+        Request::Range {
+            start,
+            end,
+            step_bound,
+            ..
+        } => (*step_bound, end.saturating_sub(*start)),
+        Request::SieveRange {
+            start,
+            end,
+            step_bound,
+            sieve,
+            ..
+        } => {
+            // Everything below 2^k is walked, then the survivors of each
+            // block of 2^k that the range touches.
+            let (start, end) = (*start, *end);
+            let modulus = 1u128 << sieve.k;
+            let below = end.min(modulus).saturating_sub(start);
+            let blocks = end.saturating_sub(start.max(modulus)) / modulus + 1;
+            let survivors = blocks.saturating_mul(sieve.survivors.len() as u128);
+            (*step_bound, below.saturating_add(survivors))
+        }
+    };
+    let steps = (step_bound as u128).saturating_mul(count);
+    let cycles = steps.saturating_mul(CYCLES_PER_STEP as u128);
+    usize::try_from(cycles)
+        .unwrap_or(usize::MAX)
+        .saturating_add(BASE_CYCLES)
+}
+
+fn run(request: &Request, limit: usize) -> Result<Session, ProveError> {
     let env = ExecutorEnv::builder()
-        .add_input(&to_vec(&n).unwrap())
+        .add_input(&to_vec(request).unwrap())
+        .session_limit(Some(limit))
         .build()
         .unwrap();
 
     let mut exec = Executor::from_elf(env, COLLATZ_ELF).unwrap();
 
-    let session = exec.run().unwrap();
+    exec.run().map_err(|err| ProveError::from_run(err, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use collatz_core::ParityVector;
 
-    let receipt = session.prove().unwrap();
+    use super::*;
 
-    // TBD
-    let c: i32 = from_slice(receipt.get_journal()).expect(
-        "Journal output should deserialize into the same types (& order) that it was written",
-    );
+    #[test]
+    fn test_collatz() {
+        let (_, journal) = do_collatz(
+            &Prover::Local,
+            6,
+            JournalSchema::Sequence,
+            None,
+            DEFAULT_STEP_BOUND,
+        )
+        .unwrap();
+        assert!(journal.stats.is_none());
+        assert_eq!(journal.outcome, Outcome::ReachedOne);
+        let sequence: Vec<u128> = journal
+            .sequence
+            .unwrap()
+            .iter()
+            .filter_map(value_to_u128)
+            .collect();
+        assert_eq!(
+            sequence,
+            vec![6, 3, 10, 5, 16, 8, 4, 2, 1],
+            "We expect the zkVM output to be the Collatz trajectory of the input"
+        );
+    }
 
-    (receipt, c)
-}
+    #[test]
+    fn test_collatz_past_u128() {
+        // 2^127 - 1 is odd, so its first step overflows a u128.
+        let n = u128::MAX >> 1;
+        let (_, journal) = do_collatz(
+            &Prover::Local,
+            n,
+            JournalSchema::Sequence,
+            None,
+            DEFAULT_STEP_BOUND,
+        )
+        .unwrap();
+        let sequence = journal.sequence.unwrap();
+        assert_eq!(value_to_u128(&sequence[0]), Some(n));
+        assert_eq!(value_to_u128(&sequence[1]), None);
+        assert_eq!(sequence[1][4], 1);
+        assert_eq!(value_to_u128(sequence.last().unwrap()), Some(1));
+    }
 
-// #[cfg(test)]
-// mod tests {
-//
-// use super::*;
-//
-// #[test]
-// fn test_factors() {
-// const TEST_FACTOR_ONE: u64 = 17;
-// const TEST_FACTOR_TWO: u64 = 23;
-// let (_, result) = multiply_factors(17, 23);
-// assert_eq!(
-// result,
-// TEST_FACTOR_ONE * TEST_FACTOR_TWO,
-// "We expect the zkVM output to be the product of the inputs"
-// )
-// }
-// }
+    #[test]
+    fn test_collatz_stats() {
+        let contributor = Some("alice".to_string());
+        let (_, journal) = do_collatz(
+            &Prover::Local,
+            27,
+            JournalSchema::Stats,
+            contributor.clone(),
+            DEFAULT_STEP_BOUND,
+        )
+        .unwrap();
+        assert_eq!(journal.start, 27);
+        assert_eq!(journal.contributor, contributor);
+        assert!(journal.sequence.is_none());
+        let stats = journal.stats.unwrap();
+        assert_eq!(stats.total_stopping_time, 111);
+        assert_eq!(stats.glide, 96);
+        assert_eq!(value_to_u128(&stats.max_value), Some(9232));
+
+        let (_, full) = do_collatz(
+            &Prover::Local,
+            27,
+            JournalSchema::Full,
+            None,
+            DEFAULT_STEP_BOUND,
+        )
+        .unwrap();
+        let mut parity = ParityVector::default();
+        let sequence = full.sequence.unwrap();
+        for value in &sequence[..sequence.len() - 1] {
+            parity.push(value[0] % 2 == 1);
+        }
+        assert_eq!(full.stats.unwrap().parity_vector_hash, parity.digest());
+    }
+
+    #[test]
+    fn test_collatz_range() {
+        let (_, summary) =
+            do_collatz_range(&Prover::Local, 1, 10, None, DEFAULT_STEP_BOUND).unwrap();
+        assert_eq!(summary.start, 1);
+        assert_eq!(summary.end, 10);
+        assert_eq!(summary.max_stopping_time, 19);
+        assert_eq!(summary.max_stopping_time_arg, 9);
+        assert_eq!(value_to_u128(&summary.max_excursion), Some(52));
+        assert_eq!(summary.max_excursion_arg, 7);
+        assert!(summary.all_reached_one);
+        assert_eq!(summary.counterexample, None);
+    }
+
+    #[test]
+    fn test_execute() {
+        let session = execute(&Request::Sequence {
+            start: 27,
+            schema: JournalSchema::Stats,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+            pause_every: None,
+        })
+        .unwrap();
+        assert!(!session.segments.is_empty());
+        let Journal::TrajectoryV1(journal) = from_slice(&session.journal).unwrap() else {
+            panic!("Expected a trajectory journal");
+        };
+        assert_eq!(journal.stats.unwrap().total_stopping_time, 111);
+    }
+
+    #[test]
+    fn test_collatz_step_bound() {
+        // 27 takes 111 steps to reach 1.
+        let (_, journal) = do_collatz(&Prover::Local, 27, JournalSchema::Stats, None, 10).unwrap();
+        assert_eq!(journal.stats.unwrap().total_stopping_time, 10);
+        let Outcome::StepBoundExceeded { last_value } = journal.outcome else {
+            panic!("Expected the step bound to be exceeded, got {:?}", journal.outcome);
+        };
+        assert_eq!(value_to_u128(&last_value), Some(214));
+
+        let (_, summary) = do_collatz_range(&Prover::Local, 1, 30, None, 100).unwrap();
+        assert!(!summary.all_reached_one);
+        let counterexample = summary.counterexample.unwrap();
+        assert_eq!(counterexample.start, 27);
+        assert!(matches!(
+            counterexample.outcome,
+            Outcome::StepBoundExceeded { .. }
+        ));
+    }
+
+    #[test]
+    fn test_collatz_zero() {
+        assert!(matches!(
+            do_collatz(
+                &Prover::Local,
+                0,
+                JournalSchema::Full,
+                None,
+                DEFAULT_STEP_BOUND
+            ),
+            Err(ProveError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_pause_every_zero() {
+        let request = Request::Sequence {
+            start: 27,
+            schema: JournalSchema::Stats,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+            pause_every: Some(0),
+        };
+        assert!(matches!(
+            check_request(&request),
+            Err(ProveError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_range_from_zero() {
+        // 0 is a fixed point of the map, not a counterexample, so ranges
+        // that include it are refused rather than proven.
+        for result in [
+            do_collatz_range(&Prover::Local, 0, 2, None, DEFAULT_STEP_BOUND).map(|_| ()),
+            do_collatz_sieve(
+                &Prover::Local,
+                0,
+                1 << 9,
+                None,
+                DEFAULT_STEP_BOUND,
+                Sieve::new(8),
+            )
+            .map(|_| ()),
+        ] {
+            assert!(matches!(result, Err(ProveError::InvalidRequest(_))));
+        }
+        assert!(matches!(
+            do_collatz_range(&Prover::Local, 5, 5, None, DEFAULT_STEP_BOUND),
+            Err(ProveError::InvalidRequest(_))
+        ));
+
+        // The guest refuses them too.
+        let request = Request::Range {
+            start: 0,
+            end: 2,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+        };
+        let err = run(&request, session_limit(&request)).err().unwrap();
+        assert!(
+            err.to_string().contains("0 has no Collatz trajectory"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_collatz_sieve() {
+        let (_, summary) = do_collatz_sieve(
+            &Prover::Local,
+            1,
+            1 << 12,
+            None,
+            DEFAULT_STEP_BOUND,
+            Sieve::new(8),
+        )
+        .unwrap();
+        assert_eq!(summary.sieve_k, 8);
+        assert_eq!(summary.sieve_digest, Sieve::new(8).digest());
+        assert!(summary.all_dropped);
+        // All of [1, 256), then 19 survivors in each of the 15 blocks above.
+        assert_eq!(summary.walked, 255 + 15 * 19);
+        // 703 has the longest glide below 2^12, and survives mod 2^8.
+        assert_eq!(summary.max_glide, 132);
+        assert_eq!(summary.max_glide_arg, 703);
+
+        let session = execute(&Request::SieveRange {
+            start: 1 << 12,
+            end: 1 << 13,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+            sieve: Sieve::new(8),
+        })
+        .unwrap();
+        let unsieved = execute(&Request::Range {
+            start: 1 << 12,
+            end: 1 << 13,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+        })
+        .unwrap();
+        let cycles = |session: &Session| -> usize {
+            let segments = session.resolve().unwrap();
+            segments.iter().map(|segment| segment.insn_cycles).sum()
+        };
+        // The sieve should save at least an order of magnitude.
+        assert!(cycles(&session) * 10 < cycles(&unsieved));
+    }
+
+    #[test]
+    fn test_session_limit() {
+        match run(
+            &Request::Sequence {
+                start: 27,
+                schema: JournalSchema::Full,
+                contributor: None,
+                step_bound: DEFAULT_STEP_BOUND,
+                pause_every: None,
+            },
+            1 << 10,
+        ) {
+            Err(ProveError::SessionLimitExceeded { limit }) => assert_eq!(limit, 1 << 10),
+            Err(err) => panic!("Expected the session limit to be exceeded, got {err}"),
+            Ok(_) => panic!("Expected the session limit to be exceeded"),
+        }
+    }
+}
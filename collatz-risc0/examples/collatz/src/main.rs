@@ -12,78 +12,646 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::env;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+    thread::sleep,
+    time::Duration,
+};
 
-use collatz::do_collatz;
-use collatz_methods::COLLATZ_ID;
+use bonsai_sdk::alpha::PollConfig;
+use clap::{Args, Parser, Subcommand};
+use collatz::{
+    decode_receipt, do_collatz, do_collatz_range, do_collatz_sieve,
+    estimate::{estimate, Budget},
+    pause::{do_collatz_paused, verify_chain},
+    remote::RemoteProver,
+    value_to_string,
+    worker::{work_once, WorkError},
+    Journal, JournalSchema, Outcome, ProveError, Prover, Request, Sieve, DEFAULT_STEP_BOUND,
+    MAX_SIEVE_K,
+};
+use collatz_coordinator::client::Client;
+use collatz_methods::{COLLATZ_ID, COLLATZ_MANIFEST};
 use rand::distributions::{Distribution, Uniform};
 use reqwest::{self};
-use risc0_zkvm::serde::from_slice;
+use risc0_zkvm::{
+    prove::ParallelConfig, serde::from_slice, ExitCode, SessionFlatReceipt, SessionReceipt,
+};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Journal {
-    pub sequence: Vec<u32>,
-}
-
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Output {
-    pub output_sequence: Vec<u32>,
+    pub input_value: u128,
+    pub contributor: Option<String>,
+    /// In decimal, since values may exceed a u128 on the way to 1.
+    pub output_sequence: Vec<String>,
     pub proof: Vec<u8>,
     pub image_id: [u32; 8],
 }
 
 const DEFAULT_API_URL: &'static str = "http://localhost:8000/public/data/actions/create";
-const DEFAULT_N: i32 = 100_000_000;
+const DEFAULT_COORDINATOR_URL: &str = "http://localhost:8002";
+const DEFAULT_N: u128 = 100_000_000;
+
+/// How long a worker waits after refusing a unit, doubling with each refusal
+/// in a row.
+const MIN_REFUSAL_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_REFUSAL_BACKOFF: Duration = Duration::from_secs(600);
+
+#[derive(Parser)]
+#[command(about, version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prove a starting value or range, writing the receipt and journal to
+    /// disk.
+    Prove {
+        #[command(flatten)]
+        input: Input,
+
+        #[command(flatten)]
+        prover: ProverArgs,
+
+        /// Directory to write `<name>.receipt` and `<name>.journal.json` to.
+        #[arg(long, default_value = ".")]
+        out: PathBuf,
+    },
+
+    /// Run the guest without proving it, reporting the cycles and segments a
+    /// proof would take and estimating the proving time and memory.
+    ExecuteOnly {
+        #[command(flatten)]
+        input: Input,
+
+        #[command(flatten)]
+        prover: ProverArgs,
+
+        /// Estimate for the prover with this name, as given to
+        /// `risc0_zkvm::prove::get_prover` or `bonsai`, rather than for the
+        /// one that would prove.
+        #[arg(long)]
+        profile: Option<String>,
+    },
+
+    /// Verify a receipt written by `prove` against this build's image ID.
+    Verify { receipt: PathBuf },
+
+    /// Upload a trajectory receipt written by `prove` to the API.
+    Submit {
+        receipt: PathBuf,
+
+        #[arg(long, env = "API_URL", default_value = DEFAULT_API_URL)]
+        api_url: String,
+
+        /// Number of times to retry a failed upload.
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+    },
+
+    /// Repeatedly lease a range from a coordinator, prove it and submit the
+    /// receipt.
+    Worker {
+        #[arg(long, env = "COORDINATOR_URL", default_value = DEFAULT_COORDINATOR_URL)]
+        coordinator: String,
+
+        /// Name committed as the contributor of every receipt.
+        #[arg(long, env = "CONTRIBUTOR")]
+        worker: String,
+
+        /// Stop after this many units instead of running forever.
+        #[arg(long)]
+        units: Option<usize>,
+
+        #[command(flatten)]
+        prover: ProverArgs,
+
+        #[command(flatten)]
+        budget: BudgetArgs,
+    },
+
+    /// Print the JSON manifest of the guest this binary proves with: its
+    /// image ID, ELF hash and the toolchain that built it.
+    Manifest,
+}
+
+/// Limits on the units a worker proves. Each unit is executed first to
+/// estimate its cost, and refused if it exceeds any of them.
+#[derive(Args)]
+struct BudgetArgs {
+    /// Most padded cycles to prove per unit.
+    #[arg(long)]
+    max_cycles: Option<usize>,
+
+    /// Most estimated seconds of proving per unit.
+    #[arg(long)]
+    max_proving_secs: Option<u64>,
+
+    /// Most estimated MiB of memory for proving a unit. Segments are only
+    /// proven in parallel as far as they fit.
+    #[arg(long)]
+    max_memory_mib: Option<usize>,
+}
+
+impl BudgetArgs {
+    fn budget(self) -> Budget {
+        Budget {
+            max_cycles: self.max_cycles,
+            max_time: self.max_proving_secs.map(Duration::from_secs),
+            max_memory: self.max_memory_mib.map(|mib| mib << 20),
+        }
+    }
+}
+
+/// Where to prove.
+#[derive(Args)]
+struct ProverArgs {
+    /// Prove on the Bonsai-compatible prover at BONSAI_API_URL, authenticated
+    /// with BONSAI_API_KEY, instead of locally.
+    #[arg(long)]
+    bonsai: bool,
+
+    /// Seconds to wait for a remote proof before giving up.
+    #[arg(long, default_value_t = 3600, requires = "bonsai")]
+    bonsai_timeout: u64,
+}
+
+impl ProverArgs {
+    fn prover(self) -> Prover {
+        if !self.bonsai {
+            return Prover::Local;
+        }
+        let client = bonsai_sdk::alpha::Client::from_env()
+            .unwrap_or_else(|err| fail(format!("Failed to create Bonsai client: {err:#}")));
+        let polling = PollConfig {
+            timeout: Duration::from_secs(self.bonsai_timeout),
+            ..Default::default()
+        };
+        Prover::Remote(RemoteProver::new(client, polling))
+    }
+}
+
+/// What to prove. Without `--n` or `--range`, a starting value is sampled
+/// uniformly from `[1, 100_000_000]`.
+#[derive(Args)]
+struct Input {
+    /// Starting value to prove.
+    #[arg(long, conflicts_with = "range", value_parser = parse_start)]
+    n: Option<u128>,
+
+    /// Half-open range of starting values `A..B` to summarize in one receipt.
+    #[arg(long, value_parser = parse_range)]
+    range: Option<(u128, u128)>,
+
+    /// Which parts of a trajectory to commit: sequence, stats or full.
+    #[arg(long, value_parser = parse_schema, default_value = "full")]
+    schema: JournalSchema,
+
+    /// Steps to take from each starting value before giving up on it.
+    #[arg(long, default_value_t = DEFAULT_STEP_BOUND)]
+    step_bound: u64,
+
+    /// Pause the guest every this many steps, proving the trajectory as a
+    /// chain of receipts that can be resumed if proving is interrupted.
+    #[arg(long, conflicts_with = "range", value_parser = clap::value_parser!(u64).range(1..))]
+    pause_every: Option<u64>,
+
+    /// Only show that each value in the range drops below itself, skipping
+    /// those ruled out by a 2^K residue sieve.
+    #[arg(long, requires = "range", value_parser = parse_sieve)]
+    sieve: Option<u32>,
+
+    #[arg(long, env = "CONTRIBUTOR")]
+    contributor: Option<String>,
+}
+
+impl Input {
+    fn request(self) -> Request {
+        match self.range {
+            Some((start, end)) if self.sieve.is_some() => Request::SieveRange {
+                start,
+                end,
+                contributor: self.contributor,
+                step_bound: self.step_bound,
+                sieve: Sieve::new(self.sieve.unwrap()),
+            },
+            Some((start, end)) => Request::Range {
+                start,
+                end,
+                contributor: self.contributor,
+                step_bound: self.step_bound,
+            },
+            None => Request::Sequence {
+                start: self.n.unwrap_or_else(|| sample_parameter(DEFAULT_N)),
+                schema: self.schema,
+                contributor: self.contributor,
+                step_bound: self.step_bound,
+                pause_every: self.pause_every,
+            },
+        }
+    }
+}
+
+fn parse_start(n: &str) -> Result<u128, String> {
+    match n.parse() {
+        Ok(0) => Err("0 has no Collatz trajectory".to_string()),
+        Ok(n) => Ok(n),
+        Err(err) => Err(format!("invalid value: {err}")),
+    }
+}
+
+fn parse_range(range: &str) -> Result<(u128, u128), String> {
+    let (start, end) = range
+        .split_once("..")
+        .ok_or_else(|| format!("expected A..B, got {range}"))?;
+    let start = start
+        .parse()
+        .map_err(|err| format!("invalid start: {err}"))?;
+    let end = end.parse().map_err(|err| format!("invalid end: {err}"))?;
+    if start == 0 || start >= end {
+        return Err(format!(
+            "{range} is not a non-empty range of positive values"
+        ));
+    }
+    Ok((start, end))
+}
+
+fn parse_sieve(k: &str) -> Result<u32, String> {
+    match k.parse() {
+        Ok(k) if (1..=MAX_SIEVE_K).contains(&k) => Ok(k),
+        _ => Err(format!("expected K between 1 and {MAX_SIEVE_K}, got {k}")),
+    }
+}
+
+fn parse_schema(schema: &str) -> Result<JournalSchema, String> {
+    match schema {
+        "sequence" => Ok(JournalSchema::Sequence),
+        "stats" => Ok(JournalSchema::Stats),
+        "full" => Ok(JournalSchema::Full),
+        _ => Err(format!("expected sequence, stats or full, got {schema}")),
+    }
+}
 
 fn main() {
-    let n = sample_parameter(DEFAULT_N);
+    match Cli::parse().command {
+        Command::Prove { input, prover, out } => prove(&prover.prover(), input.request(), &out),
+        Command::ExecuteOnly {
+            input,
+            prover,
+            profile,
+        } => execute_only(
+            &profile.unwrap_or_else(|| prover.prover().name()),
+            input.request(),
+        ),
+        Command::Verify { receipt } => {
+            let receipt = load_receipt(&receipt);
+            if let Err(err) = receipt.verify(COLLATZ_ID.into()) {
+                fail(format!("Receipt did not verify: {err}"));
+            }
+            let journal = decode_journal(&receipt);
+            if let Journal::SieveRangeV1(summary) = &journal {
+                if !summary.has_valid_sieve() {
+                    fail(format!(
+                        "Receipt relies on a sieve other than the 2^{} one",
+                        summary.sieve_k
+                    ));
+                }
+            }
+            println!("Receipt verified");
+            print_journal(&journal);
+        }
+        Command::Submit {
+            receipt,
+            api_url,
+            retries,
+        } => submit(&load_receipt(&receipt), &api_url, retries),
+        Command::Worker {
+            coordinator,
+            worker,
+            units,
+            prover,
+            budget,
+        } => work(
+            &Client::new(&coordinator),
+            &prover.prover(),
+            &worker,
+            units,
+            &budget.budget(),
+        ),
+        Command::Manifest => println!("{COLLATZ_MANIFEST}"),
+    }
+}
 
-    println!("n = {}", n);
+fn work(client: &Client, prover: &Prover, worker: &str, units: Option<usize>, budget: &Budget) {
+    let mut done = 0;
+    let mut backoff = MIN_REFUSAL_BACKOFF;
+    while units.map_or(true, |units| done < units) {
+        match work_once(client, prover, worker, budget) {
+            Ok(unit) => {
+                println!("Completed unit {}: {}..{}", unit.id, unit.start, unit.end);
+                done += 1;
+                backoff = MIN_REFUSAL_BACKOFF;
+            }
+            // The refused unit is handed out again first, so give other
+            // workers a chance to take it.
+            Err(err @ WorkError::OverBudget { .. }) => {
+                eprintln!("{err}; leasing again in {}s", backoff.as_secs());
+                sleep(backoff);
+                backoff = (backoff * 2).min(MAX_REFUSAL_BACKOFF);
+            }
+            Err(err) => {
+                eprintln!("{err}; retrying in 10s");
+                sleep(Duration::from_secs(10));
+            }
+        }
+    }
+}
 
-    let (receipt, _) = do_collatz(n);
+fn prove(prover: &Prover, request: Request, out: &Path) {
+    let (name, result) = match request {
+        Request::Sequence {
+            start,
+            pause_every: Some(_),
+            ..
+        } => return prove_paused(prover, request, start, out),
+        Request::Sequence {
+            start,
+            schema,
+            contributor,
+            step_bound,
+            pause_every: None,
+        } => {
+            println!("n = {start}");
+            let result = do_collatz(prover, start, schema, contributor, step_bound)
+                .map(|(receipt, journal)| (receipt, Journal::TrajectoryV1(journal)));
+            (format!("collatz-{start}"), result)
+        }
+        Request::Range {
+            start,
+            end,
+            contributor,
+            step_bound,
+        } => {
+            println!("range = {start}..{end}");
+            let result = do_collatz_range(prover, start, end, contributor, step_bound)
+                .map(|(receipt, summary)| (receipt, Journal::RangeV1(summary)));
+            (format!("collatz-{start}-{end}"), result)
+        }
+        Request::SieveRange {
+            start,
+            end,
+            contributor,
+            step_bound,
+            sieve,
+        } => {
+            println!("range = {start}..{end}, sieve = 2^{}", sieve.k);
+            let result = do_collatz_sieve(prover, start, end, contributor, step_bound, sieve)
+                .map(|(receipt, summary)| (receipt, Journal::SieveRangeV1(summary)));
+            (format!("collatz-{start}-{end}-sieve"), result)
+        }
+    };
+    let (receipt, journal) = result.unwrap_or_else(|err| report_prove_error(err));
 
     receipt.verify(COLLATZ_ID.into()).expect(
         "Code you have proven should successfully verify; did you specify the correct image ID?",
     );
+    print_journal(&journal);
+
+    fs::create_dir_all(out).expect("Failed to create output directory");
+    let receipt_path = out.join(format!("{name}.receipt"));
+    fs::write(&receipt_path, receipt.encode()).expect("Failed to write receipt");
+    let journal_path = out.join(format!("{name}.journal.json"));
+    let journal_json = serde_json::to_string_pretty(&journal).expect("Failed to serialize journal");
+    fs::write(&journal_path, journal_json).expect("Failed to write journal");
+    println!(
+        "Wrote {} and {}",
+        receipt_path.display(),
+        journal_path.display()
+    );
+}
 
-    let outputs: Journal = from_slice(&receipt.get_journal()).expect("Journal didn't deserialize well.");
+/// Prove a paused trajectory into `<out>/collatz-<start>.chunks`, resuming
+/// from a checkpoint left there by an interrupted run.
+fn prove_paused(prover: &Prover, request: Request, start: u128, out: &Path) {
+    if !matches!(prover, Prover::Local) {
+        fail("--pause-every is only supported when proving locally".to_string());
+    }
+    println!("n = {start}");
+    let name = format!("collatz-{start}");
+    let dir = out.join(format!("{name}.chunks"));
+    let (receipts, journal) =
+        do_collatz_paused(&request, &dir).unwrap_or_else(|err| report_prove_error(err));
+    if let Err(err) = verify_chain(&receipts, COLLATZ_ID.into()) {
+        fail(format!("Chunk receipts did not verify: {err}"));
+    }
+    let journal = Journal::TrajectoryV1(journal);
+    print_journal(&journal);
+
+    let journal_path = out.join(format!("{name}.journal.json"));
+    let journal_json = serde_json::to_string_pretty(&journal).expect("Failed to serialize journal");
+    fs::write(&journal_path, journal_json).expect("Failed to write journal");
+    println!(
+        "Wrote {} chunk receipts to {} and {}",
+        receipts.len(),
+        dir.display(),
+        journal_path.display()
+    );
+}
+
+fn execute_only(prover: &str, request: Request) {
+    // `prove` proves the segments of a session one at a time.
+    let parallel = ParallelConfig {
+        threads: 1,
+        max_memory: None,
+    };
+    let estimate =
+        estimate(prover, &request, &parallel).unwrap_or_else(|err| report_prove_error(err));
+    println!("{estimate}");
+    if let ExitCode::Paused(_) = estimate.exit_code {
+        // Only the first chunk ran, and it commits no journal.
+        return;
+    }
+
+    let journal: Journal = from_slice(&estimate.journal).expect("Journal didn't deserialize well.");
+    print_journal(&journal);
+}
+
+fn report_prove_error(err: ProveError) -> ! {
+    match err {
+        ProveError::SessionLimitExceeded { .. } => {
+            eprintln!("{err}; the guest did not finish within its step bound");
+            process::exit(2);
+        }
+        err => fail(format!("Failed to prove: {err}")),
+    }
+}
+
+fn submit(receipt: &SessionFlatReceipt, api_url: &str, retries: u32) {
+    if let Err(err) = receipt.verify(COLLATZ_ID.into()) {
+        fail(format!(
+            "Refusing to submit a receipt that does not verify: {err}"
+        ));
+    }
+    let Journal::TrajectoryV1(journal) = decode_journal(receipt) else {
+        fail("Only trajectory receipts can be submitted".to_string());
+    };
+    let Some(sequence) = journal.sequence else {
+        fail("Only receipts that commit the sequence can be submitted".to_string());
+    };
+    let output_sequence = sequence.iter().map(value_to_string).collect();
 
     let out = Output {
-        output_sequence: outputs.sequence,
+        input_value: journal.start,
+        contributor: journal.contributor,
+        output_sequence,
         proof: receipt.encode(),
         image_id: COLLATZ_ID,
     };
-
     let out_json = serde_json::to_string(&out).expect("Failed to serialize to JSON");
-    // println!("Output in JSON format: {}", out_json);
 
-    let url = env::var("API_URL").unwrap_or_else(|_| DEFAULT_API_URL.to_string());
-    upload(url, out_json).expect("Failed to upload to API");
+    let mut attempt = 0;
+    loop {
+        match upload(api_url, &out_json) {
+            Ok(()) => return,
+            Err(err) if attempt < retries && err.is_retryable() => {
+                attempt += 1;
+                let delay = Duration::from_secs(1 << attempt.min(6));
+                eprintln!("Upload failed: {err}; retrying in {delay:?} ({attempt}/{retries})");
+                sleep(delay);
+            }
+            Err(err) => fail(format!("Upload failed: {err}")),
+        }
+    }
 }
 
 // Random sample from uniform distribution: integer in [1, upper_bound].
-pub fn sample_parameter(upper_bound: i32) -> i32 {
+pub fn sample_parameter(upper_bound: u128) -> u128 {
     let mut rng = rand::thread_rng();
     let rv = Uniform::new_inclusive(1, upper_bound);
     rv.sample(&mut rng)
 }
 
-fn upload(url: String, data: String) -> std::result::Result<(), reqwest::Error> {
+enum UploadError {
+    Request(reqwest::Error),
+    Status(reqwest::StatusCode, String),
+}
+
+impl UploadError {
+    /// Whether the upload might succeed if tried again. The API rejecting the
+    /// upload is final.
+    fn is_retryable(&self) -> bool {
+        match self {
+            UploadError::Request(_) => true,
+            UploadError::Status(status, _) => status.is_server_error(),
+        }
+    }
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            UploadError::Request(err) => write!(f, "{err}"),
+            UploadError::Status(status, body) => write!(f, "{status}: {body}"),
+        }
+    }
+}
+
+fn upload(url: &str, data: &str) -> Result<(), UploadError> {
     let client = reqwest::blocking::Client::new();
 
     let res = client
-        .post(&url)
-        .body(data)
+        .post(url)
+        .body(data.to_string())
         .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .send();
+        .send()
+        .map_err(UploadError::Request)?;
 
-    match res {
-        Ok(res) => {
-            println!("Status: {}", res.status());
-            println!("Headers: {:?}", res.headers());
-        }
-        Err(_) => {}
+    let status = res.status();
+    println!("Status: {status}");
+    if !status.is_success() {
+        return Err(UploadError::Status(status, res.text().unwrap_or_default()));
     }
     Ok(())
 }
+
+/// Load a receipt written by `prove`.
+fn load_receipt(path: &Path) -> SessionFlatReceipt {
+    let data = fs::read(path).unwrap_or_else(|err| fail(format!("Failed to read receipt: {err}")));
+    decode_receipt(&data).unwrap_or_else(|err| fail(format!("Failed to decode receipt: {err}")))
+}
+
+fn decode_journal(receipt: &SessionFlatReceipt) -> Journal {
+    from_slice(receipt.get_journal()).expect("Journal didn't deserialize well.")
+}
+
+fn print_journal(journal: &Journal) {
+    match journal {
+        Journal::TrajectoryV1(journal) => {
+            println!("n = {}", journal.start);
+            print_outcome(journal.start, &journal.outcome);
+            if let Some(stats) = &journal.stats {
+                println!(
+                    "stopping time = {}, glide = {}, max = {}",
+                    stats.total_stopping_time,
+                    stats.glide,
+                    value_to_string(&stats.max_value)
+                );
+            }
+        }
+        Journal::RangeV1(summary) => {
+            println!("range = {}..{}", summary.start, summary.end);
+            println!(
+                "max stopping time = {} (n = {}), max excursion = {} (n = {})",
+                summary.max_stopping_time,
+                summary.max_stopping_time_arg,
+                value_to_string(&summary.max_excursion),
+                summary.max_excursion_arg
+            );
+            if let Some(counterexample) = &summary.counterexample {
+                print_outcome(counterexample.start, &counterexample.outcome);
+            }
+        }
+        Journal::SieveRangeV1(summary) => {
+            println!("range = {}..{}", summary.start, summary.end);
+            println!(
+                "sieve = 2^{} ({}), walked = {}, max glide = {} (n = {})",
+                summary.sieve_k,
+                summary.sieve_digest,
+                summary.walked,
+                summary.max_glide,
+                summary.max_glide_arg
+            );
+            if let Some(counterexample) = &summary.counterexample {
+                print_outcome(counterexample.start, &counterexample.outcome);
+            }
+        }
+    }
+}
+
+fn print_outcome(n: u128, outcome: &Outcome) {
+    match outcome {
+        Outcome::ReachedOne => {}
+        Outcome::NontrivialCycle { members } => println!(
+            "{n} enters a cycle not containing 1: {:?}",
+            members.iter().map(value_to_string).collect::<Vec<_>>()
+        ),
+        Outcome::StepBoundExceeded { last_value } => println!(
+            "{n} did not reach 1 within the step bound, stopped at {}",
+            value_to_string(last_value)
+        ),
+        Outcome::Overflow { last_value } => println!(
+            "{n} grew past 256 bits before reaching 1, stopped at {}",
+            value_to_string(last_value)
+        ),
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{message}");
+    process::exit(1);
+}
@@ -0,0 +1,295 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proving very long trajectories in chunks.
+//!
+//! With [Request::Sequence]'s `pause_every` set, the guest pauses every K
+//! steps. Each chunk is proven on its own and written to disk along with an
+//! [ExecutorCheckpoint], so an interrupted run resumes from the last chunk
+//! rather than from the start. Segments waiting to be proven are kept in a
+//! [SegmentStore] there too, rather than in memory, until their chunk is
+//! proven. The receipts form a chain:
+//! each chunk starts from the image the previous one paused in, which
+//! [verify_chain] checks.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use collatz_core::{decode_receipt, Journal, Request, TrajectoryJournal};
+use collatz_methods::COLLATZ_ELF;
+use risc0_zkvm::{
+    receipt::compute_image_id,
+    serde::{from_slice, to_vec},
+    sha::Digest,
+    Executor, ExecutorCheckpoint, ExecutorEnv, ExitCode, SegmentStore, SessionFlatReceipt,
+    SessionReceipt, VerificationError,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{check_request, session_limit, ProveError};
+
+const CHECKPOINT: &str = "checkpoint";
+const SEGMENTS: &str = "segments";
+
+/// Everything needed to resume a paused run.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    request: Request,
+    executor: ExecutorCheckpoint,
+    chunks: usize,
+}
+
+impl Checkpoint {
+    fn load(dir: &Path) -> anyhow::Result<Option<Self>> {
+        match fs::read(dir.join(CHECKPOINT)) {
+            Ok(data) => Ok(Some(
+                bincode::deserialize(&data).context("Failed to decode checkpoint")?,
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context("Failed to read checkpoint"),
+        }
+    }
+
+    /// Write the checkpoint so that a crash leaves either the old one or the
+    /// new one in place, never a torn file.
+    fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        let tmp = dir.join(format!("{CHECKPOINT}.tmp"));
+        fs::write(&tmp, bincode::serialize(self)?).context("Failed to write checkpoint")?;
+        fs::rename(&tmp, dir.join(CHECKPOINT)).context("Failed to write checkpoint")
+    }
+}
+
+fn chunk_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("chunk-{index}.receipt"))
+}
+
+/// Prove `request`, which should set `pause_every`, one chunk at a time,
+/// keeping receipts and checkpoints in `dir`.
+///
+/// If `dir` holds a checkpoint for the same request, proving resumes from it.
+/// Returns the receipts of every chunk in order, the last of which commits
+/// the journal.
+pub fn do_collatz_paused(
+    request: &Request,
+    dir: &Path,
+) -> Result<(Vec<SessionFlatReceipt>, TrajectoryJournal), ProveError> {
+    check_request(request)?;
+    fs::create_dir_all(dir)
+        .context("Failed to create checkpoint directory")
+        .map_err(ProveError::Other)?;
+    let limit = session_limit(request);
+    // Chunks run from the same program, so they share most of their pages.
+    let store = SegmentStore::open(&dir.join(SEGMENTS)).map_err(ProveError::Other)?;
+
+    let mut chunks = 0;
+    let mut exec = match Checkpoint::load(dir).map_err(ProveError::Other)? {
+        Some(checkpoint) if checkpoint.request == *request => {
+            chunks = checkpoint.chunks;
+            let env = ExecutorEnv::builder()
+                .session_limit(Some(limit))
+                .build()
+                .unwrap();
+            Executor::restore(env, checkpoint.executor).map_err(ProveError::Other)?
+        }
+        Some(_) => {
+            return Err(ProveError::Other(anyhow::anyhow!(
+                "{} holds a checkpoint for a different request",
+                dir.display()
+            )))
+        }
+        None => {
+            let env = ExecutorEnv::builder()
+                .add_input(&to_vec(request).unwrap())
+                .session_limit(Some(limit))
+                .build()
+                .unwrap();
+            Executor::from_elf(env, COLLATZ_ELF).map_err(ProveError::Other)?
+        }
+    };
+
+    loop {
+        let session = exec
+            .run_with_callback(|segment| Ok(Box::new(store.store(&segment)?)))
+            .map_err(|err| ProveError::from_run(err, limit))?;
+        let receipt = session.prove().map_err(ProveError::Other)?;
+        fs::write(chunk_path(dir, chunks), receipt.encode())
+            .context("Failed to write chunk receipt")
+            .map_err(ProveError::Other)?;
+        store.gc(&[]).map_err(ProveError::Other)?;
+        chunks += 1;
+
+        if !matches!(session.exit_code, ExitCode::Paused(_)) {
+            break;
+        }
+        let checkpoint = Checkpoint {
+            request: request.clone(),
+            executor: exec.checkpoint().map_err(ProveError::Other)?,
+            chunks,
+        };
+        checkpoint.save(dir).map_err(ProveError::Other)?;
+    }
+    let _ = fs::remove_file(dir.join(CHECKPOINT));
+    let _ = fs::remove_dir_all(dir.join(SEGMENTS));
+
+    let receipts = (0..chunks)
+        .map(|index| {
+            let data = fs::read(chunk_path(dir, index))?;
+            decode_receipt(&data).map_err(anyhow::Error::from)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .context("Failed to load chunk receipts")
+        .map_err(ProveError::Other)?;
+
+    let journal: Journal = from_slice(receipts.last().unwrap().get_journal()).expect(
+        "Journal output should deserialize into the same types (& order) that it was written",
+    );
+    let Journal::TrajectoryV1(journal) = journal else {
+        panic!("Expected a trajectory journal, got {journal:?}");
+    };
+
+    Ok((receipts, journal))
+}
+
+/// Why a chain of chunk receipts was rejected.
+#[derive(Debug)]
+pub enum ChainError {
+    /// There are no receipts.
+    Empty,
+
+    /// The receipt of chunk `index` did not verify against the image the
+    /// previous chunk paused in.
+    Verification {
+        index: usize,
+        err: VerificationError,
+    },
+
+    /// A chunk other than the last did not pause, or the last did not halt.
+    ExitCode { index: usize, exit_code: ExitCode },
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainError::Empty => write!(f, "No chunk receipts"),
+            ChainError::Verification { index, err } => {
+                write!(f, "Chunk {index} did not verify: {err}")
+            }
+            ChainError::ExitCode { index, exit_code } => {
+                write!(f, "Chunk {index} ended with unexpected {exit_code:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChainError {}
+
+/// Verify that `receipts` prove one execution of `image_id` split at pauses,
+/// returning the journal committed by the last chunk.
+pub fn verify_chain(
+    receipts: &[SessionFlatReceipt],
+    image_id: Digest,
+) -> Result<&[u8], ChainError> {
+    let mut expected = image_id;
+    for (index, receipt) in receipts.iter().enumerate() {
+        let verification = |err| ChainError::Verification { index, err };
+        receipt.verify(expected).map_err(verification)?;
+        let last = receipt.segments.last().unwrap();
+        let metadata = last.get_metadata().map_err(verification)?;
+
+        let is_last = index + 1 == receipts.len();
+        match metadata.exit_code {
+            ExitCode::Paused(_) if !is_last => {}
+            ExitCode::Halted(_) if is_last => return Ok(receipt.get_journal()),
+            exit_code => return Err(ChainError::ExitCode { index, exit_code }),
+        }
+        expected = compute_image_id(&metadata.post.merkle_root, metadata.post.pc);
+    }
+    Err(ChainError::Empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use collatz_core::{JournalSchema, DEFAULT_STEP_BOUND};
+    use collatz_methods::COLLATZ_ID;
+
+    use super::*;
+
+    #[test]
+    fn test_pause_and_resume() {
+        let dir = std::env::temp_dir().join(format!("collatz-pause-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // 27 takes 111 steps, so pausing every 40 gives three chunks.
+        let request = Request::Sequence {
+            start: 27,
+            schema: JournalSchema::Stats,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+            pause_every: Some(40),
+        };
+
+        let (receipts, journal) = do_collatz_paused(&request, &dir).unwrap();
+        assert_eq!(receipts.len(), 3);
+        assert_eq!(journal.stats.unwrap().total_stopping_time, 111);
+        assert!(!dir.join(CHECKPOINT).exists());
+        assert!(!dir.join(SEGMENTS).exists());
+        let committed = verify_chain(&receipts, COLLATZ_ID.into()).unwrap();
+        assert_eq!(committed, receipts[2].get_journal());
+
+        // Chunks out of order, or missing the last one, do not verify.
+        let swapped = [receipts[1].clone(), receipts[0].clone()];
+        assert!(matches!(
+            verify_chain(&swapped, COLLATZ_ID.into()),
+            Err(ChainError::Verification { index: 0, .. })
+        ));
+        assert!(matches!(
+            verify_chain(&receipts[..2], COLLATZ_ID.into()),
+            Err(ChainError::ExitCode { index: 1, .. })
+        ));
+
+        // Resuming after the first chunk reproduces the rest of the chain.
+        let executor = Executor::from_elf(
+            ExecutorEnv::builder()
+                .add_input(&to_vec(&request).unwrap())
+                .build()
+                .unwrap(),
+            COLLATZ_ELF,
+        )
+        .and_then(|mut exec| {
+            exec.run()?;
+            exec.checkpoint()
+        })
+        .unwrap();
+        Checkpoint {
+            request: request.clone(),
+            executor,
+            chunks: 1,
+        }
+        .save(&dir)
+        .unwrap();
+        fs::remove_file(chunk_path(&dir, 2)).unwrap();
+        let (resumed, _) = do_collatz_paused(&request, &dir).unwrap();
+        assert_eq!(resumed.len(), 3);
+        verify_chain(&resumed, COLLATZ_ID.into()).unwrap();
+        let post = |receipt: &SessionFlatReceipt| {
+            let metadata = receipt.segments.last().unwrap().get_metadata().unwrap();
+            metadata.post.merkle_root
+        };
+        assert_eq!(post(&resumed[1]), post(&receipts[1]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,437 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proving on a Bonsai-compatible remote prover.
+
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+use bonsai_sdk::alpha::{responses::SessionStatus, Client, PollConfig, SdkErr, SessionId};
+use collatz_methods::{COLLATZ_ELF, COLLATZ_ID};
+use risc0_zkvm::{
+    serde::{from_slice, to_vec},
+    sha::Digest,
+    SessionFlatReceipt, SessionReceipt, VerificationError,
+};
+
+use crate::{decode_receipt, Journal, Request};
+
+/// Why a remote proof could not be obtained.
+#[derive(Debug)]
+pub enum RemoteError {
+    /// A request to the prover failed, or the session was still running
+    /// when the polling timeout elapsed.
+    Api(SdkErr),
+
+    /// The session ended without succeeding.
+    SessionFailed {
+        session: String,
+        status: SessionStatus,
+    },
+
+    /// The session succeeded without a receipt to download.
+    MissingReceipt {
+        session: String,
+    },
+
+    DecodeReceipt(risc0_zkvm::serde::Error),
+
+    /// The downloaded receipt did not verify locally.
+    Verification(VerificationError),
+
+    DecodeJournal(risc0_zkvm::serde::Error),
+
+    /// The receipt verified but commits to something other than what was
+    /// asked for.
+    JournalMismatch {
+        field: &'static str,
+    },
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemoteError::Api(err) => write!(f, "Remote prover error: {err}"),
+            RemoteError::SessionFailed { session, status } => {
+                write!(f, "Session {session} ended with status {status:?}")
+            }
+            RemoteError::MissingReceipt { session } => {
+                write!(f, "Session {session} succeeded without a receipt")
+            }
+            RemoteError::DecodeReceipt(err) => write!(f, "Failed to decode receipt: {err}"),
+            RemoteError::Verification(err) => write!(f, "Receipt did not verify: {err}"),
+            RemoteError::DecodeJournal(err) => write!(f, "Failed to decode journal: {err}"),
+            RemoteError::JournalMismatch { field } => {
+                write!(f, "Receipt journal has the wrong {field} for the request")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+/// Proves Collatz requests on a remote prover, checking each receipt locally
+/// before handing it back.
+///
+/// The remote prover is expected to serve receipts as written by
+/// [SessionReceipt::encode].
+pub struct RemoteProver {
+    client: Client,
+    polling: PollConfig,
+
+    /// Uploaded images by image ID, so the ELF is only uploaded once.
+    images: Mutex<HashMap<Digest, String>>,
+}
+
+impl RemoteProver {
+    pub fn new(client: Client, polling: PollConfig) -> Self {
+        Self {
+            client,
+            polling,
+            images: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn prove(&self, request: &Request) -> Result<SessionFlatReceipt, RemoteError> {
+        let image = self.upload_image()?;
+        let input = to_vec(request)
+            .unwrap()
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let input = self.client.upload_input(input).map_err(RemoteError::Api)?;
+        let session = self
+            .client
+            .create_session(image, input)
+            .map_err(RemoteError::Api)?;
+
+        let receipt_url = self.wait(&session)?;
+        let data = self
+            .client
+            .download(&receipt_url)
+            .map_err(RemoteError::Api)?;
+        let receipt = decode_receipt(&data).map_err(RemoteError::DecodeReceipt)?;
+        receipt
+            .verify(COLLATZ_ID.into())
+            .map_err(RemoteError::Verification)?;
+        let journal = from_slice(receipt.get_journal()).map_err(RemoteError::DecodeJournal)?;
+        check_journal(request, &journal)?;
+        Ok(receipt)
+    }
+
+    fn upload_image(&self) -> Result<String, RemoteError> {
+        let image_id = Digest::from(COLLATZ_ID);
+        let mut images = self.images.lock().unwrap();
+        if let Some(image) = images.get(&image_id) {
+            return Ok(image.clone());
+        }
+        let image = self
+            .client
+            .upload_img(COLLATZ_ELF.to_vec())
+            .map_err(RemoteError::Api)?;
+        images.insert(image_id, image.clone());
+        Ok(image)
+    }
+
+    /// Wait for `session` to finish, returning the URL of its receipt.
+    fn wait(&self, session: &SessionId) -> Result<String, RemoteError> {
+        let res = session
+            .wait_for_completion(&self.client, &self.polling)
+            .map_err(RemoteError::Api)?;
+        if res.status != SessionStatus::Succeeded {
+            return Err(RemoteError::SessionFailed {
+                session: session.uuid.clone(),
+                status: res.status,
+            });
+        }
+        res.receipt_url.ok_or_else(|| RemoteError::MissingReceipt {
+            session: session.uuid.clone(),
+        })
+    }
+}
+
+/// Check that `journal` commits to what `request` asked for, so that a
+/// prover cannot answer with a valid receipt for some other request.
+fn check_journal(request: &Request, journal: &Journal) -> Result<(), RemoteError> {
+    let mismatch = |field| Err(RemoteError::JournalMismatch { field });
+    // (start, end, contributor, step_bound), as asked for and as committed.
+    let (expected, got) = match (request, journal) {
+        (
+            Request::Sequence {
+                start,
+                contributor,
+                step_bound,
+                ..
+            },
+            Journal::TrajectoryV1(journal),
+        ) => (
+            (*start, None, contributor, *step_bound),
+            (
+                journal.start,
+                None,
+                &journal.contributor,
+                journal.step_bound,
+            ),
+        ),
+        (
+            Request::Range {
+                start,
+                end,
+                contributor,
+                step_bound,
+            },
+            Journal::RangeV1(summary),
+        ) => (
+            (*start, Some(*end), contributor, *step_bound),
+            (
+                summary.start,
+                Some(summary.end),
+                &summary.contributor,
+                summary.step_bound,
+            ),
+        ),
+        (
+            Request::SieveRange {
+                start,
+                end,
+                contributor,
+                step_bound,
+                sieve,
+            },
+            Journal::SieveRangeV1(summary),
+        ) => {
+            if (summary.sieve_k, summary.sieve_digest) != (sieve.k, sieve.digest()) {
+                return mismatch("sieve");
+            }
+            (
+                (*start, Some(*end), contributor, *step_bound),
+                (
+                    summary.start,
+                    Some(summary.end),
+                    &summary.contributor,
+                    summary.step_bound,
+                ),
+            )
+        }
+        _ => return mismatch("kind of journal"),
+    };
+
+    if expected.0 != got.0 {
+        return mismatch("start");
+    }
+    if expected.1 != got.1 {
+        return mismatch("end");
+    }
+    if expected.2 != got.2 {
+        return mismatch("contributor");
+    }
+    if expected.3 != got.3 {
+        return mismatch("step bound");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use tiny_http::{Method, Response, Server};
+
+    use super::*;
+    use crate::{Journal, JournalSchema, Prover, DEFAULT_STEP_BOUND};
+
+    const TEST_KEY: &str = "TESTKEY";
+
+    /// What the mock prover has been asked to do.
+    #[derive(Default)]
+    struct MockState {
+        image_uploads: usize,
+        inputs: Vec<Vec<u8>>,
+        polls: usize,
+
+        /// Prove this instead of the uploaded input, if set.
+        substitute: Option<Request>,
+    }
+
+    /// Serve the Bonsai REST API, proving sessions locally. Each
+    /// session reports RUNNING on its first poll and `final_status` after.
+    fn run_mock(server: Server, state: Arc<Mutex<MockState>>, final_status: &'static str) {
+        let addr = server.server_addr().to_ip().unwrap();
+        for mut request in server.incoming_requests() {
+            assert!(request
+                .headers()
+                .iter()
+                .any(|header| header.field.equiv("x-api-key") && header.value == TEST_KEY));
+            let method = request.method().clone();
+            let url = request.url().to_string();
+            let path: Vec<&str> = url.split('/').skip(1).collect();
+            let mut state = state.lock().unwrap();
+            let body = match (method, path.as_slice()) {
+                (Method::Get, [route @ ("images" | "inputs"), "upload"]) => {
+                    let uuid = match *route {
+                        "images" => {
+                            state.image_uploads += 1;
+                            "image".to_string()
+                        }
+                        _ => format!("input-{}", state.inputs.len()),
+                    };
+                    format!(r#"{{"url": "http://{addr}/put/{uuid}", "uuid": "{uuid}"}}"#)
+                        .into_bytes()
+                }
+                (Method::Put, ["put", uuid]) => {
+                    let mut data = Vec::new();
+                    request.as_reader().read_to_end(&mut data).unwrap();
+                    if uuid.starts_with("input-") {
+                        state.inputs.push(data);
+                    }
+                    Vec::new()
+                }
+                (Method::Post, ["sessions", "create"]) => {
+                    let mut req = String::new();
+                    request.as_reader().read_to_string(&mut req).unwrap();
+                    let req: serde_json::Value = serde_json::from_str(&req).unwrap();
+                    assert_eq!(req["img"], "image");
+                    format!(r#"{{"uuid": "{}"}}"#, req["input"].as_str().unwrap()).into_bytes()
+                }
+                (Method::Get, ["sessions", "status", session]) => {
+                    state.polls += 1;
+                    let body = if state.polls % 2 == 1 {
+                        r#"{"status": "RUNNING", "receipt_url": null}"#.to_string()
+                    } else {
+                        format!(
+                            r#"{{"status": "{final_status}", "receipt_url": "http://{addr}/receipts/{session}"}}"#
+                        )
+                    };
+                    body.into_bytes()
+                }
+                (Method::Get, ["receipts", session]) => {
+                    let index: usize = session["input-".len()..].parse().unwrap();
+                    let words: Vec<u32> = state.inputs[index]
+                        .chunks(4)
+                        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                        .collect();
+                    let request = match &state.substitute {
+                        Some(request) => request.clone(),
+                        None => from_slice(&words).unwrap(),
+                    };
+                    Prover::Local.prove(&request).unwrap().encode()
+                }
+                _ => panic!("Unexpected request {url}"),
+            };
+            request.respond(Response::from_data(body)).unwrap();
+        }
+    }
+
+    fn remote_prover(final_status: &'static str) -> (RemoteProver, Arc<Mutex<MockState>>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let mock_state = state.clone();
+        thread::spawn(move || run_mock(server, mock_state, final_status));
+
+        let client = Client::from_parts(url, TEST_KEY.to_string()).unwrap();
+        let polling = PollConfig {
+            interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+        (RemoteProver::new(client, polling), state)
+    }
+
+    #[test]
+    fn test_remote_prover() {
+        let (prover, state) = remote_prover("SUCCEEDED");
+        for start in [1, 11] {
+            let receipt = prover
+                .prove(&Request::Range {
+                    start,
+                    end: start + 10,
+                    contributor: None,
+                    step_bound: DEFAULT_STEP_BOUND,
+                })
+                .unwrap();
+            let Journal::RangeV1(summary) = from_slice(receipt.get_journal()).unwrap() else {
+                panic!("Expected a range journal");
+            };
+            assert_eq!((summary.start, summary.end), (start, start + 10));
+        }
+
+        let state = state.lock().unwrap();
+        assert_eq!(
+            state.image_uploads, 1,
+            "The image should only be uploaded once"
+        );
+        assert_eq!(state.inputs.len(), 2);
+        assert_eq!(state.polls, 4);
+    }
+
+    #[test]
+    fn test_remote_session_failed() {
+        let (prover, _) = remote_prover("FAILED");
+        let err = prover
+            .prove(&Request::Range {
+                start: 1,
+                end: 11,
+                contributor: None,
+                step_bound: DEFAULT_STEP_BOUND,
+            })
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            RemoteError::SessionFailed {
+                status: SessionStatus::Failed,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_remote_journal_mismatch() {
+        let (prover, state) = remote_prover("SUCCEEDED");
+        let request = |contributor: Option<&str>| Request::Range {
+            start: 1,
+            end: 11,
+            contributor: contributor.map(str::to_string),
+            step_bound: DEFAULT_STEP_BOUND,
+        };
+        state.lock().unwrap().substitute = Some(request(Some("mallory")));
+        let err = prover.prove(&request(None)).unwrap_err();
+        assert!(matches!(
+            err,
+            RemoteError::JournalMismatch {
+                field: "contributor"
+            }
+        ));
+
+        // A trajectory walked under a different step bound is refused too.
+        let request = |step_bound| Request::Sequence {
+            start: 27,
+            schema: JournalSchema::Stats,
+            contributor: None,
+            step_bound,
+            pause_every: None,
+        };
+        state.lock().unwrap().substitute = Some(request(200));
+        let err = prover.prove(&request(DEFAULT_STEP_BOUND)).unwrap_err();
+        assert!(matches!(
+            err,
+            RemoteError::JournalMismatch {
+                field: "step bound"
+            }
+        ));
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prove work units handed out by a coordinator.
+
+use std::fmt;
+
+use collatz_coordinator::{
+    client::{Client, ClientError},
+    WorkUnit,
+};
+use risc0_zkvm::SessionReceipt;
+
+use crate::{
+    estimate::{estimate_session, Budget},
+    execute, ProveError, Prover, Request,
+};
+
+#[derive(Debug)]
+pub enum WorkError {
+    Coordinator(ClientError),
+    Prove(ProveError),
+
+    /// The unit was estimated to cost more than the worker's budget, so it
+    /// was not proven. Its lease was given back for another worker to take.
+    OverBudget {
+        unit: WorkUnit,
+        reason: String,
+    },
+}
+
+impl fmt::Display for WorkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WorkError::Coordinator(err) => write!(f, "Coordinator error: {err}"),
+            WorkError::Prove(err) => write!(f, "Failed to prove: {err}"),
+            WorkError::OverBudget { unit, reason } => write!(
+                f,
+                "Refused unit {} ({}..{}): {reason}",
+                unit.id, unit.start, unit.end
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WorkError {}
+
+/// Lease a unit, prove it on `prover` with `worker` as the contributor and
+/// submit the receipt.
+///
+/// Unless the `budget` is unlimited, the unit is first executed without
+/// proving to check that it fits, and released if it does not. Local units are
+/// then proven from that execution, on as many threads as fit in the budget.
+pub fn work_once(
+    client: &Client,
+    prover: &Prover,
+    worker: &str,
+    budget: &Budget,
+) -> Result<WorkUnit, WorkError> {
+    let unit = client.lease().map_err(WorkError::Coordinator)?;
+    let request = Request::Range {
+        start: unit.start,
+        end: unit.end,
+        contributor: Some(worker.to_string()),
+        step_bound: unit.step_bound,
+    };
+    // A remote prover executes the unit again whatever happens here, so it is
+    // only executed locally to be estimated.
+    let session = match prover {
+        Prover::Remote(_) if budget.is_unlimited() => None,
+        _ => Some(execute(&request).map_err(WorkError::Prove)?),
+    };
+    let parallel = budget.parallel_config();
+    if let Some(session) = session.as_ref().filter(|_| !budget.is_unlimited()) {
+        let estimate =
+            estimate_session(&prover.name(), session, &parallel).map_err(WorkError::Prove)?;
+        if let Err(reason) = budget.check(&estimate) {
+            client.release(&unit).map_err(WorkError::Coordinator)?;
+            return Err(WorkError::OverBudget { unit, reason });
+        }
+    }
+    let receipt = match (prover, session) {
+        (Prover::Local, Some(session)) => session
+            .prove_parallel(&parallel)
+            .map(|receipt| Box::new(receipt) as Box<dyn SessionReceipt>)
+            .map_err(|err| WorkError::Prove(ProveError::Other(err)))?,
+        _ => prover.prove(&request).map_err(WorkError::Prove)?,
+    };
+    client
+        .submit(&unit, worker, receipt.encode())
+        .map_err(WorkError::Coordinator)?;
+    Ok(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use collatz_coordinator::{server, Config, Coordinator};
+    use collatz_core::ImageAllowlist;
+    use collatz_methods::COLLATZ_ID;
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::{do_collatz_range, DEFAULT_STEP_BOUND};
+
+    #[test]
+    fn test_worker() {
+        let coordinator = Arc::new(Coordinator::new(Config {
+            first: 1,
+            unit_size: 10,
+            step_bound: DEFAULT_STEP_BOUND,
+            lease_duration: Duration::from_secs(60),
+            image_ids: ImageAllowlist::new(vec![COLLATZ_ID.into()]),
+        }));
+        let listener = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = listener.server_addr().to_ip().unwrap();
+        thread::spawn(move || server::serve(coordinator, listener, 2));
+        let client = Client::new(&format!("http://{addr}"));
+
+        let unit = work_once(&client, &Prover::Local, "alice", &Budget::default()).unwrap();
+        assert_eq!((unit.start, unit.end), (1, 11));
+        let status = client.status().unwrap();
+        assert_eq!(status.frontier, 11);
+        assert_eq!(status.completed, 1);
+        assert_eq!(status.leased, 0);
+
+        // A unit is only completed once.
+        let (receipt, _) = do_collatz_range(
+            &Prover::Local,
+            1,
+            11,
+            Some("alice".to_string()),
+            DEFAULT_STEP_BOUND,
+        )
+        .unwrap();
+        let err = client.submit(&unit, "alice", receipt.encode()).unwrap_err();
+        assert!(matches!(err, ClientError::Status(StatusCode::CONFLICT, _)));
+
+        // Receipts cannot be claimed by another worker, or for another unit.
+        let unit = client.lease().unwrap();
+        assert_eq!((unit.start, unit.end), (11, 21));
+        let (receipt, _) = do_collatz_range(
+            &Prover::Local,
+            11,
+            21,
+            Some("bob".to_string()),
+            DEFAULT_STEP_BOUND,
+        )
+        .unwrap();
+        let err = client.submit(&unit, "alice", receipt.encode()).unwrap_err();
+        assert!(matches!(
+            err,
+            ClientError::Status(StatusCode::UNPROCESSABLE_ENTITY, _)
+        ));
+        let (receipt, _) = do_collatz_range(
+            &Prover::Local,
+            1,
+            11,
+            Some("bob".to_string()),
+            DEFAULT_STEP_BOUND,
+        )
+        .unwrap();
+        let err = client.submit(&unit, "bob", receipt.encode()).unwrap_err();
+        assert!(matches!(
+            err,
+            ClientError::Status(StatusCode::UNPROCESSABLE_ENTITY, _)
+        ));
+
+        // Nor with a step bound other than the unit's.
+        let (receipt, _) =
+            do_collatz_range(&Prover::Local, 11, 21, Some("alice".to_string()), 1).unwrap();
+        let err = client.submit(&unit, "alice", receipt.encode()).unwrap_err();
+        assert!(matches!(
+            err,
+            ClientError::Status(StatusCode::UNPROCESSABLE_ENTITY, _)
+        ));
+        assert_eq!(client.status().unwrap().frontier, 11);
+
+        // Units over budget are refused without being proven.
+        let budget = Budget {
+            max_cycles: Some(1),
+            ..Default::default()
+        };
+        match work_once(&client, &Prover::Local, "alice", &budget) {
+            Err(WorkError::OverBudget { unit, .. }) => assert_eq!(unit.start, 21),
+            Err(err) => panic!("Expected the unit to be refused, got {err}"),
+            Ok(unit) => panic!("Expected unit {} to be refused", unit.id),
+        }
+        assert_eq!(client.status().unwrap().completed, 1);
+        // The refused unit is handed out again straight away.
+        assert_eq!(client.lease().unwrap().start, 21);
+    }
+}
@@ -12,27 +12,22 @@ fn test_simple() {
         "default"
     };
 
-    Command::new("cargo")
+    let output = Command::new("cargo")
         .args([
             "run",
             "--release",
             "--features",
             feature,
-            "--bin",
-            "prove",
             "--",
-            "--i",
-            "waldo.webp",
-            "-x",
-            "1150",
-            "-y",
-            "291",
-            "----width 58",
-            "--height",
-            "70",
-            "-m",
-            "waldo_mask.png",
+            "execute-only",
+            "--n",
+            "27",
+            "--schema",
+            "stats",
         ])
         .output()
-        .expect("failed to run prove");
+        .expect("failed to run execute-only");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("stopping time = 111, glide = 96, max = 9232"));
 }
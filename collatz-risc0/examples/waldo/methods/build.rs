@@ -22,6 +22,7 @@ fn main() {
         GuestOptions {
             features: vec![],
             std: true,
+            hermetic: None,
         },
     )]));
 }
@@ -33,7 +33,7 @@ use risc0_zkvm::{
     MemoryImage, Program,
 };
 use risc0_zkvm_platform::{memory, PAGE_SIZE};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest as ShaDigest, Sha256};
 use tempfile::tempdir_in;
 use zip::ZipArchive;
@@ -71,6 +71,24 @@ struct Risc0Method {
     elf_path: PathBuf,
 }
 
+/// A record of how a guest method was built, embedded as `*_MANIFEST` in the
+/// generated methods.rs as JSON.
+///
+/// Publishing the manifest of a hermetic build lets others check that their
+/// own build of the same source produces the same image ID.
+#[derive(Debug, Serialize)]
+struct GuestManifest<'a> {
+    name: &'a str,
+    /// Hex SHA-256 of the ELF binary.
+    elf_sha256: String,
+    /// Hex image ID, as displayed by [Digest].
+    image_id: String,
+    /// The `rustc -V` of the toolchain that built the guest.
+    toolchain: &'a str,
+    hermetic: bool,
+    features: &'a [String],
+}
+
 impl Risc0Method {
     fn make_image_id(&self) -> Digest {
         if !self.elf_path.exists() {
@@ -87,7 +105,21 @@ impl Risc0Method {
         image.compute_id()
     }
 
-    fn rust_def(&self) -> String {
+    fn manifest(&self, toolchain: &str, options: &GuestOptions) -> String {
+        let image_id = self.make_image_id();
+        let elf = fs::read(&self.elf_path).unwrap();
+        let manifest = GuestManifest {
+            name: &self.name,
+            elf_sha256: sha_digest_with_hex(&elf).1,
+            image_id: image_id.to_string(),
+            toolchain,
+            hermetic: options.hermetic.is_some(),
+            features: &options.features,
+        };
+        serde_json::to_string(&manifest).unwrap()
+    }
+
+    fn rust_def(&self, manifest: &str) -> String {
         let elf_path = self.elf_path.display();
 
         // Quick check for '#' to avoid injection of arbitrary Rust code into the the
@@ -105,6 +137,7 @@ impl Risc0Method {
 pub const {upper}_ELF: &[u8] = &{elf_contents:?};
 pub const {upper}_ID: [u32; 8] = {image_id:?};
 pub const {upper}_PATH: &str = r#"{elf_path}"#;
+pub const {upper}_MANIFEST: &str = {manifest:?};
 "##
         )
     }
@@ -345,14 +378,97 @@ where
     fs::rename(&tmp_dest_base, dest_base.as_ref()).unwrap();
 }
 
+// Environment variables that change how cargo or rustc compile, and so are
+// cleared for hermetic builds.
+const BUILD_ENV_PREFIXES: &[&str] = &[
+    "CARGO_BUILD_",
+    "CARGO_ENCODED_RUSTFLAGS",
+    "CARGO_INCREMENTAL",
+    "CARGO_PROFILE_",
+    "CARGO_TARGET_",
+    "RUSTC",
+    "RUSTDOCFLAGS",
+    "RUSTFLAGS",
+];
+
+/// Returns the `rustc -V` of the toolchain guests are built with.
+fn toolchain_version(hermetic: Option<&HermeticOptions>) -> String {
+    let mut cmd = match hermetic {
+        Some(hermetic) => {
+            let mut cmd = Command::new("rustc");
+            cmd.env("RUSTUP_TOOLCHAIN", &hermetic.toolchain);
+            cmd
+        }
+        None => Command::new(env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string())),
+    };
+    let output = cmd.arg("-V").output().expect("Failed to run rustc");
+    if !output.status.success() {
+        eprintln!(
+            "ERROR: Toolchain for guest builds is not available: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        std::process::exit(-1);
+    }
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+/// Returns the deepest directory containing every local package that `pkg`
+/// is built from.
+fn source_root(pkg: &Package) -> PathBuf {
+    let metadata = MetadataCommand::new()
+        .manifest_path(&pkg.manifest_path)
+        .other_options(vec!["--locked".to_string()])
+        .exec()
+        .unwrap();
+    metadata
+        .packages
+        .iter()
+        .filter(|dep| dep.source.is_none())
+        .map(|dep| {
+            dep.manifest_path
+                .parent()
+                .unwrap()
+                .as_std_path()
+                .to_path_buf()
+        })
+        .reduce(|root, dir| {
+            root.components()
+                .zip(dir.components())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect()
+        })
+        .unwrap()
+}
+
+/// Returns the `--remap-path-prefix` flags that strip machine-specific paths
+/// from a hermetic build. Later flags take precedence, so the more specific
+/// prefixes come last.
+fn remap_path_flags(pkg: &Package, guest_build_env: &GuestBuildEnv) -> Vec<String> {
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| directories::BaseDirs::new().map(|dirs| dirs.home_dir().join(".cargo")));
+    let mut remaps = vec![(source_root(pkg), "/src")];
+    remaps.extend(cargo_home.map(|dir| (dir, "/cargo")));
+    remaps.push((guest_build_env.rust_lib_src.clone(), "/rust-std"));
+    remaps
+        .into_iter()
+        .flat_map(|(from, to)| {
+            [
+                "--remap-path-prefix".to_string(),
+                format!("{}={to}", from.display()),
+            ]
+        })
+        .collect()
+}
+
 // Builds a package that targets the riscv guest into the specified target
 // directory.
 fn build_guest_package<P>(
     pkg: &Package,
     target_dir: P,
     guest_build_env: &GuestBuildEnv,
-    features: Vec<String>,
-    std: bool,
+    options: &GuestOptions,
 ) where
     P: AsRef<Path>,
 {
@@ -363,9 +479,28 @@ fn build_guest_package<P>(
     }
 
     fs::create_dir_all(target_dir.as_ref()).unwrap();
-    let cargo = env::var("CARGO").unwrap();
+    let hermetic = options.hermetic.as_ref();
+    let mut cmd = match hermetic {
+        // Go through the rustup proxy rather than the cargo running this
+        // build script, which may belong to a different toolchain.
+        Some(hermetic) => {
+            let mut cmd = Command::new("cargo");
+            cmd.env("RUSTUP_TOOLCHAIN", &hermetic.toolchain);
+            for (key, _) in env::vars_os() {
+                let name = key.to_str().unwrap_or_default();
+                if BUILD_ENV_PREFIXES
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+                {
+                    cmd.env_remove(&key);
+                }
+            }
+            cmd
+        }
+        None => Command::new(env::var("CARGO").unwrap()),
+    };
     let mut std_parts = vec!["alloc", "core", "proc_macro", "panic_abort"];
-    if std {
+    if options.std {
         std_parts.push("std");
     }
     let build_std = format!("build-std={}", std_parts.join(","));
@@ -383,48 +518,56 @@ fn build_guest_package<P>(
         "--target-dir",
         target_dir.as_ref().to_str().unwrap(),
     ];
-    let features_str = features.join(",");
-    if !features.is_empty() {
+    let features_str = options.features.join(",");
+    if !options.features.is_empty() {
         args.push("--features");
         args.push(&features_str);
     }
-    println!("Building guest package: {cargo} {}", args.join(" "));
+    if hermetic.is_some() {
+        // Dependencies come from the guest's Cargo.lock, not from whatever
+        // the registry holds today.
+        args.push("--locked");
+    }
+    println!(
+        "Building guest package: {} {}",
+        cmd.get_program().to_string_lossy(),
+        args.join(" ")
+    );
     // The RISC0_STANDARD_LIB variable can be set for testing purposes
     // to override the downloaded standard library.  It should point
     // to the root of the rust repository.
-    let risc0_standard_lib: String = if let Ok(path) = env::var("RISC0_STANDARD_LIB") {
-        path
-    } else {
-        guest_build_env.rust_lib_src.to_str().unwrap().into()
+    let risc0_standard_lib: String = match env::var("RISC0_STANDARD_LIB") {
+        Ok(path) if hermetic.is_none() => path,
+        _ => guest_build_env.rust_lib_src.to_str().unwrap().into(),
     };
 
     println!("Using rust standard library root: {}", risc0_standard_lib);
 
-    let mut cmd = Command::new(cargo);
+    let mut rustflags = vec![
+        // Replace atomic ops with nonatomic versions since the guest is single threaded.
+        "-C".to_string(),
+        "passes=loweratomic".to_string(),
+        // Remap absolute pathnames in compiled ELFs for builds that are more reproducible.
+        "-Z".to_string(),
+        "remap-cwd-prefix=.".to_string(),
+        // Specify where to start loading the program in
+        // memory.  The clang linker understands the same
+        // command line arguments as the GNU linker does; see
+        // https://ftp.gnu.org/old-gnu/Manuals/ld-2.9.1/html_mono/ld.html#SEC3
+        // for details.
+        "-C".to_string(),
+        format!("link-arg=-Ttext=0x{:08X}", memory::TEXT_START),
+        // Apparently not having an entry point is only a linker warning(!), so
+        // error out in this case.
+        "-C".to_string(),
+        "link-arg=--fatal-warnings".to_string(),
+    ];
+    if hermetic.is_some() {
+        rustflags.extend(remap_path_flags(pkg, guest_build_env));
+    }
+
     let mut child = cmd
-        .env(
-            "CARGO_ENCODED_RUSTFLAGS",
-            [
-                // Replace atomic ops with nonatomic versions since the guest is single threaded.
-                "-C",
-                "passes=loweratomic",
-                // Remap absolute pathnames in compiled ELFs for builds that are more reproducible.
-                "-Z",
-                "remap-cwd-prefix=.",
-                // Specify where to start loading the program in
-                // memory.  The clang linker understands the same
-                // command line arguments as the GNU linker does; see
-                // https://ftp.gnu.org/old-gnu/Manuals/ld-2.9.1/html_mono/ld.html#SEC3
-                // for details.
-                "-C",
-                &format!("link-arg=-Ttext=0x{:08X}", memory::TEXT_START),
-                // Apparently not having an entry point is only a linker warning(!), so
-                // error out in this case.
-                "-C",
-                "link-arg=--fatal-warnings",
-            ]
-            .join("\x1f"),
-        )
+        .env("CARGO_ENCODED_RUSTFLAGS", rustflags.join("\x1f"))
         .env("__CARGO_TESTS_ONLY_SRC_ROOT", risc0_standard_lib)
         .args(args)
         .stderr(Stdio::piped())
@@ -475,6 +618,10 @@ pub struct GuestOptions {
 
     /// Enable standard library support
     pub std: bool,
+
+    /// Build the guest hermetically, so that the same source gives the same
+    /// image ID on any machine.
+    pub hermetic: Option<HermeticOptions>,
 }
 
 impl Default for GuestOptions {
@@ -482,10 +629,24 @@ impl Default for GuestOptions {
         GuestOptions {
             features: vec![],
             std: true,
+            hermetic: None,
         }
     }
 }
 
+/// Settings for a hermetic guest build.
+///
+/// A hermetic build runs the given rustup toolchain whatever toolchain the
+/// outer build uses, ignores environment variables that change compiler
+/// flags, requires the guest's Cargo.lock to be up to date, always uses the
+/// pinned standard library sources, and strips local paths from the ELF. The
+/// image ID then depends only on the source, its lockfile and the toolchain,
+/// which is recorded in the `*_MANIFEST`.
+pub struct HermeticOptions {
+    /// The rustup toolchain to build with, e.g. `nightly-2023-03-06`.
+    pub toolchain: String,
+}
+
 /// Embeds methods built for RISC-V for use by host-side dependencies.
 /// Specify custom options for a guest package by defining its [GuestOptions].
 /// See [embed_methods].
@@ -524,17 +685,13 @@ pub fn embed_methods_with_options(mut guest_pkg_to_options: HashMap<&str, GuestO
             .remove(guest_pkg.name.as_str())
             .unwrap_or_default();
 
-        build_guest_package(
-            &guest_pkg,
-            &guest_dir,
-            &guest_build_env,
-            guest_options.features,
-            guest_options.std,
-        );
+        build_guest_package(&guest_pkg, &guest_dir, &guest_build_env, &guest_options);
 
+        let toolchain = toolchain_version(guest_options.hermetic.as_ref());
         for method in guest_methods(&guest_pkg, &guest_dir) {
+            let manifest = method.manifest(&toolchain, &guest_options);
             methods_file
-                .write_all(method.rust_def().as_bytes())
+                .write_all(method.rust_def(&manifest).as_bytes())
                 .unwrap();
 
             #[cfg(feature = "guest-list")]
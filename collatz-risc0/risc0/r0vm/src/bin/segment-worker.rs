@@ -0,0 +1,81 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{path::PathBuf, thread, time::Duration};
+
+use clap::Parser;
+use risc0_zkvm::prove::{default_parallel_prover, get_parallel_prover, Spool, SpoolWorker};
+
+/// Proves the segments published to a spool directory by another process.
+#[derive(Parser)]
+#[clap(about, version, author)]
+struct Args {
+    /// The spool directory shared with the coordinator.
+    #[clap(long)]
+    spool: PathBuf,
+
+    /// The prover to use, e.g. "cpu" or "cpu:poseidon". Must match the
+    /// coordinator's. Defaults to RISC0_PROVER, or else "cpu".
+    #[clap(long)]
+    prover: Option<String>,
+
+    /// The name written into this worker's claims. Defaults to the process ID.
+    #[clap(long)]
+    name: Option<String>,
+
+    /// Seconds between touches of a claim while proving. Keep this well under
+    /// the coordinator's lease timeout.
+    #[clap(long, default_value_t = 10)]
+    heartbeat_secs: u64,
+
+    /// Milliseconds to wait before looking for work again when there is none.
+    #[clap(long, default_value_t = 500)]
+    poll_ms: u64,
+
+    /// Exit once there is nothing left to claim, instead of waiting for more.
+    #[clap(long)]
+    exit_when_idle: bool,
+}
+
+fn main() {
+    env_logger::init();
+
+    let args = Args::parse();
+    let spool = Spool::open(&args.spool).unwrap();
+    let prover = match args.prover.as_deref() {
+        Some(name) => get_parallel_prover(name),
+        None => default_parallel_prover(),
+    };
+    let name = args.name.unwrap_or_else(|| std::process::id().to_string());
+    let worker = SpoolWorker::new(
+        spool,
+        &name,
+        prover,
+        Duration::from_secs(args.heartbeat_secs),
+    );
+
+    loop {
+        match worker.work_once() {
+            Ok(Some(index)) => eprintln!("Proved segment {index}"),
+            Ok(None) if args.exit_when_idle => break,
+            Ok(None) => thread::sleep(Duration::from_millis(args.poll_ms)),
+            // The claim is left to go stale, so the coordinator decides
+            // whether the segment is worth another attempt.
+            Err(err) => {
+                eprintln!("Failed to prove a segment: {err:#}");
+                thread::sleep(Duration::from_millis(args.poll_ms));
+            }
+        }
+    }
+}
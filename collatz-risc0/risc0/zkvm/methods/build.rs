@@ -30,6 +30,7 @@ fn main() {
             GuestOptions {
                 features: Vec::new(),
                 std: false,
+                hermetic: None,
             },
         ),
         (
@@ -37,6 +38,7 @@ fn main() {
             GuestOptions {
                 features: vec!["test_feature1".to_string(), "test_feature2".to_string()],
                 std: true,
+                hermetic: None,
             },
         ),
     ]);
@@ -152,6 +152,17 @@ impl MemoryImage {
         Ok(img)
     }
 
+    /// Reassemble an image from the pages of [MemoryImage::pages] and the
+    /// rest of an image they came from.
+    pub(crate) fn from_pages(pages: BTreeMap<u32, Vec<u8>>, info: PageTableInfo, pc: u32) -> Self {
+        Self { pages, info, pc }
+    }
+
+    /// The pages held by this image, by page index. Pages not held are zero.
+    pub(crate) fn pages(&self) -> &BTreeMap<u32, Vec<u8>> {
+        &self.pages
+    }
+
     /// Load a page specified by page_idx. If no page is found, a zero page is
     /// returned.
     pub fn load_page(&self, page_idx: u32) -> Vec<u8> {
@@ -272,7 +283,7 @@ impl MemoryImage {
     }
 }
 
-fn hash_page_bytes(page: &[u8]) -> Digest {
+pub(crate) fn hash_page_bytes(page: &[u8]) -> Digest {
     let mut state = SHA256_INIT;
     assert!(page.len() % BLOCK_BYTES == 0);
     for block in page.chunks_exact(BLOCK_BYTES) {
@@ -0,0 +1,100 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Driving an [Executor] from async code.
+
+use std::sync::mpsc;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::{oneshot, watch};
+
+use super::{CancelToken, Executor, Progress};
+use crate::{Segment, SegmentRef, Session, SimpleSegmentRef};
+
+type Reply = oneshot::Sender<Result<Session>>;
+
+/// Runs an [Executor] on tokio's blocking thread pool.
+///
+/// An [Executor] cannot move between threads, so the driver builds it on a
+/// blocking thread and keeps it there, running it whenever
+/// [ExecutorDriver::run] is awaited. The thread is given back to the pool
+/// once the driver is dropped and the last run has finished.
+pub struct ExecutorDriver {
+    commands: mpsc::Sender<(CancelToken, Reply)>,
+    progress: watch::Receiver<Progress>,
+}
+
+impl ExecutorDriver {
+    /// Build an executor with `make` and keep its [Segment]s in memory.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn spawn<M>(make: M) -> Self
+    where
+        M: FnOnce() -> Result<Executor<'static>> + Send + 'static,
+    {
+        Self::spawn_with_callback(make, |segment| Ok(Box::new(SimpleSegmentRef::new(segment))))
+    }
+
+    /// Build an executor with `make` and hand each [Segment] to `callback`,
+    /// as [Executor::run_with_callback] does.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn spawn_with_callback<M, F>(make: M, mut callback: F) -> Self
+    where
+        M: FnOnce() -> Result<Executor<'static>> + Send + 'static,
+        F: FnMut(Segment) -> Result<Box<dyn SegmentRef>> + Send + 'static,
+    {
+        let (commands, command_rx) = mpsc::channel::<(CancelToken, Reply)>();
+        let (progress_tx, progress) = watch::channel(Progress::default());
+        tokio::task::spawn_blocking(move || {
+            let mut exec = match make() {
+                Ok(exec) => exec,
+                Err(err) => {
+                    let err = format!("{err:#}");
+                    for (_, reply) in command_rx {
+                        let _ = reply.send(Err(anyhow!("Failed to build executor: {err}")));
+                    }
+                    return;
+                }
+            };
+            for (cancel, reply) in command_rx {
+                let session = exec.run_with_control(&mut callback, &cancel, |progress| {
+                    progress_tx.send_replace(progress);
+                });
+                let _ = reply.send(session);
+            }
+        });
+        Self { commands, progress }
+    }
+
+    /// Run the executor until it halts, pauses or `cancel` is cancelled, as
+    /// [Executor::run_with_control] does.
+    ///
+    /// Runs happen one at a time, in the order they were asked for.
+    pub async fn run(&self, cancel: CancelToken) -> Result<Session> {
+        let (reply, session) = oneshot::channel();
+        self.commands
+            .send((cancel, reply))
+            .map_err(|_| anyhow!("Executor thread exited"))?;
+        session
+            .await
+            .map_err(|_| anyhow!("Executor thread exited"))?
+    }
+
+    /// A receiver of the latest [Progress] of the executor, updated while it
+    /// runs.
+    pub fn progress(&self) -> watch::Receiver<Progress> {
+        self.progress.clone()
+    }
+}
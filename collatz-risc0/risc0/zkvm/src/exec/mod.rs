@@ -18,6 +18,8 @@
 //! one or more [Segment]s, each of which contains an execution trace of the
 //! specified program.
 
+#[cfg(feature = "async")]
+pub(crate) mod driver;
 mod env;
 pub(crate) mod io;
 mod monitor;
@@ -26,7 +28,17 @@ pub(crate) mod profiler;
 #[cfg(test)]
 mod tests;
 
-use std::{cell::RefCell, fmt::Debug, io::Write, mem::take, rc::Rc};
+use std::{
+    cell::RefCell,
+    fmt::{self, Debug},
+    io::Write,
+    mem::take,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::{anyhow, bail, Context, Result};
 use crypto_bigint::{CheckedMul, Encoding, NonZero, U256, U512};
@@ -65,6 +77,13 @@ const SHA_CYCLES: usize = 72;
 /// Number of cycles required to complete a BigInt operation.
 const BIGINT_CYCLES: usize = 9;
 
+/// The version of [ExecutorCheckpoint] written by this build.
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// How many instructions run between checks for cancellation, and between
+/// [Progress] reports.
+const CONTROL_INTERVAL: usize = 1 << 12;
+
 /// The Executor provides an implementation for the execution phase.
 ///
 /// The proving phase uses an execution trace generated by the Executor.
@@ -83,6 +102,75 @@ pub struct Executor<'a> {
     pending_syscall: Option<SyscallRecord>,
     syscalls: Vec<SyscallRecord>,
     exit_code: Option<ExitCode>,
+    journal: Journal,
+    cancelled_segments: u32,
+}
+
+/// Stops an [Executor::run_with_control] from another thread.
+///
+/// Cancelling cannot be undone, so each run that may be cancelled needs a
+/// token of its own.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Construct a [CancelToken] that has not been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the run holding this token to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [CancelToken::cancel] has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// How far an execution has got.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Cycles executed so far, counting each finished segment as full.
+    pub cycles: usize,
+
+    /// Segments finished so far, including those of cancelled runs this one
+    /// resumes.
+    pub segments: usize,
+}
+
+/// The error an [Executor] run fails with when the session reaches the
+/// limit set by [ExecutorEnvBuilder::session_limit], so that callers can
+/// tell it apart from other failures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionLimitExceeded;
+
+impl fmt::Display for SessionLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Session limit exceeded")
+    }
+}
+
+impl std::error::Error for SessionLimitExceeded {}
+
+/// A snapshot of an [Executor] between segments, from which
+/// [Executor::restore] continues the execution exactly as the original
+/// executor would have.
+///
+/// Checkpoints can be taken before the first call to [Executor::run] and
+/// after any call that returned [ExitCode::Paused], and are serializable so
+/// that a long execution can survive its process.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExecutorCheckpoint {
+    version: u32,
+    pre_image: MemoryImage,
+    memory: MemoryImage,
+    pc: u32,
+    exit_code: Option<ExitCode>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -133,8 +221,16 @@ impl<'a> Executor<'a> {
     /// the guest program is executed to determine how its proof should be
     /// divided into subparts.
     pub fn new(env: ExecutorEnv<'a>, image: MemoryImage, pc: u32) -> Self {
-        let pre_image = image.clone();
-        let monitor = MemoryMonitor::new(image, env.trace_callback.is_some());
+        Self::with_memory(env, image.clone(), image, pc)
+    }
+
+    fn with_memory(
+        env: ExecutorEnv<'a>,
+        pre_image: MemoryImage,
+        memory: MemoryImage,
+        pc: u32,
+    ) -> Self {
+        let monitor = MemoryMonitor::new(memory, env.trace_callback.is_some());
         let loader = Loader::new();
         let init_cycles = loader.init_cycles();
         let fini_cycles = loader.fini_cycles();
@@ -154,6 +250,8 @@ impl<'a> Executor<'a> {
             pending_syscall: None,
             syscalls: Vec::new(),
             exit_code: None,
+            journal: Journal::default(),
+            cancelled_segments: 0,
         }
     }
 
@@ -199,25 +297,75 @@ impl<'a> Executor<'a> {
 
     /// Run the executor until [ExitCode::Paused] or [ExitCode::Halted] is
     /// reached, producing a [Session] as a result.
-    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<Session>
+    pub fn run_with_callback<F>(&mut self, callback: F) -> Result<Session>
     where
         F: FnMut(Segment) -> Result<Box<dyn SegmentRef>>,
+    {
+        self.run_with_control(callback, &CancelToken::new(), |_| ())
+    }
+
+    /// Run the executor like [Executor::run_with_callback], reporting
+    /// [Progress] to `progress` as it goes, and once more when it pauses or
+    /// halts, and stopping early once `cancel` is cancelled.
+    ///
+    /// A cancelled run ends its current segment at the last instruction
+    /// executed, as if the segment were full, and returns the [Session] so
+    /// far with an [ExitCode] of [SystemSplit](ExitCode::SystemSplit). That
+    /// session cannot be proven on its own. Either discard it, or run the
+    /// executor again to resume: the segments of the next run carry on from
+    /// it, and its journal includes what was written before cancelling, so
+    /// [Session::append] joins the two into the session an uninterrupted run
+    /// would have proven.
+    pub fn run_with_control<F, P>(
+        &mut self,
+        mut callback: F,
+        cancel: &CancelToken,
+        mut progress: P,
+    ) -> Result<Session>
+    where
+        F: FnMut(Segment) -> Result<Box<dyn SegmentRef>>,
+        P: FnMut(Progress),
     {
         if let Some(ExitCode::Halted(_)) = self.exit_code {
             bail!("cannot resume an execution which exited with ExitCode::Halted");
         }
 
-        self.monitor.clear_session();
+        if self.exit_code != Some(ExitCode::SystemSplit) {
+            self.monitor.clear_session();
+            self.journal = Journal::default();
+            self.cancelled_segments = 0;
+        }
 
-        let journal = Journal::default();
+        let journal = self.journal.clone();
         self.env
             .io
             .borrow_mut()
             .with_write_fd(fileno::JOURNAL, journal.clone());
 
         let mut run_loop = || -> Result<ExitCode> {
+            let mut steps = 0;
             loop {
-                if let Some(exit_code) = self.step()? {
+                let mut cancelled = false;
+                if steps % CONTROL_INTERVAL == 0 {
+                    progress(self.progress());
+                    if cancel.is_cancelled() {
+                        if self.insn_counter == 0 {
+                            return Ok(ExitCode::SystemSplit);
+                        }
+                        // End the segment before the next instruction, just
+                        // as a split does when that instruction won't fit.
+                        self.split_insn = Some(self.insn_counter);
+                        cancelled = true;
+                    }
+                }
+                steps += 1;
+
+                let exit_code = if cancelled {
+                    Some(ExitCode::SystemSplit)
+                } else {
+                    self.step()?
+                };
+                if let Some(exit_code) = exit_code {
                     let total_cycles = self.total_cycles();
                     log::debug!("exit_code: {exit_code:?}, total_cycles: {total_cycles}");
                     assert!(total_cycles <= (1 << self.env.segment_limit_po2));
@@ -234,8 +382,7 @@ impl<'a> Executor<'a> {
                         exit_code,
                         self.split_insn,
                         log2_ceil(total_cycles.next_power_of_two()),
-                        self.segments
-                            .len()
+                        (self.cancelled_segments as usize + self.segments.len())
                             .try_into()
                             .context("Too many segments to fit in u32")?,
                         self.body_cycles,
@@ -243,15 +390,29 @@ impl<'a> Executor<'a> {
                     let segment_ref = callback(segment)?;
                     self.segments.push(segment_ref);
                     match exit_code {
-                        ExitCode::SystemSplit => self.split(post_image),
-                        ExitCode::SessionLimit => bail!("Session limit exceeded"),
+                        ExitCode::SystemSplit => {
+                            self.split(post_image);
+                            progress(self.progress());
+                            if cancelled {
+                                log::debug!("Cancelled: {}", self.segments.len());
+                                return Ok(exit_code);
+                            }
+                        }
+                        ExitCode::SessionLimit => bail!(SessionLimitExceeded),
                         ExitCode::Paused(inner) => {
                             log::debug!("Paused({inner}): {}", self.segment_cycle);
                             self.split(post_image);
+                            progress(self.progress());
                             return Ok(exit_code);
                         }
                         ExitCode::Halted(inner) => {
                             log::debug!("Halted({inner}): {}", self.segment_cycle);
+                            // The halted segment is finished, so it counts as
+                            // full rather than adding its cycles again.
+                            progress(Progress {
+                                cycles: self.session_cycle() - self.segment_cycle,
+                                ..self.progress()
+                            });
                             return Ok(exit_code);
                         }
                     };
@@ -261,11 +422,81 @@ impl<'a> Executor<'a> {
 
         let exit_code = run_loop()?;
         self.exit_code = Some(exit_code);
-        Ok(Session::new(
-            take(&mut self.segments),
-            journal.buf.take(),
-            exit_code,
-        ))
+        let journal = if exit_code == ExitCode::SystemSplit {
+            // Keep the journal for the run that resumes this one.
+            self.cancelled_segments += self.segments.len() as u32;
+            journal.buf.borrow().clone()
+        } else {
+            journal.buf.take()
+        };
+        Ok(Session::new(take(&mut self.segments), journal, exit_code))
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            cycles: self.session_cycle(),
+            segments: self.cancelled_segments as usize + self.segments.len(),
+        }
+    }
+
+    /// The memory image a paused execution resumes from, or [None] unless the
+    /// last run ended with [ExitCode::Paused].
+    ///
+    /// The image holds the guest's registers and its `pc`, so passing it to
+    /// [Executor::new] with a fresh [ExecutorEnv] continues the execution
+    /// where it paused, e.g. after a restart or on another machine. The
+    /// segments of the continued execution chain from the post-state of the
+    /// last paused segment.
+    pub fn paused_image(&self) -> Option<&MemoryImage> {
+        match self.exit_code {
+            Some(ExitCode::Paused(_)) => Some(&self.pre_image),
+            _ => None,
+        }
+    }
+
+    /// Snapshot the state of this executor so that it can be continued later
+    /// with [Executor::restore].
+    ///
+    /// Fails if the execution has halted, or if the executor is in the middle
+    /// of a segment, which is only the case if the last call to
+    /// [Executor::run] failed or instructions were executed with
+    /// [Executor::step].
+    pub fn checkpoint(&self) -> Result<ExecutorCheckpoint> {
+        if let Some(ExitCode::Halted(_)) = self.exit_code {
+            bail!("cannot checkpoint an execution which exited with ExitCode::Halted");
+        }
+        if let Some(ExitCode::SystemSplit) = self.exit_code {
+            bail!("cannot checkpoint a cancelled execution before it is resumed");
+        }
+        if self.insn_counter != 0 || !self.segments.is_empty() {
+            bail!("cannot checkpoint an executor in the middle of a segment");
+        }
+        Ok(ExecutorCheckpoint {
+            version: CHECKPOINT_VERSION,
+            pre_image: self.pre_image.clone(),
+            memory: self.monitor.image().clone(),
+            pc: self.pc,
+            exit_code: self.exit_code,
+        })
+    }
+
+    /// Construct an [Executor] that continues from `checkpoint`.
+    ///
+    /// The segments it produces are identical to those the checkpointed
+    /// executor would have produced. The `env` provides the input, I/O
+    /// handlers and limits from here on, so it only needs the input the guest
+    /// has yet to read.
+    pub fn restore(env: ExecutorEnv<'a>, checkpoint: ExecutorCheckpoint) -> Result<Self> {
+        if checkpoint.version != CHECKPOINT_VERSION {
+            bail!(
+                "unsupported checkpoint version {}, expected {CHECKPOINT_VERSION}",
+                checkpoint.version
+            );
+        }
+        let mut exec =
+            Self::with_memory(env, checkpoint.pre_image, checkpoint.memory, checkpoint.pc);
+        exec.exit_code = checkpoint.exit_code;
+        Ok(exec)
     }
 
     fn split(&mut self, pre_image: MemoryImage) {
@@ -382,7 +613,8 @@ impl<'a> Executor<'a> {
     }
 
     fn session_cycle(&self) -> usize {
-        self.segments.len() * self.env.get_segment_limit() + self.segment_cycle
+        (self.cancelled_segments as usize + self.segments.len()) * self.env.get_segment_limit()
+            + self.segment_cycle
     }
 
     fn ecall(&mut self) -> Result<OpCodeResult> {
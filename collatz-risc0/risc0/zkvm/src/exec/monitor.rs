@@ -358,6 +358,12 @@ impl MemoryMonitor {
         self.init_registers();
     }
 
+    /// The memory as of the start of the current segment, with the writes of
+    /// earlier segments flushed into it.
+    pub fn image(&self) -> &MemoryImage {
+        &self.image
+    }
+
     pub fn clear_session(&mut self) {
         self.clear_segment();
         self.session_cycle = 0;
@@ -22,10 +22,13 @@ use risc0_zkvm_methods::{
 use risc0_zkvm_platform::{fileno, PAGE_SIZE, WORD_SIZE};
 use test_log::test;
 
-use super::{Executor, ExecutorEnv, TraceEvent};
+use super::{CancelToken, Executor, ExecutorEnv, SessionLimitExceeded, TraceEvent};
+#[cfg(feature = "async")]
+use crate::ExecutorDriver;
 use crate::{
     serde::{from_slice, to_vec},
-    testutils, ExitCode, MemoryImage, Program, Session,
+    testutils::{self, busy_loop_env},
+    ExitCode, MemoryImage, Program, Segment, SegmentRef, Session, SimpleSegmentRef,
 };
 
 #[test]
@@ -55,6 +58,65 @@ fn basic() {
     assert_eq!(segments[0].index, 0);
 }
 
+#[test]
+fn resume_from_paused_image() {
+    let env = ExecutorEnv::builder()
+        .add_input(&to_vec(&MultiTestSpec::PauseContinue).unwrap())
+        .build()
+        .unwrap();
+    let mut exec = Executor::from_elf(env, MULTI_TEST_ELF).unwrap();
+    assert!(exec.paused_image().is_none());
+
+    let session = exec.run().unwrap();
+    assert_eq!(session.exit_code, ExitCode::Paused(0));
+    let paused = session.resolve().unwrap();
+    let image = exec.paused_image().unwrap().clone();
+    assert_eq!(image.compute_id(), paused.last().unwrap().post_image_id);
+
+    // A new executor picks up where the first one paused.
+    let pc = image.pc;
+    let mut resumed = Executor::new(ExecutorEnv::default(), image, pc);
+    let session = resumed.run().unwrap();
+    assert_eq!(session.exit_code, ExitCode::Halted(0));
+    let segments = session.resolve().unwrap();
+    assert_eq!(
+        segments[0].pre_image.compute_id(),
+        paused.last().unwrap().post_image_id
+    );
+    assert!(resumed.paused_image().is_none());
+}
+
+#[test]
+fn checkpoint_restore() {
+    let env = || {
+        ExecutorEnv::builder()
+            .add_input(&to_vec(&MultiTestSpec::PauseContinue).unwrap())
+            .build()
+            .unwrap()
+    };
+    let serialize = |session: Session| -> Vec<Vec<u8>> {
+        let segments = session.resolve().unwrap();
+        segments
+            .iter()
+            .map(|segment| bincode::serialize(segment).unwrap())
+            .collect()
+    };
+
+    let mut exec = Executor::from_elf(env(), MULTI_TEST_ELF).unwrap();
+    let first = serialize(exec.run().unwrap());
+    let rest = serialize(exec.run().unwrap());
+    assert!(exec.checkpoint().is_err());
+
+    let mut exec = Executor::from_elf(env(), MULTI_TEST_ELF).unwrap();
+    assert_eq!(serialize(exec.run().unwrap()), first);
+    let checkpoint = bincode::serialize(&exec.checkpoint().unwrap()).unwrap();
+    drop(exec);
+
+    let checkpoint = bincode::deserialize(&checkpoint).unwrap();
+    let mut restored = Executor::restore(ExecutorEnv::default(), checkpoint).unwrap();
+    assert_eq!(serialize(restored.run().unwrap()), rest);
+}
+
 #[test]
 fn system_split() {
     let entry = 0x4000;
@@ -550,6 +612,95 @@ fn session_limit() {
 
     let err = run_session(1 << 16, 15, 3).err().unwrap();
     assert!(err.to_string().contains("Session limit exceeded"));
+    assert!(err.is::<SessionLimitExceeded>());
 
     assert!(run_session(1 << 16, 15, 10).is_ok());
 }
+
+#[test]
+fn cancel_and_resume() {
+    let segment_limit_po2 = 16; // 64k cycles
+    let uninterrupted = Executor::from_elf(busy_loop_env(3, segment_limit_po2), MULTI_TEST_ELF)
+        .unwrap()
+        .run()
+        .unwrap();
+
+    let mut exec = Executor::from_elf(busy_loop_env(3, segment_limit_po2), MULTI_TEST_ELF).unwrap();
+
+    // Cancelling before the first instruction leaves nothing behind.
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let session = exec.run_with_control(simple, &cancel, |_| ()).unwrap();
+    assert_eq!(session.exit_code, ExitCode::SystemSplit);
+    assert!(session.segments.is_empty());
+
+    // Cancel partway into the second segment.
+    let cancel = CancelToken::new();
+    let mut reports = Vec::new();
+    let mut session = exec
+        .run_with_control(simple, &cancel, |progress| {
+            if progress.segments == 1 && progress.cycles > 1 << segment_limit_po2 {
+                cancel.cancel();
+            }
+            reports.push(progress);
+        })
+        .unwrap();
+    assert!(reports
+        .windows(2)
+        .all(|pair| pair[0].cycles <= pair[1].cycles));
+    assert_eq!(session.exit_code, ExitCode::SystemSplit);
+    let segments = session.resolve().unwrap();
+    assert_eq!(segments.len(), 2);
+    assert!(segments
+        .iter()
+        .all(|segment| segment.exit_code == ExitCode::SystemSplit));
+    assert!(exec.checkpoint().is_err());
+
+    let rest = exec.run().unwrap();
+    session.append(rest);
+    assert_eq!(session.exit_code, ExitCode::Halted(0));
+    assert_eq!(session.journal, uninterrupted.journal);
+    let segments = session.resolve().unwrap();
+    for (idx, pair) in segments.windows(2).enumerate() {
+        assert_eq!(pair[0].index, idx as u32);
+        assert_eq!(pair[0].exit_code, ExitCode::SystemSplit);
+        assert_eq!(pair[0].post_image_id, pair[1].pre_image.compute_id());
+    }
+    assert!(exec.run().is_err());
+}
+
+fn simple(segment: Segment) -> Result<Box<dyn SegmentRef>> {
+    Ok(Box::new(SimpleSegmentRef::new(segment)))
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn driver() {
+    let segment_limit_po2 = 16; // 64k cycles
+    let driver = ExecutorDriver::spawn(move || {
+        Executor::from_elf(busy_loop_env(3, segment_limit_po2), MULTI_TEST_ELF)
+    });
+
+    // Revoke the run once the first segment is done.
+    let cancel = CancelToken::new();
+    let mut progress = driver.progress();
+    let watcher = tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            while progress.changed().await.is_ok() {
+                if progress.borrow().segments > 0 {
+                    cancel.cancel();
+                    break;
+                }
+            }
+        }
+    });
+    let mut session = driver.run(cancel).await.unwrap();
+    watcher.await.unwrap();
+    assert_eq!(session.exit_code, ExitCode::SystemSplit);
+    assert!(!session.segments.is_empty());
+
+    session.append(driver.run(CancelToken::new()).await.unwrap());
+    assert_eq!(session.exit_code, ExitCode::Halted(0));
+    assert_eq!(driver.progress().borrow().segments, session.segments.len());
+}
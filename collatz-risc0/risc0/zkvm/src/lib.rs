@@ -35,6 +35,8 @@ pub mod serde;
 #[cfg(feature = "prove")]
 mod session;
 pub mod sha;
+#[cfg(feature = "prove")]
+mod store;
 
 #[cfg(test)]
 mod testutils;
@@ -45,19 +47,26 @@ use risc0_zkp::core::hash::{
     poseidon::PoseidonHashFn,
     sha::{Sha256, Sha256HashFn},
 };
+pub use risc0_zkp::verify::VerificationError;
 pub use risc0_zkvm_platform::{declare_syscall, memory::MEM_SIZE, PAGE_SIZE};
 
 #[cfg(feature = "binfmt")]
 pub use self::binfmt::{elf::Program, image::MemoryImage};
+#[cfg(feature = "async")]
+pub use self::exec::driver::ExecutorDriver;
 #[cfg(feature = "profiler")]
 pub use self::exec::profiler::Profiler;
 pub use self::receipt::{ExitCode, SegmentReceipt, SessionFlatReceipt, SessionReceipt};
 #[cfg(feature = "prove")]
 pub use self::{
     exec::io::{Syscall, SyscallContext},
-    exec::{Executor, ExecutorEnv, ExecutorEnvBuilder},
+    exec::{
+        CancelToken, Executor, ExecutorCheckpoint, ExecutorEnv, ExecutorEnvBuilder, Progress,
+        SessionLimitExceeded,
+    },
     prove::loader::Loader,
     session::{FileSegmentRef, Segment, SegmentRef, Session, SimpleSegmentRef},
+    store::{SegmentStore, StoredSegmentRef},
 };
 use crate::control_id::{RawControlId, BLAKE2B_CONTROL_ID, POSEIDON_CONTROL_ID, SHA256_CONTROL_ID};
 #[cfg(not(target_os = "zkvm"))]
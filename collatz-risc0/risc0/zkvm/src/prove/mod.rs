@@ -35,7 +35,10 @@
 
 mod exec;
 pub(crate) mod loader;
+mod parallel;
 mod plonk;
+mod queue;
+mod spool;
 #[cfg(test)]
 mod tests;
 
@@ -61,6 +64,14 @@ use risc0_zkp::{
 use risc0_zkvm_platform::WORD_SIZE;
 
 use self::{exec::MachineContext, loader::Loader};
+pub use self::{
+    parallel::{
+        default_parallel_prover, get_parallel_prover, segment_memory, ParallelConfig,
+        ParallelProver, ThreadedProver,
+    },
+    queue::ProvingQueue,
+    spool::{Spool, SpoolConfig, SpoolSegmentRef, SpoolWorker},
+};
 use crate::{
     receipt::SessionReceipt, ControlId, Segment, SegmentReceipt, Session, SessionFlatReceipt,
     CIRCUIT,
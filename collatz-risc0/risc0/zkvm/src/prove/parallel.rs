@@ -0,0 +1,312 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proving the segments of a [Session] concurrently.
+//!
+//! Segments are proven independently of one another, so a session with many
+//! of them can be proven on several threads at once. The receipts are put
+//! back in segment order, so the result is the same [SessionFlatReceipt] that
+//! [Prover::prove_session] produces.
+
+use std::{
+    collections::HashMap,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Result};
+use risc0_circuit_rv32im::{REGISTER_GROUP_ACCUM, REGISTER_GROUP_CODE, REGISTER_GROUP_DATA};
+use risc0_core::field::baby_bear::{BabyBear, Elem, ExtElem};
+use risc0_zkp::{
+    adapter::TapsProvider,
+    core::{digest::Digest, hash::HashSuite},
+    hal::{EvalCheck, Hal},
+    verify::CpuVerifyHal,
+    INV_RATE,
+};
+
+use super::{cpu, HalEval, LocalProver, Prover};
+use crate::{
+    receipt::SessionReceipt, ControlId, Segment, SegmentReceipt, Session, SessionFlatReceipt,
+    CIRCUIT,
+};
+
+/// How many segments to prove at once.
+#[derive(Clone, Debug)]
+pub struct ParallelConfig {
+    /// The number of segments proven at the same time.
+    pub threads: usize,
+
+    /// An upper bound in bytes on the [segment_memory] of the segments being
+    /// proven at the same time. A segment is always proven once nothing else
+    /// is, even if it alone exceeds the bound.
+    pub max_memory: Option<usize>,
+}
+
+impl Default for ParallelConfig {
+    /// One thread per available core, with no memory bound.
+    fn default() -> Self {
+        Self {
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            max_memory: None,
+        }
+    }
+}
+
+/// A rough figure for the memory used while proving a segment of `2^po2`
+/// cycles: the committed register groups, evaluated over the extended domain.
+pub fn segment_memory(po2: usize) -> usize {
+    let taps = CIRCUIT.get_taps();
+    let columns: usize = [
+        REGISTER_GROUP_ACCUM,
+        REGISTER_GROUP_CODE,
+        REGISTER_GROUP_DATA,
+    ]
+    .into_iter()
+    .map(|group| taps.group_size(group))
+    .sum();
+    (columns * (1 + INV_RATE) * std::mem::size_of::<Elem>()) << po2
+}
+
+/// A [Prover] that can be shared between threads and proves the segments of a
+/// session concurrently.
+pub trait ParallelProver: Prover + Send + Sync {
+    /// Prove the segments of `session` on up to `config.threads` threads and
+    /// collect the receipts in segment order.
+    fn prove_session_parallel(
+        &self,
+        session: &Session,
+        config: &ParallelConfig,
+    ) -> Result<SessionFlatReceipt>;
+
+    /// Verify `receipt`, made from receipts of this prover, against
+    /// `image_id` with this prover's hash function.
+    fn verify_session(&self, receipt: &SessionFlatReceipt, image_id: Digest) -> Result<()>;
+
+    /// Verify the seal of a single segment `receipt`, made by this prover,
+    /// with this prover's hash function.
+    fn verify_segment(&self, receipt: &SegmentReceipt) -> Result<()>;
+}
+
+/// A [ParallelProver] that runs locally.
+///
+/// A [HalEval] is not shared between threads, so each thread makes its own
+/// with the function given to [ThreadedProver::new].
+pub struct ThreadedProver<H, E>
+where
+    H: Hal<Field = BabyBear, Elem = Elem, ExtElem = ExtElem>,
+    <<H as Hal>::HashSuite as HashSuite<BabyBear>>::HashFn: ControlId,
+    E: EvalCheck<H>,
+{
+    name: String,
+    hal_eval: fn() -> HalEval<H, E>,
+}
+
+impl<H, E> ThreadedProver<H, E>
+where
+    H: Hal<Field = BabyBear, Elem = Elem, ExtElem = ExtElem>,
+    <<H as Hal>::HashSuite as HashSuite<BabyBear>>::HashFn: ControlId,
+    E: EvalCheck<H>,
+{
+    /// Construct a [ThreadedProver] with the given name, making a [HalEval]
+    /// for each thread with `hal_eval`.
+    pub fn new(name: &str, hal_eval: fn() -> HalEval<H, E>) -> Self {
+        Self {
+            name: name.to_string(),
+            hal_eval,
+        }
+    }
+
+    fn local(&self) -> LocalProver<H, E> {
+        LocalProver::new(&self.name, (self.hal_eval)())
+    }
+}
+
+impl<H, E> Prover for ThreadedProver<H, E>
+where
+    H: Hal<Field = BabyBear, Elem = Elem, ExtElem = ExtElem>,
+    <<H as Hal>::HashSuite as HashSuite<BabyBear>>::HashFn: ControlId,
+    E: EvalCheck<H>,
+{
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_peak_memory_usage(&self) -> usize {
+        self.local().get_peak_memory_usage()
+    }
+
+    fn prove_session(&self, session: &Session) -> Result<Box<dyn SessionReceipt>> {
+        let receipt = self.prove_session_parallel(session, &ParallelConfig::default())?;
+        Ok(Box::new(receipt))
+    }
+
+    fn prove_segment(&self, segment: &Segment) -> Result<SegmentReceipt> {
+        self.local().prove_segment(segment)
+    }
+}
+
+impl<H, E> ParallelProver for ThreadedProver<H, E>
+where
+    H: Hal<Field = BabyBear, Elem = Elem, ExtElem = ExtElem>,
+    <<H as Hal>::HashSuite as HashSuite<BabyBear>>::HashFn: ControlId,
+    E: EvalCheck<H>,
+{
+    fn prove_session_parallel(
+        &self,
+        session: &Session,
+        config: &ParallelConfig,
+    ) -> Result<SessionFlatReceipt> {
+        let threads = config.threads.max(1);
+        log::info!("prove_session_parallel: {}, threads: {threads}", self.name);
+        if session.segments.is_empty() {
+            bail!("Session has no segments to prove");
+        }
+
+        let (job_tx, job_rx) = mpsc::channel::<(usize, Segment)>();
+        let job_rx = Mutex::new(job_rx);
+        let (done_tx, done_rx) = mpsc::channel();
+        let mut receipts: Vec<Option<SegmentReceipt>> = vec![None; session.segments.len()];
+        let mut image_id = None;
+
+        thread::scope(|scope| -> Result<()> {
+            for _ in 0..threads.min(session.segments.len()) {
+                let (job_rx, done_tx) = (&job_rx, done_tx.clone());
+                scope.spawn(move || {
+                    let prover = self.local();
+                    loop {
+                        let job = job_rx.lock().unwrap().recv();
+                        let Ok((index, segment)) = job else {
+                            break;
+                        };
+                        // A panic would leave the segment in flight forever.
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            prover.prove_segment(&segment)
+                        }))
+                        .unwrap_or_else(|_| Err(anyhow!("Proving segment {index} panicked")));
+                        if done_tx.send((index, segment.po2, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(done_tx);
+            // Owning the sender here closes the queue, and so stops the
+            // threads, however this closure returns.
+            let job_tx = job_tx;
+
+            // Segments are resolved in order on this thread, only once there
+            // is room to prove them.
+            let mut next = None;
+            let mut resolved = 0;
+            let mut in_flight = 0;
+            let mut memory = 0;
+            loop {
+                while in_flight < threads {
+                    if next.is_none() && resolved < session.segments.len() {
+                        let segment = session.segments[resolved].resolve()?;
+                        if resolved == 0 {
+                            image_id = Some(segment.pre_image.compute_id());
+                        }
+                        next = Some((resolved, segment));
+                        resolved += 1;
+                    }
+                    let Some((_, segment)) = &next else {
+                        break;
+                    };
+                    let cost = segment_memory(segment.po2);
+                    let over = config.max_memory.map_or(false, |max| memory + cost > max);
+                    if in_flight > 0 && over {
+                        break;
+                    }
+                    job_tx
+                        .send(next.take().unwrap())
+                        .map_err(|_| anyhow!("Prover threads exited early"))?;
+                    memory += cost;
+                    in_flight += 1;
+                }
+                if in_flight == 0 {
+                    return Ok(());
+                }
+                let (index, po2, result) = done_rx.recv()?;
+                in_flight -= 1;
+                memory -= segment_memory(po2);
+                receipts[index] = Some(result?);
+            }
+        })?;
+
+        let segments = receipts
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow!("A segment was not proven"))?;
+        let receipt = SessionFlatReceipt {
+            segments,
+            journal: session.journal.clone(),
+        };
+        self.verify_session(&receipt, image_id.unwrap())?;
+        Ok(receipt)
+    }
+
+    fn verify_session(&self, receipt: &SessionFlatReceipt, image_id: Digest) -> Result<()> {
+        let hal = CpuVerifyHal::<_, H::HashSuite, _>::new(&crate::CIRCUIT);
+        receipt.verify_with_hal(&hal, image_id)?;
+        Ok(())
+    }
+
+    fn verify_segment(&self, receipt: &SegmentReceipt) -> Result<()> {
+        let hal = CpuVerifyHal::<_, H::HashSuite, _>::new(&crate::CIRCUIT);
+        receipt.verify_with_hal(&hal)?;
+        Ok(())
+    }
+}
+
+fn parallel_provers() -> HashMap<String, Arc<dyn ParallelProver>> {
+    let mut table: HashMap<String, Arc<dyn ParallelProver>> = HashMap::new();
+    let prover = Arc::new(ThreadedProver::new("cpu", cpu::sha256_hal_eval));
+    table.insert("cpu".to_string(), prover.clone());
+    table.insert("$default".to_string(), prover);
+
+    let prover = Arc::new(ThreadedProver::new("cpu:poseidon", cpu::poseidon_hal_eval));
+    table.insert("cpu:poseidon".to_string(), prover.clone());
+    table.insert("$poseidon".to_string(), prover);
+    table
+}
+
+/// Return a default [ParallelProver] based on environment variables, falling
+/// back to the SHA-256 CPU prover.
+///
+/// Only the CPU provers are available here, since a GPU is best kept busy by a
+/// single thread.
+pub fn default_parallel_prover() -> Arc<dyn ParallelProver> {
+    let provers = parallel_provers();
+    if let Ok(requested) = std::env::var("RISC0_PROVER") {
+        if let Some(prover) = provers.get(&requested) {
+            return prover.clone();
+        }
+    }
+    provers.get("$default").unwrap().clone()
+}
+
+/// Return a [ParallelProver] registered with the specified `name`.
+pub fn get_parallel_prover(name: &str) -> Arc<dyn ParallelProver> {
+    parallel_provers().get(name).unwrap().clone()
+}
+
+impl Session {
+    /// Prove the segments concurrently with the [default_parallel_prover].
+    pub fn prove_parallel(&self, config: &ParallelConfig) -> Result<SessionFlatReceipt> {
+        default_parallel_prover().prove_session_parallel(self, config)
+    }
+}
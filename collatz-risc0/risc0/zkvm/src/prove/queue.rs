@@ -0,0 +1,159 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proving segments while the executor is still producing them.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Result};
+use risc0_zkp::core::digest::Digest;
+
+use super::ParallelProver;
+use crate::{Segment, SegmentReceipt, Session, SessionFlatReceipt};
+
+type Proven = (u32, Result<SegmentReceipt>);
+
+/// A bounded queue of segments proven on background threads.
+///
+/// Segments are pushed from the callback of
+/// [crate::Executor::run_with_callback] as soon as they are split off, so
+/// proving overlaps execution. Once `capacity` segments are waiting,
+/// [ProvingQueue::push] blocks, which pauses the executor until a prover thread
+/// is free.
+///
+/// ```rust
+/// use risc0_zkvm::{
+///     prove::{default_parallel_prover, ProvingQueue},
+///     Executor, ExecutorEnv, SimpleSegmentRef,
+/// };
+/// use risc0_zkvm_methods::FIB_ELF;
+///
+/// # #[cfg(not(feature = "cuda"))]
+/// # {
+/// let env = ExecutorEnv::builder().add_input(&[20]).build().unwrap();
+/// let mut exec = Executor::from_elf(env, FIB_ELF).unwrap();
+/// let mut queue = ProvingQueue::new(default_parallel_prover(), 2, 2);
+/// let session = exec
+///     .run_with_callback(|segment| {
+///         let segment_ref = SimpleSegmentRef::new(segment.clone());
+///         queue.push(segment)?;
+///         Ok(Box::new(segment_ref))
+///     })
+///     .unwrap();
+/// let receipt = queue.finish(&session).unwrap();
+/// # }
+/// ```
+pub struct ProvingQueue {
+    prover: Arc<dyn ParallelProver>,
+    jobs: Option<mpsc::SyncSender<Segment>>,
+    results: mpsc::Receiver<Proven>,
+    receipts: Vec<SegmentReceipt>,
+    pushed: usize,
+    image_id: Option<Digest>,
+}
+
+impl ProvingQueue {
+    /// Start `threads` threads proving segments with `prover`, with room for
+    /// `capacity` segments waiting to be proven.
+    pub fn new(prover: Arc<dyn ParallelProver>, threads: usize, capacity: usize) -> Self {
+        let (job_tx, job_rx) = mpsc::sync_channel::<Segment>(capacity);
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (done_tx, done_rx) = mpsc::channel();
+        for _ in 0..threads.max(1) {
+            let (prover, job_rx, done_tx) = (prover.clone(), job_rx.clone(), done_tx.clone());
+            thread::spawn(move || loop {
+                let job = job_rx.lock().unwrap().recv();
+                let Ok(segment) = job else {
+                    break;
+                };
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| prover.prove_segment(&segment)))
+                        .unwrap_or_else(|_| {
+                            Err(anyhow!("Proving segment {} panicked", segment.index))
+                        });
+                if done_tx.send((segment.index, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        Self {
+            prover,
+            jobs: Some(job_tx),
+            results: done_rx,
+            receipts: Vec::new(),
+            pushed: 0,
+            image_id: None,
+        }
+    }
+
+    /// Queue `segment` to be proven, blocking while the queue is full.
+    ///
+    /// Fails if a segment pushed earlier has failed to prove, so that the
+    /// execution can be stopped early.
+    pub fn push(&mut self, segment: Segment) -> Result<()> {
+        while let Ok(proven) = self.results.try_recv() {
+            self.collect(proven)?;
+        }
+        if self.pushed == 0 {
+            self.image_id = Some(segment.pre_image.compute_id());
+        }
+        let jobs = self.jobs.as_ref().unwrap();
+        jobs.send(segment)
+            .map_err(|_| anyhow!("Prover threads exited early"))?;
+        self.pushed += 1;
+        Ok(())
+    }
+
+    /// Wait for every queued segment to be proven and collect the receipts
+    /// for `session`, the result of the execution the segments came from.
+    pub fn finish(mut self, session: &Session) -> Result<SessionFlatReceipt> {
+        // Closing the queue stops the threads once it is empty.
+        self.jobs = None;
+        while self.receipts.len() < self.pushed {
+            let proven = self
+                .results
+                .recv()
+                .map_err(|_| anyhow!("Prover threads exited early"))?;
+            self.collect(proven)?;
+        }
+        let Some(image_id) = self.image_id else {
+            bail!("No segments were pushed to prove");
+        };
+        if self.pushed != session.segments.len() {
+            bail!(
+                "Proved {} segments of a session with {}",
+                self.pushed,
+                session.segments.len()
+            );
+        }
+
+        self.receipts.sort_by_key(|receipt| receipt.index);
+        let receipt = SessionFlatReceipt {
+            segments: self.receipts,
+            journal: session.journal.clone(),
+        };
+        self.prover.verify_session(&receipt, image_id)?;
+        Ok(receipt)
+    }
+
+    fn collect(&mut self, (index, result): Proven) -> Result<()> {
+        let receipt = result.map_err(|err| err.context(format!("Proving segment {index}")))?;
+        self.receipts.push(receipt);
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Proving segments in other processes through a shared directory.
+//!
+//! A [Spool] is a directory with three subdirectories:
+//!
+//! * `segments/` holds each published [Segment], named by its index.
+//! * `claimed/` holds a file for each segment a worker is proving. The worker
+//!   creates it to claim the segment and rewrites it while proving, so a claim
+//!   that has not been touched for a while belongs to a dead worker.
+//! * `done/` holds the [SegmentReceipt] for each proven segment.
+//!
+//! Every segment and receipt file starts with the SHA-256 of the rest of the
+//! file, so that a truncated or corrupted file is rejected rather than
+//! proven or trusted. The coordinator verifies each receipt against its
+//! segment, and removes stale claims and bad receipts, which lets another
+//! worker retry the segment, up to a limit.
+//!
+//! A spool holds one session at a time: publishing segment 0 starts a new
+//! session and clears whatever an earlier one left behind. A receipt that a
+//! worker of the earlier session writes late does not match its segment, so
+//! it is retried like any other bad receipt.
+//!
+//! The `segment-worker` binary of `risc0-r0vm` runs a [SpoolWorker].
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::ParallelProver;
+use crate::{
+    receipt::compute_image_id,
+    sha::{Digest, Impl, Sha256, DIGEST_BYTES},
+    Segment, SegmentReceipt, SegmentRef, Session, SessionFlatReceipt,
+};
+
+const SEGMENTS: &str = "segments";
+const CLAIMED: &str = "claimed";
+const DONE: &str = "done";
+
+/// How a coordinator waits on the workers of a [Spool].
+#[derive(Clone, Debug)]
+pub struct SpoolConfig {
+    /// A claim that has not been touched for this long belongs to a dead
+    /// worker. Should be several times the [SpoolWorker] heartbeat.
+    pub lease_timeout: Duration,
+
+    /// How many times a segment may be handed out before giving up on it.
+    pub max_attempts: usize,
+
+    /// How long to wait between scans of the spool.
+    pub poll_interval: Duration,
+
+    /// How long to wait for the whole session before giving up, so that a
+    /// spool without live workers does not hang the coordinator.
+    pub timeout: Duration,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            lease_timeout: Duration::from_secs(60),
+            max_attempts: 3,
+            poll_interval: Duration::from_millis(500),
+            timeout: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// A directory through which segments are handed to [SpoolWorker]s.
+#[derive(Clone, Debug)]
+pub struct Spool {
+    root: PathBuf,
+}
+
+/// A [SegmentRef] to a segment published to a [Spool].
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SpoolSegmentRef {
+    path: PathBuf,
+}
+
+#[typetag::serde]
+impl SegmentRef for SpoolSegmentRef {
+    fn resolve(&self) -> Result<Segment> {
+        Ok(bincode::deserialize(&read_checked(&self.path)?)?)
+    }
+}
+
+impl Spool {
+    /// Open the spool at `root`, creating it if it does not exist.
+    pub fn open(root: &Path) -> Result<Self> {
+        for dir in [SEGMENTS, CLAIMED, DONE] {
+            fs::create_dir_all(root.join(dir))
+                .with_context(|| format!("Failed to create {}", root.join(dir).display()))?;
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn path(&self, dir: &str, index: u32) -> PathBuf {
+        self.root.join(dir).join(format!("{index:08}"))
+    }
+
+    /// Publish `segment` for workers to prove. Segment 0 starts a new session,
+    /// so publishing it first clears the spool.
+    pub fn publish(&self, segment: &Segment) -> Result<SpoolSegmentRef> {
+        if segment.index == 0 {
+            self.clear()?;
+        }
+        let path = self.path(SEGMENTS, segment.index);
+        write_checked(&path, &bincode::serialize(segment)?)?;
+        Ok(SpoolSegmentRef { path })
+    }
+
+    /// Remove every segment, claim and receipt from the spool.
+    fn clear(&self) -> Result<()> {
+        for dir in [SEGMENTS, CLAIMED, DONE] {
+            for entry in fs::read_dir(self.root.join(dir))? {
+                match fs::remove_file(entry?.path()) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The indices of the published segments that have not been proven yet,
+    /// in increasing order.
+    fn unproven(&self) -> Result<Vec<u32>> {
+        let mut indices = Vec::new();
+        for entry in fs::read_dir(self.root.join(SEGMENTS))? {
+            let name = entry?.file_name();
+            let Some(index) = name.to_str().and_then(|name| name.parse().ok()) else {
+                continue;
+            };
+            if !self.path(DONE, index).exists() {
+                indices.push(index);
+            }
+        }
+        indices.sort();
+        Ok(indices)
+    }
+
+    /// Wait for workers to prove every segment of `session`, published to
+    /// this spool, and collect the receipts in segment order. Each receipt,
+    /// and then the result, is verified with `prover`, which must use the same
+    /// hash function as the workers.
+    pub fn collect(
+        &self,
+        session: &Session,
+        prover: &dyn ParallelProver,
+        config: &SpoolConfig,
+    ) -> Result<SessionFlatReceipt> {
+        let count = session.segments.len();
+        if count == 0 {
+            bail!("Session has no segments to prove");
+        }
+        let mut receipts: Vec<Option<SegmentReceipt>> = vec![None; count];
+        let mut attempts = vec![1; count];
+        let mut retry = |index: usize, reason: String| -> Result<()> {
+            log::warn!("Retrying segment {index}: {reason}");
+            let _ = fs::remove_file(self.path(DONE, index as u32));
+            let _ = fs::remove_file(self.path(CLAIMED, index as u32));
+            attempts[index] += 1;
+            if attempts[index] > config.max_attempts {
+                bail!(
+                    "Segment {index} failed {} times: {reason}",
+                    config.max_attempts
+                );
+            }
+            Ok(())
+        };
+
+        let started = Instant::now();
+        loop {
+            for index in 0..count {
+                if receipts[index].is_some() {
+                    continue;
+                }
+                let done = self.path(DONE, index as u32);
+                if done.exists() {
+                    let receipt = read_receipt(&done, index as u32).and_then(|receipt| {
+                        check_receipt(&receipt, session.segments[index].as_ref(), prover)?;
+                        Ok(receipt)
+                    });
+                    match receipt {
+                        Ok(receipt) => {
+                            receipts[index] = Some(receipt);
+                            let _ = fs::remove_file(self.path(CLAIMED, index as u32));
+                        }
+                        Err(err) => retry(index, format!("{err:#}"))?,
+                    }
+                    continue;
+                }
+                let claimed = self.path(CLAIMED, index as u32);
+                if let Ok(modified) = fs::metadata(&claimed).and_then(|meta| meta.modified()) {
+                    let idle = SystemTime::now()
+                        .duration_since(modified)
+                        .unwrap_or_default();
+                    if idle > config.lease_timeout {
+                        retry(index, format!("claim idle for {idle:?}"))?;
+                    }
+                }
+            }
+            if receipts.iter().all(Option::is_some) {
+                break;
+            }
+            if started.elapsed() > config.timeout {
+                let proven = receipts.iter().filter(|receipt| receipt.is_some()).count();
+                bail!(
+                    "Timed out after {:?} with {proven} of {count} segments proven",
+                    config.timeout
+                );
+            }
+            thread::sleep(config.poll_interval);
+        }
+
+        let image_id = session.segments[0].resolve()?.pre_image.compute_id();
+        let receipt = SessionFlatReceipt {
+            segments: receipts.into_iter().flatten().collect(),
+            journal: session.journal.clone(),
+        };
+        prover.verify_session(&receipt, image_id)?;
+        Ok(receipt)
+    }
+}
+
+/// A process that proves the segments published to a [Spool].
+pub struct SpoolWorker {
+    spool: Spool,
+    name: String,
+    prover: Arc<dyn ParallelProver>,
+    heartbeat: Duration,
+}
+
+impl SpoolWorker {
+    /// Construct a [SpoolWorker] named `name` that proves with `prover` and
+    /// touches its claim every `heartbeat`.
+    pub fn new(
+        spool: Spool,
+        name: &str,
+        prover: Arc<dyn ParallelProver>,
+        heartbeat: Duration,
+    ) -> Self {
+        Self {
+            spool,
+            name: name.to_string(),
+            prover,
+            heartbeat,
+        }
+    }
+
+    /// Claim and prove the first unclaimed segment, returning its index, or
+    /// [None] if every segment is proven or claimed.
+    pub fn work_once(&self) -> Result<Option<u32>> {
+        for index in self.spool.unproven()? {
+            let claimed = self.spool.path(CLAIMED, index);
+            let mut claim = match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&claimed)
+            {
+                Ok(claim) => claim,
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err.into()),
+            };
+            claim.write_all(self.name.as_bytes())?;
+            drop(claim);
+
+            log::info!("{}: proving segment {index}", self.name);
+            let receipt = self.prove(index, &claimed)?;
+            let done = self.spool.path(DONE, index);
+            write_checked(&done, &bincode::serialize(&receipt)?)?;
+            let _ = fs::remove_file(&claimed);
+            return Ok(Some(index));
+        }
+        Ok(None)
+    }
+
+    fn prove(&self, index: u32, claimed: &Path) -> Result<SegmentReceipt> {
+        let path = self.spool.path(SEGMENTS, index);
+        let segment: Segment = bincode::deserialize(&read_checked(&path)?)?;
+        thread::scope(|scope| {
+            // Dropping the sender stops the heartbeat.
+            let (_stop, stopped) = mpsc::channel::<()>();
+            scope.spawn(move || {
+                while stopped.recv_timeout(self.heartbeat) == Err(mpsc::RecvTimeoutError::Timeout) {
+                    // Only touch the claim; if it was taken away, a late
+                    // receipt is still accepted.
+                    if let Ok(mut claim) = OpenOptions::new().write(true).open(claimed) {
+                        let _ = claim.write_all(self.name.as_bytes());
+                    }
+                }
+            });
+            self.prover.prove_segment(&segment)
+        })
+    }
+}
+
+fn read_receipt(path: &Path, index: u32) -> Result<SegmentReceipt> {
+    let receipt: SegmentReceipt = bincode::deserialize(&read_checked(path)?)?;
+    if receipt.index != index {
+        bail!("Receipt for segment {} in place of {index}", receipt.index);
+    }
+    Ok(receipt)
+}
+
+/// Check that `receipt` is a valid proof of `segment`, rather than of a
+/// segment of another session.
+fn check_receipt(
+    receipt: &SegmentReceipt,
+    segment: &dyn SegmentRef,
+    prover: &dyn ParallelProver,
+) -> Result<()> {
+    prover.verify_segment(receipt)?;
+    let pre = receipt.get_metadata()?.pre;
+    let image_id = segment.resolve()?.pre_image.compute_id();
+    if compute_image_id(&pre.merkle_root, pre.pc) != image_id {
+        bail!("Receipt does not start from the segment's image");
+    }
+    Ok(())
+}
+
+/// Write `contents` behind its SHA-256, via a temporary file so that readers
+/// never see part of it. The temporary name is unique to the call, so that
+/// workers in one process proving the same segment do not write over each
+/// other.
+fn write_checked(path: &Path, contents: &[u8]) -> Result<()> {
+    static TMP_COUNT: AtomicU64 = AtomicU64::new(0);
+    let count = TMP_COUNT.fetch_add(1, Ordering::Relaxed);
+    let tmp = path.with_extension(format!("{}.{count}.tmp", std::process::id()));
+    let mut file = File::create(&tmp)?;
+    file.write_all(Impl::hash_bytes(contents).as_bytes())?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read_checked(path: &Path) -> Result<Vec<u8>> {
+    let mut contents =
+        fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if contents.len() < DIGEST_BYTES {
+        bail!("{} is truncated", path.display());
+    }
+    let checksum = Digest::try_from(&contents[..DIGEST_BYTES])?;
+    contents.drain(..DIGEST_BYTES);
+    if *Impl::hash_bytes(&contents) != checksum {
+        bail!("{} does not match its checksum", path.display());
+    }
+    Ok(contents)
+}
@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::rc::Rc;
+use std::{
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use anyhow::Result;
 use risc0_circuit_rv32im::cpu::CpuEvalCheck;
@@ -27,12 +30,16 @@ use risc0_zkvm_platform::{memory, WORD_SIZE};
 use serial_test::serial;
 use test_log::test;
 
-use super::{get_prover, LocalProver, Prover};
+use super::{
+    default_parallel_prover, get_prover, segment_memory, LocalProver, ParallelConfig, Prover,
+    ProvingQueue, Spool, SpoolConfig, SpoolWorker,
+};
 use crate::{
     prove::HalEval,
     receipt::SessionReceipt,
     serde::{from_slice, to_vec},
-    testutils, Executor, ExecutorEnv, ExitCode, SessionFlatReceipt, CIRCUIT,
+    testutils, CancelToken, Executor, ExecutorEnv, ExitCode, SessionFlatReceipt, SimpleSegmentRef,
+    CIRCUIT,
 };
 
 fn prove_nothing(name: &str) -> Result<Box<dyn SessionReceipt>> {
@@ -259,6 +266,161 @@ fn continuation() {
     }
 }
 
+#[test]
+fn prove_parallel() {
+    let segment_limit_po2 = 16; // 64k cycles
+    let env = testutils::busy_loop_env(3, segment_limit_po2);
+    let mut exec = Executor::from_elf(env, MULTI_TEST_ELF).unwrap();
+    let session = exec.run().unwrap();
+    assert!(session.segments.len() > 2);
+
+    let configs = [
+        ParallelConfig {
+            threads: 4,
+            max_memory: None,
+        },
+        // Too little memory for two segments at once, so one at a time.
+        ParallelConfig {
+            threads: 4,
+            max_memory: Some(segment_memory(segment_limit_po2)),
+        },
+    ];
+    for config in configs {
+        let receipt = session.prove_parallel(&config).unwrap();
+        assert_eq!(receipt.segments.len(), session.segments.len());
+        for (idx, receipt) in receipt.segments.iter().enumerate() {
+            assert_eq!(receipt.index, idx as u32);
+        }
+        assert_eq!(receipt.journal, session.journal);
+        receipt.verify(MULTI_TEST_ID.into()).unwrap();
+    }
+}
+
+#[test]
+fn prove_while_executing() {
+    let segment_limit_po2 = 16; // 64k cycles
+    let env = testutils::busy_loop_env(3, segment_limit_po2);
+    let mut exec = Executor::from_elf(env, MULTI_TEST_ELF).unwrap();
+
+    // A queue of one makes the executor wait for the provers.
+    let mut queue = ProvingQueue::new(default_parallel_prover(), 2, 1);
+    let session = exec
+        .run_with_callback(|segment| {
+            let segment_ref = SimpleSegmentRef::new(segment.clone());
+            queue.push(segment)?;
+            Ok(Box::new(segment_ref))
+        })
+        .unwrap();
+    assert!(session.segments.len() > 2);
+
+    let receipt = queue.finish(&session).unwrap();
+    assert_eq!(receipt.segments.len(), session.segments.len());
+    for (idx, receipt) in receipt.segments.iter().enumerate() {
+        assert_eq!(receipt.index, idx as u32);
+    }
+    assert_eq!(receipt.journal, session.journal);
+    receipt.verify(MULTI_TEST_ID.into()).unwrap();
+}
+
+#[test]
+fn prove_resumed_after_cancel() {
+    let segment_limit_po2 = 16; // 64k cycles
+    let env = testutils::busy_loop_env(2, segment_limit_po2);
+    let mut exec = Executor::from_elf(env, MULTI_TEST_ELF).unwrap();
+
+    // Cancel halfway into the first segment, which ends it early.
+    let cancel = CancelToken::new();
+    let mut session = exec
+        .run_with_control(
+            |segment| Ok(Box::new(SimpleSegmentRef::new(segment))),
+            &cancel,
+            |progress| {
+                if progress.cycles > 1 << (segment_limit_po2 - 1) {
+                    cancel.cancel();
+                }
+            },
+        )
+        .unwrap();
+    assert_eq!(session.exit_code, ExitCode::SystemSplit);
+    assert_eq!(session.segments.len(), 1);
+
+    session.append(exec.run().unwrap());
+    let receipt = session.prove().unwrap();
+    receipt.verify(MULTI_TEST_ID.into()).unwrap();
+}
+
+#[test]
+fn prove_through_spool() {
+    let segment_limit_po2 = 16; // 64k cycles
+    let env = testutils::busy_loop_env(2, segment_limit_po2);
+    let mut exec = Executor::from_elf(env, MULTI_TEST_ELF).unwrap();
+
+    let root = std::env::temp_dir().join(format!("risc0-spool-{}", std::process::id()));
+    let spool = Spool::open(&root).unwrap();
+    // Leftovers from an earlier session are cleared when segment 0 is
+    // published.
+    std::fs::write(root.join("segments/00000099"), b"stale").unwrap();
+    std::fs::write(root.join("claimed/00000000"), b"stale").unwrap();
+    let session = exec
+        .run_with_callback(|segment| Ok(Box::new(spool.publish(&segment)?)))
+        .unwrap();
+    assert!(session.segments.len() > 1);
+    assert_eq!(session.segments[1].resolve().unwrap().index, 1);
+    assert!(!root.join("segments/00000099").exists());
+    assert!(!root.join("claimed/00000000").exists());
+
+    // A corrupt receipt is thrown away and the segment proven again.
+    std::fs::write(root.join("done/00000000"), b"not a receipt").unwrap();
+
+    let config = SpoolConfig {
+        poll_interval: std::time::Duration::from_millis(10),
+        timeout: std::time::Duration::from_secs(600),
+        ..Default::default()
+    };
+    let prover = default_parallel_prover();
+    let worker = SpoolWorker::new(
+        spool.clone(),
+        "worker",
+        prover.clone(),
+        std::time::Duration::from_secs(1),
+    );
+    let collect = || {
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    if worker.work_once().unwrap().is_none() {
+                        std::thread::sleep(config.poll_interval);
+                    }
+                }
+            });
+            let receipt = spool.collect(&session, prover.as_ref(), &config);
+            stop.store(true, Ordering::Relaxed);
+            receipt
+        })
+        .unwrap()
+    };
+    let receipt = collect();
+
+    assert_eq!(receipt.segments.len(), session.segments.len());
+    for (idx, receipt) in receipt.segments.iter().enumerate() {
+        assert_eq!(receipt.index, idx as u32);
+    }
+    receipt.verify(MULTI_TEST_ID.into()).unwrap();
+
+    // A valid receipt for another segment is retried too.
+    use crate::sha::{Impl, Sha256};
+    let mut wrong = receipt.segments[1].clone();
+    wrong.index = 0;
+    let contents = bincode::serialize(&wrong).unwrap();
+    let mut file = Impl::hash_bytes(&contents).as_bytes().to_vec();
+    file.extend(contents);
+    std::fs::write(root.join("done/00000000"), file).unwrap();
+    let receipt = collect();
+    std::fs::remove_dir_all(&root).unwrap();
+    receipt.verify(MULTI_TEST_ID.into()).unwrap();
+}
+
 // These tests come from:
 // https://github.com/riscv-software-src/riscv-tests
 // They were built using the toolchain from:
@@ -110,6 +110,16 @@ impl Session {
             .map(|segment_ref| segment_ref.resolve())
             .collect()
     }
+
+    /// Join `rest`, the [Session] of the run that resumed this cancelled one,
+    /// onto the end of this one. Its journal already includes this one's.
+    ///
+    /// See [crate::Executor::run_with_control].
+    pub fn append(&mut self, rest: Session) {
+        self.segments.extend(rest.segments);
+        self.journal = rest.journal;
+        self.exit_code = rest.exit_code;
+    }
 }
 
 impl Segment {
@@ -172,6 +182,9 @@ impl SimpleSegmentRef {
 ///
 /// There is an example of using [FileSegmentRef] in [our EVM example]
 /// (https://github.com/risc0/risc0/blob/main/examples/zkevm-demo/src/main.rs).
+///
+/// Each file holds the whole pre-image of its segment. For long sessions,
+/// a [crate::SegmentStore] keeps the pages that segments share only once.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct FileSegmentRef {
     path: PathBuf,
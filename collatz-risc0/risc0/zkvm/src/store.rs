@@ -0,0 +1,364 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storing segments on disk without repeating the memory they share.
+//!
+//! Most of the pre-image of a [Segment] is unchanged from the segment before
+//! it, and often from other sessions of the same guest. A [SegmentStore]
+//! keeps each page once, compressed and named by its page digest, and each
+//! segment as a compressed manifest of page digests plus everything else in
+//! the [Segment], named by its SHA-256:
+//!
+//! ```text
+//! pages/ab/ab12...   one memory page
+//! segments/5e0f...   one segment manifest
+//! ```
+//!
+//! Files are never rewritten, so a store can be shared by any number of
+//! sessions. Nothing is removed from it except by [SegmentStore::gc].
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    binfmt::image::{hash_page_bytes, PageTableInfo},
+    exec::SyscallRecord,
+    receipt::ExitCode,
+    session::PageFaults,
+    sha::{Digest, Impl, Sha256},
+    MemoryImage, Segment, SegmentRef,
+};
+
+const PAGES: &str = "pages";
+const SEGMENTS: &str = "segments";
+
+/// The zstd level files are written with. Memory pages compress well even at
+/// low levels, which keep storing fast enough to run alongside the executor.
+const LEVEL: i32 = 3;
+
+/// A [Segment] with its pre-image reduced to the digests of its pages.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    pages: BTreeMap<u32, Digest>,
+    info: PageTableInfo,
+    pc: u32,
+
+    /// The Merkle root of the pre-image, checked once it is reassembled.
+    root: Digest,
+
+    post_image_id: Digest,
+    faults: PageFaults,
+    syscalls: Vec<SyscallRecord>,
+    split_insn: Option<u32>,
+    exit_code: ExitCode,
+    po2: usize,
+    index: u32,
+    insn_cycles: usize,
+}
+
+/// A directory of segments that share their memory pages.
+#[derive(Clone, Debug)]
+pub struct SegmentStore {
+    root: PathBuf,
+}
+
+/// A [SegmentRef] to a segment in a [SegmentStore], named by the digest of
+/// its manifest.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredSegmentRef {
+    root: PathBuf,
+    digest: Digest,
+}
+
+impl StoredSegmentRef {
+    /// The digest of the segment's manifest.
+    pub fn digest(&self) -> Digest {
+        self.digest
+    }
+}
+
+#[typetag::serde]
+impl SegmentRef for StoredSegmentRef {
+    fn resolve(&self) -> Result<Segment> {
+        let store = SegmentStore {
+            root: self.root.clone(),
+        };
+        store.load(&self.digest)
+    }
+}
+
+impl SegmentStore {
+    /// Open the store at `root`, creating it if it does not exist.
+    pub fn open(root: &Path) -> Result<Self> {
+        for dir in [PAGES, SEGMENTS] {
+            fs::create_dir_all(root.join(dir))
+                .with_context(|| format!("Failed to create {}", root.join(dir).display()))?;
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn page_path(&self, digest: &Digest) -> PathBuf {
+        let name = digest.to_string();
+        self.root.join(PAGES).join(&name[..2]).join(name)
+    }
+
+    fn segment_path(&self, digest: &Digest) -> PathBuf {
+        self.root.join(SEGMENTS).join(digest.to_string())
+    }
+
+    /// Store `segment`, writing only the pages that are not already stored.
+    pub fn store(&self, segment: &Segment) -> Result<StoredSegmentRef> {
+        let image = &segment.pre_image;
+        let mut pages = BTreeMap::new();
+        for (&page_idx, page) in image.pages() {
+            let digest = hash_page_bytes(page);
+            let path = self.page_path(&digest);
+            if !path.exists() {
+                fs::create_dir_all(path.parent().unwrap())?;
+                write_new(&path, &zstd::encode_all(page.as_slice(), LEVEL)?)?;
+            }
+            pages.insert(page_idx, digest);
+        }
+
+        let manifest = Manifest {
+            pages,
+            info: image.info.clone(),
+            pc: image.pc,
+            root: image.compute_root_hash(),
+            post_image_id: segment.post_image_id,
+            faults: segment.faults.clone(),
+            syscalls: segment.syscalls.clone(),
+            split_insn: segment.split_insn,
+            exit_code: segment.exit_code,
+            po2: segment.po2,
+            index: segment.index,
+            insn_cycles: segment.insn_cycles,
+        };
+        let contents = bincode::serialize(&manifest)?;
+        let digest = *Impl::hash_bytes(&contents);
+        let path = self.segment_path(&digest);
+        if !path.exists() {
+            write_new(&path, &zstd::encode_all(contents.as_slice(), LEVEL)?)?;
+        }
+        Ok(StoredSegmentRef {
+            root: self.root.clone(),
+            digest,
+        })
+    }
+
+    fn load_manifest(&self, digest: &Digest) -> Result<Manifest> {
+        let contents = read_compressed(&self.segment_path(digest))?;
+        if *Impl::hash_bytes(&contents) != *digest {
+            bail!("Segment {digest} does not match its digest");
+        }
+        Ok(bincode::deserialize(&contents)?)
+    }
+
+    /// Load the segment whose manifest has `digest`, checking every page
+    /// against its digest and the reassembled pre-image against its root.
+    pub fn load(&self, digest: &Digest) -> Result<Segment> {
+        let manifest = self.load_manifest(digest)?;
+
+        let mut pages = BTreeMap::new();
+        for (page_idx, digest) in manifest.pages {
+            let page = read_compressed(&self.page_path(&digest))?;
+            if page.len() != manifest.info.page_size as usize || hash_page_bytes(&page) != digest {
+                bail!("Page {digest} does not match its digest");
+            }
+            pages.insert(page_idx, page);
+        }
+        if !pages.contains_key(&manifest.info.root_idx) {
+            bail!("Segment {digest} has no root page");
+        }
+
+        let pre_image = MemoryImage::from_pages(pages, manifest.info, manifest.pc);
+        let root = pre_image.compute_root_hash();
+        if root != manifest.root {
+            bail!(
+                "Reassembled pre-image root {root} does not match the recorded {}",
+                manifest.root
+            );
+        }
+        Ok(Segment::new(
+            pre_image,
+            manifest.post_image_id,
+            manifest.faults,
+            manifest.syscalls,
+            manifest.exit_code,
+            manifest.split_insn,
+            manifest.po2,
+            manifest.index,
+            manifest.insn_cycles,
+        ))
+    }
+
+    /// Remove every segment but those whose manifest digest is in `keep`,
+    /// and every page that none of those refer to.
+    ///
+    /// Nothing may be stored meanwhile, since the pages of a segment being
+    /// stored are not referred to until its manifest is written.
+    pub fn gc(&self, keep: &[Digest]) -> Result<()> {
+        let mut pages = BTreeSet::new();
+        for digest in keep {
+            let manifest = self.load_manifest(digest)?;
+            pages.extend(
+                manifest
+                    .pages
+                    .into_values()
+                    .map(|digest| digest.to_string()),
+            );
+        }
+        let keep: BTreeSet<_> = keep.iter().map(|digest| digest.to_string()).collect();
+
+        for entry in fs::read_dir(self.root.join(SEGMENTS))? {
+            let entry = entry?;
+            if !keep.contains(entry.file_name().to_string_lossy().as_ref()) {
+                remove(&entry.path())?;
+            }
+        }
+        for dir in fs::read_dir(self.root.join(PAGES))? {
+            let dir = dir?.path();
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if !pages.contains(entry.file_name().to_string_lossy().as_ref()) {
+                    remove(&entry.path())?;
+                }
+            }
+            // Only succeeds once the directory is empty.
+            let _ = fs::remove_dir(&dir);
+        }
+        Ok(())
+    }
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("Failed to remove {}", path.display())),
+    }
+}
+
+/// Write `contents` to `path` via a temporary file, so that a file in the
+/// store is either whole or absent. The temporary name is unique to the call,
+/// so that threads storing the same page do not write over each other.
+fn write_new(path: &Path, contents: &[u8]) -> Result<()> {
+    static TMP_COUNT: AtomicU64 = AtomicU64::new(0);
+    let count = TMP_COUNT.fetch_add(1, Ordering::Relaxed);
+    let tmp = path.with_extension(format!("{}.{count}.tmp", std::process::id()));
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read_compressed(path: &Path) -> Result<Vec<u8>> {
+    let contents = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    zstd::decode_all(contents.as_slice())
+        .with_context(|| format!("Failed to decompress {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use risc0_zkvm_methods::MULTI_TEST_ELF;
+
+    use super::*;
+    use crate::{testutils::busy_loop_env, Executor};
+
+    fn count_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    count_files(&path)
+                } else {
+                    1
+                }
+            })
+            .sum()
+    }
+
+    #[test]
+    fn store_and_resolve() {
+        let segment_limit_po2 = 16; // 64k cycles
+        let env = busy_loop_env(2, segment_limit_po2);
+        let mut exec = Executor::from_elf(env, MULTI_TEST_ELF).unwrap();
+        let session = exec.run().unwrap();
+        let segments = session.resolve().unwrap();
+        assert!(segments.len() > 1);
+
+        let root = std::env::temp_dir().join(format!("risc0-store-{}", std::process::id()));
+        let store = SegmentStore::open(&root).unwrap();
+        let refs: Vec<_> = segments
+            .iter()
+            .map(|segment| store.store(segment).unwrap())
+            .collect();
+
+        // Storing a segment again writes nothing new.
+        let files = count_files(&root);
+        let again = store.store(&segments[0]).unwrap();
+        assert_eq!(again.digest, refs[0].digest);
+        assert_eq!(count_files(&root), files);
+
+        // Pages shared by the segments are stored once.
+        let pages: usize = segments
+            .iter()
+            .map(|segment| segment.pre_image.pages().len())
+            .sum();
+        assert!(count_files(&root.join(PAGES)) < pages);
+
+        for (segment, segment_ref) in segments.iter().zip(&refs) {
+            let resolved = segment_ref.resolve().unwrap();
+            assert_eq!(
+                bincode::serialize(&resolved).unwrap(),
+                bincode::serialize(segment).unwrap()
+            );
+        }
+
+        // Collecting all but the last segment keeps only what it refers to.
+        let last = refs.last().unwrap();
+        store.gc(&[last.digest()]).unwrap();
+        assert!(refs[0].resolve().is_err());
+        let kept = last.resolve().unwrap();
+        let distinct: BTreeSet<_> = kept
+            .pre_image
+            .pages()
+            .values()
+            .map(|page| hash_page_bytes(page).to_string())
+            .collect();
+        assert_eq!(count_files(&root.join(PAGES)), distinct.len());
+
+        // A corrupted page is caught on resolve.
+        let (_, page) = kept.pre_image.pages().iter().next().unwrap();
+        let path = store.page_path(&hash_page_bytes(page));
+        let mut corrupt = page.clone();
+        corrupt[0] ^= 1;
+        fs::write(&path, zstd::encode_all(corrupt.as_slice(), LEVEL).unwrap()).unwrap();
+        assert!(last.resolve().is_err());
+
+        store.gc(&[]).unwrap();
+        assert_eq!(count_files(&root), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
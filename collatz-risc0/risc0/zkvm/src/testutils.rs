@@ -17,8 +17,26 @@ use core::mem;
 use crypto_bigint::{
     rand_core::CryptoRngCore, CheckedMul, Encoding, NonZero, Random, RandomMod, U256, U512,
 };
+use risc0_zkvm_methods::multi_test::MultiTestSpec;
 use risc0_zkvm_platform::syscall::bigint;
 
+use crate::{serde::to_vec, ExecutorEnv};
+
+/// An environment in which `MULTI_TEST_ELF` busy-loops for `segments` times
+/// `2^segment_limit_po2` cycles, so that it runs a little past that many
+/// segments.
+pub fn busy_loop_env(segments: u32, segment_limit_po2: usize) -> ExecutorEnv<'static> {
+    let spec = to_vec(&MultiTestSpec::BusyLoop {
+        cycles: segments << segment_limit_po2,
+    })
+    .unwrap();
+    ExecutorEnv::builder()
+        .add_input(&spec)
+        .segment_limit_po2(segment_limit_po2)
+        .build()
+        .unwrap()
+}
+
 // Convert to little-endian u32 array. Only reinterprettation on LE machines.
 fn bigint_to_arr(num: &U256) -> [u32; bigint::WIDTH_WORDS] {
     let mut arr: [u32; bigint::WIDTH_WORDS] = bytemuck::cast(num.to_le_bytes());
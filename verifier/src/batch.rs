@@ -0,0 +1,256 @@
+//! Verify many receipts at once, e.g. to re-audit every stored receipt after a
+//! verifier upgrade.
+
+use crate::{check_allowlist, parse_image_id, verify_file, Report};
+use collatz_core::ImageAllowlist;
+use risc0_zkvm::sha::Digest;
+use serde::Deserialize;
+use serde_json::Value as Json;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::{fmt, fs, io, thread};
+
+/// One receipt to verify.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub image_id: Digest,
+    pub receipt: PathBuf,
+    pub input_value: Option<u128>,
+}
+
+/// Why a batch could not be assembled.
+#[derive(Debug)]
+pub enum BatchError {
+    Io(io::Error),
+
+    /// A manifest line is not a valid [ManifestEntry].
+    InvalidManifest { line: usize, message: String },
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatchError::Io(err) => write!(f, "{err}"),
+            BatchError::InvalidManifest { line, message } => {
+                write!(f, "Invalid manifest entry on line {line}: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+impl From<io::Error> for BatchError {
+    fn from(err: io::Error) -> Self {
+        BatchError::Io(err)
+    }
+}
+
+/// A line of a JSON Lines manifest. Relative receipt paths are resolved
+/// against the manifest's directory.
+#[derive(Deserialize)]
+struct ManifestEntry {
+    /// The image ID as an array of eight `u32`s, or that array as a string.
+    image_id: Json,
+    receipt: PathBuf,
+    input_value: Option<u128>,
+}
+
+/// Every `.dat` file in `dir`, verified against `image_id`.
+///
+/// Receipts stored by the API are named `<n>_receipt.dat`; for those `n` is
+/// checked as the claimed input value.
+pub fn jobs_from_dir(dir: &Path, image_id: Digest) -> Result<Vec<Job>, BatchError> {
+    let mut jobs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().is_none_or(|ext| ext != "dat") {
+            continue;
+        }
+        let input_value = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_suffix("_receipt"))
+            .and_then(|n| n.parse().ok());
+        jobs.push(Job {
+            image_id,
+            receipt: path,
+            input_value,
+        });
+    }
+    jobs.sort_by(|a, b| a.receipt.cmp(&b.receipt));
+    Ok(jobs)
+}
+
+/// The receipts listed in a JSON Lines manifest, one [ManifestEntry] per
+/// line. Blank lines are skipped.
+pub fn jobs_from_manifest(manifest: &Path) -> Result<Vec<Job>, BatchError> {
+    let base = manifest.parent().unwrap_or(Path::new(""));
+    let mut jobs = Vec::new();
+    for (i, line) in fs::read_to_string(manifest)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |message: String| BatchError::InvalidManifest {
+            line: i + 1,
+            message,
+        };
+        let entry: ManifestEntry =
+            serde_json::from_str(line).map_err(|err| invalid(err.to_string()))?;
+        let image_id = match &entry.image_id {
+            Json::String(image_id) => parse_image_id(image_id),
+            image_id => parse_image_id(&image_id.to_string()),
+        }
+        .map_err(|failure| invalid(failure.to_string()))?;
+        jobs.push(Job {
+            image_id,
+            receipt: base.join(entry.receipt),
+            input_value: entry.input_value,
+        });
+    }
+    Ok(jobs)
+}
+
+/// Verify `jobs` on `threads` worker threads, calling `on_report` on the
+/// calling thread as each one finishes. Reports arrive in completion order,
+/// not the order of `jobs`. Jobs whose image ID is not on the `allowlist`, if
+/// one is given, are rejected without being verified.
+pub fn run(
+    jobs: &[Job],
+    threads: usize,
+    allowlist: Option<&ImageAllowlist>,
+    mut on_report: impl FnMut(&Job, Report),
+) {
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, jobs.len().max(1)) {
+            let tx = tx.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(job) = jobs.get(i) else {
+                    break;
+                };
+                let report = match check_allowlist(allowlist, job.image_id) {
+                    Ok(()) => verify_file(job.image_id, &job.receipt, job.input_value),
+                    Err(report) => *report,
+                };
+                if tx.send((i, report)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        for (i, report) in rx {
+            on_report(&jobs[i], report);
+        }
+    });
+}
+
+/// Render the result of a [Job] as a single JSON line: the [Report] with the
+/// receipt path and claimed input value added.
+pub fn to_json_line(job: &Job, report: &Report) -> String {
+    let mut json = report.to_json();
+    json["receipt"] = Json::String(job.receipt.display().to_string());
+    json["input_value"] = job
+        .input_value
+        .map_or(Json::Null, |n| Json::String(n.to_string()));
+    json.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("verifier-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn dir_jobs() {
+        let dir = temp_dir("dir");
+        for name in ["6_receipt.dat", "receipt.dat", "27_receipt.dat", "notes.txt"] {
+            fs::write(dir.join(name), []).unwrap();
+        }
+
+        let image_id = Digest::new([1, 2, 3, 4, 5, 6, 7, 8]);
+        let jobs = jobs_from_dir(&dir, image_id).unwrap();
+        let found: Vec<_> = jobs
+            .iter()
+            .map(|job| (job.receipt.file_name().unwrap().to_str().unwrap(), job.input_value))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("27_receipt.dat", Some(27)),
+                ("6_receipt.dat", Some(6)),
+                ("receipt.dat", None),
+            ]
+        );
+
+        let mut lines = Vec::new();
+        run(&jobs, 2, None, |job, report| {
+            assert_eq!(report.exit_code(), 12);
+            lines.push(to_json_line(job, &report));
+        });
+        assert_eq!(lines.len(), 3);
+        for line in lines {
+            let json: Json = serde_json::from_str(&line).unwrap();
+            assert_eq!(json["error"]["kind"], "decode_receipt");
+        }
+
+        let allowlist = ImageAllowlist::new(vec![Digest::default()]);
+        let mut rejected = 0;
+        run(&jobs, 2, Some(&allowlist), |_, report| {
+            assert_eq!(report.exit_code(), 15);
+            rejected += 1;
+        });
+        assert_eq!(rejected, 3);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_jobs() {
+        let dir = temp_dir("manifest");
+        let manifest = dir.join("manifest.jsonl");
+        fs::write(
+            &manifest,
+            concat!(
+                r#"{"image_id": [1, 2, 3, 4, 5, 6, 7, 8], "receipt": "a.dat", "input_value": 6}"#,
+                "\n\n",
+                r#"{"image_id": "[8, 7, 6, 5, 4, 3, 2, 1]", "receipt": "/tmp/b.dat"}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            jobs_from_manifest(&manifest).unwrap(),
+            vec![
+                Job {
+                    image_id: Digest::new([1, 2, 3, 4, 5, 6, 7, 8]),
+                    receipt: dir.join("a.dat"),
+                    input_value: Some(6),
+                },
+                Job {
+                    image_id: Digest::new([8, 7, 6, 5, 4, 3, 2, 1]),
+                    receipt: PathBuf::from("/tmp/b.dat"),
+                    input_value: None,
+                },
+            ]
+        );
+
+        fs::write(&manifest, r#"{"image_id": [1, 2], "receipt": "a.dat"}"#).unwrap();
+        assert!(matches!(
+            jobs_from_manifest(&manifest),
+            Err(BatchError::InvalidManifest { line: 1, .. })
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,460 @@
+use collatz_core::{value_to_string, ImageAllowlist, Journal, Outcome, Value};
+use risc0_zkvm::{
+    sha::Digest, ExitCode, ReceiptMetadata, SessionFlatReceipt, SessionReceipt, SystemState,
+    VerificationError,
+};
+use serde_json::{json, Value as Json};
+use std::fmt;
+use std::path::Path;
+
+pub mod batch;
+pub mod server;
+
+/// Why a receipt was not accepted. Each variant maps to its own process exit
+/// code, see [Failure::exit_code].
+#[derive(Debug)]
+pub enum Failure {
+    /// The receipt file could not be read.
+    ReadReceipt(std::io::Error),
+
+    /// The image ID is not a JSON array of eight `u32`s.
+    InvalidImageId(String),
+
+    /// The image ID is not on the allowlist of published guest builds.
+    UnlistedImageId(Digest),
+
+    /// The receipt bytes are not a serialized [SessionFlatReceipt].
+    DecodeReceipt(String),
+
+    /// The receipt did not verify against the image ID.
+    Verification(VerificationError),
+
+    /// The receipt verified but its journal is not a Collatz [Journal].
+    DecodeJournal(String),
+
+    /// The journal is for a different starting value than claimed.
+    InputMismatch { claimed: u128, actual: u128 },
+
+    /// The journal relies on a sieve other than `Sieve::new(sieve_k)`, so
+    /// the starting values it skipped are not covered.
+    InvalidSieve { sieve_k: u32 },
+}
+
+impl Failure {
+    /// The process exit code reporting this failure. Codes 1 and 2 are left
+    /// to panics and command line usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::ReadReceipt(_) => 10,
+            Failure::InvalidImageId(_) => 11,
+            Failure::DecodeReceipt(_) => 12,
+            Failure::DecodeJournal(_) => 13,
+            Failure::InputMismatch { .. } => 14,
+            Failure::UnlistedImageId(_) => 15,
+            Failure::InvalidSieve { .. } => 16,
+            Failure::Verification(err) => match err {
+                VerificationError::ImageVerificationError => 20,
+                VerificationError::ReceiptFormatError => 21,
+                VerificationError::ControlVerificationError => 22,
+                VerificationError::MerkleQueryOutOfRange { .. } => 23,
+                VerificationError::InvalidProof => 24,
+                VerificationError::JournalDigestMismatch => 25,
+                VerificationError::UnexpectedExitCode => 26,
+            },
+        }
+    }
+
+    /// A stable, machine-readable name for this failure.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Failure::ReadReceipt(_) => "read_receipt",
+            Failure::InvalidImageId(_) => "invalid_image_id",
+            Failure::DecodeReceipt(_) => "decode_receipt",
+            Failure::DecodeJournal(_) => "decode_journal",
+            Failure::InputMismatch { .. } => "input_mismatch",
+            Failure::UnlistedImageId(_) => "unlisted_image_id",
+            Failure::InvalidSieve { .. } => "invalid_sieve",
+            Failure::Verification(err) => match err {
+                VerificationError::ImageVerificationError => "wrong_image_id",
+                VerificationError::ReceiptFormatError => "receipt_format",
+                VerificationError::ControlVerificationError => "control_id_mismatch",
+                VerificationError::MerkleQueryOutOfRange { .. } => "merkle_query_out_of_range",
+                VerificationError::InvalidProof => "invalid_proof",
+                VerificationError::JournalDigestMismatch => "journal_digest_mismatch",
+                VerificationError::UnexpectedExitCode => "unexpected_exit_code",
+            },
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::ReadReceipt(err) => write!(f, "Failed to read receipt: {err}"),
+            Failure::InvalidImageId(err) => write!(f, "Invalid image ID: {err}"),
+            Failure::UnlistedImageId(image_id) => {
+                write!(f, "Image ID {image_id} is not an accepted build")
+            }
+            Failure::DecodeReceipt(err) => write!(f, "Failed to decode receipt: {err}"),
+            Failure::Verification(err) => write!(f, "Verification failed: {err}"),
+            Failure::DecodeJournal(err) => write!(f, "Failed to decode journal: {err}"),
+            Failure::InputMismatch { claimed, actual } => write!(
+                f,
+                "Receipt is for input value {actual}, not the claimed {claimed}"
+            ),
+            Failure::InvalidSieve { sieve_k } => {
+                write!(f, "Journal does not commit to the 2^{sieve_k} sieve")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// The outcome of verifying one receipt, rendered by [Report::to_json].
+pub struct Report {
+    pub image_id: Option<Digest>,
+    pub receipt: Option<SessionFlatReceipt>,
+    pub journal: Option<Journal>,
+    pub failure: Option<Failure>,
+}
+
+impl Report {
+    /// A report for a receipt that was rejected before it could be decoded.
+    pub fn failed(failure: Failure) -> Self {
+        Report {
+            image_id: None,
+            receipt: None,
+            journal: None,
+            failure: Some(failure),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.failure.as_ref().map_or(0, Failure::exit_code)
+    }
+
+    /// Render the report as JSON. This is the contract with the API backend:
+    /// fields may be added but are never renamed or removed.
+    pub fn to_json(&self) -> Json {
+        let segments = self.receipt.as_ref().map(|receipt| {
+            receipt
+                .segments
+                .iter()
+                .map(|segment| {
+                    json!({
+                        "index": segment.index,
+                        "seal_size": segment.get_seal_bytes().len(),
+                        "metadata": segment.get_metadata().ok().as_ref().map(metadata_to_json),
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+        json!({
+            "verified": self.failure.is_none(),
+            "error": self.failure.as_ref().map(|failure| json!({
+                "kind": failure.kind(),
+                "exit_code": failure.exit_code(),
+                "message": failure.to_string(),
+            })),
+            "image_id": self.image_id.map(|digest| digest.to_string()),
+            "segment_count": self.receipt.as_ref().map(|receipt| receipt.segments.len()),
+            "seal_size": self.receipt.as_ref().map(|receipt| receipt.get_seal_len()),
+            "segments": segments,
+            "journal": self.journal.as_ref().map(journal_to_json),
+        })
+    }
+}
+
+/// Parse an image ID given as a JSON array of eight `u32`s, the form the
+/// Collatz host uploads.
+pub fn parse_image_id(image_id: &str) -> Result<Digest, Failure> {
+    let words: [u32; 8] =
+        serde_json::from_str(image_id).map_err(|err| Failure::InvalidImageId(err.to_string()))?;
+    Ok(Digest::new(words))
+}
+
+/// Reject `image_id` if an `allowlist` is given and does not list it. The
+/// image ID a receipt comes with says nothing about which guest it proves,
+/// so it must be one of the published builds.
+pub fn check_allowlist(
+    allowlist: Option<&ImageAllowlist>,
+    image_id: Digest,
+) -> Result<(), Box<Report>> {
+    match allowlist {
+        Some(allowlist) if !allowlist.contains(&image_id) => Err(Box::new(Report {
+            image_id: Some(image_id),
+            ..Report::failed(Failure::UnlistedImageId(image_id))
+        })),
+        _ => Ok(()),
+    }
+}
+
+/// Decode a receipt as written by [SessionReceipt::encode].
+pub fn decode_receipt(data: &[u8]) -> Result<SessionFlatReceipt, Failure> {
+    collatz_core::decode_receipt(data).map_err(|err| Failure::DecodeReceipt(err.to_string()))
+}
+
+pub fn extract_result_from_receipt(receipt: &SessionFlatReceipt) -> Result<Journal, Failure> {
+    risc0_zkvm::serde::from_slice(receipt.get_journal())
+        .map_err(|err| Failure::DecodeJournal(err.to_string()))
+}
+
+/// Reject a sieved range whose sieve digest is not that of the table its
+/// `sieve_k` names.
+pub fn check_sieve(journal: &Journal) -> Result<(), Failure> {
+    match journal {
+        Journal::SieveRangeV1(summary) if !summary.has_valid_sieve() => {
+            Err(Failure::InvalidSieve {
+                sieve_k: summary.sieve_k,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Verify `data` against `image_id` and decode its journal, checking it is
+/// for `input_value` if one is claimed and that any sieve it used is sound.
+pub fn verify_receipt(image_id: Digest, data: &[u8], input_value: Option<u128>) -> Report {
+    let mut report = Report {
+        image_id: Some(image_id),
+        receipt: None,
+        journal: None,
+        failure: None,
+    };
+
+    let receipt = match decode_receipt(data) {
+        Ok(receipt) => report.receipt.insert(receipt),
+        Err(failure) => {
+            report.failure = Some(failure);
+            return report;
+        }
+    };
+
+    if let Err(err) = receipt.verify(image_id) {
+        report.failure = Some(Failure::Verification(err));
+        return report;
+    }
+
+    match extract_result_from_receipt(receipt) {
+        Ok(journal) => {
+            if let Some(claimed) = input_value {
+                if journal.start() != claimed {
+                    report.failure = Some(Failure::InputMismatch {
+                        claimed,
+                        actual: journal.start(),
+                    });
+                }
+            }
+            if let Err(failure) = check_sieve(&journal) {
+                report.failure.get_or_insert(failure);
+            }
+            report.journal = Some(journal);
+        }
+        Err(failure) => report.failure = Some(failure),
+    }
+
+    report
+}
+
+/// Read the receipt at `path` and verify it with [verify_receipt].
+pub fn verify_file(image_id: Digest, path: &Path, input_value: Option<u128>) -> Report {
+    match std::fs::read(path) {
+        Ok(data) => verify_receipt(image_id, &data, input_value),
+        Err(err) => Report {
+            image_id: Some(image_id),
+            ..Report::failed(Failure::ReadReceipt(err))
+        },
+    }
+}
+
+fn system_state_to_json(state: &SystemState) -> Json {
+    json!({
+        "pc": state.pc,
+        "merkle_root": state.merkle_root.to_string(),
+    })
+}
+
+fn exit_code_to_json(exit_code: &ExitCode) -> Json {
+    match exit_code {
+        ExitCode::SystemSplit => json!({ "kind": "system_split" }),
+        ExitCode::SessionLimit => json!({ "kind": "session_limit" }),
+        ExitCode::Paused(code) => json!({ "kind": "paused", "code": code }),
+        ExitCode::Halted(code) => json!({ "kind": "halted", "code": code }),
+    }
+}
+
+fn metadata_to_json(metadata: &ReceiptMetadata) -> Json {
+    json!({
+        "pre": system_state_to_json(&metadata.pre),
+        "post": system_state_to_json(&metadata.post),
+        "exit_code": exit_code_to_json(&metadata.exit_code),
+        "input": metadata.input.to_string(),
+        "output": metadata.output.to_string(),
+    })
+}
+
+/// Render a journal as JSON. Trajectory values can exceed what JSON consumers
+/// handle as numbers, so they are written as decimal strings.
+pub fn journal_to_json(journal: &Journal) -> Json {
+    let value = |v: &Value| Json::String(value_to_string(v));
+    let outcome = |outcome: &Outcome| match outcome {
+        Outcome::ReachedOne => json!({ "kind": "reached_one" }),
+        Outcome::NontrivialCycle { members } => json!({
+            "kind": "nontrivial_cycle",
+            "members": members.iter().map(value).collect::<Vec<_>>(),
+        }),
+        Outcome::StepBoundExceeded { last_value } => json!({
+            "kind": "step_bound_exceeded",
+            "last_value": value(last_value),
+        }),
+        Outcome::Overflow { last_value } => json!({
+            "kind": "overflow",
+            "last_value": value(last_value),
+        }),
+    };
+    match journal {
+        Journal::TrajectoryV1(trajectory) => json!({
+            "kind": "trajectory",
+            "version": 1,
+            "start": trajectory.start.to_string(),
+            "contributor": trajectory.contributor,
+            "step_bound": trajectory.step_bound,
+            "outcome": outcome(&trajectory.outcome),
+            "sequence": trajectory
+                .sequence
+                .as_ref()
+                .map(|sequence| sequence.iter().map(value).collect::<Vec<_>>()),
+            "stats": trajectory.stats.as_ref().map(|stats| json!({
+                "total_stopping_time": stats.total_stopping_time,
+                "glide": stats.glide,
+                "max_value": value(&stats.max_value),
+                "parity_vector_hash": stats.parity_vector_hash.to_string(),
+            })),
+        }),
+        Journal::RangeV1(summary) => json!({
+            "kind": "range",
+            "version": 1,
+            "start": summary.start.to_string(),
+            "end": summary.end.to_string(),
+            "contributor": summary.contributor,
+            "step_bound": summary.step_bound,
+            "max_stopping_time": summary.max_stopping_time,
+            "max_stopping_time_arg": summary.max_stopping_time_arg.to_string(),
+            "max_excursion": value(&summary.max_excursion),
+            "max_excursion_arg": summary.max_excursion_arg.to_string(),
+            "all_reached_one": summary.all_reached_one,
+            "counterexample": summary.counterexample.as_ref().map(|counterexample| json!({
+                "start": counterexample.start.to_string(),
+                "outcome": outcome(&counterexample.outcome),
+            })),
+        }),
+        Journal::SieveRangeV1(summary) => json!({
+            "kind": "sieve_range",
+            "version": 1,
+            "start": summary.start.to_string(),
+            "end": summary.end.to_string(),
+            "contributor": summary.contributor,
+            "step_bound": summary.step_bound,
+            "sieve_k": summary.sieve_k,
+            "sieve_digest": summary.sieve_digest.to_string(),
+            "walked": summary.walked,
+            "max_glide": summary.max_glide,
+            "max_glide_arg": summary.max_glide_arg.to_string(),
+            "all_dropped": summary.all_dropped,
+            "counterexample": summary.counterexample.as_ref().map(|counterexample| json!({
+                "start": counterexample.start.to_string(),
+                "outcome": outcome(&counterexample.outcome),
+            })),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use collatz_core::{Sieve, SieveSummary, DEFAULT_STEP_BOUND};
+
+    use super::*;
+
+    const IMAGE_ID: &str = "[1, 2, 3, 4, 5, 6, 7, 8]";
+
+    #[test]
+    fn image_id() {
+        assert_eq!(
+            parse_image_id(IMAGE_ID).unwrap(),
+            Digest::new([1, 2, 3, 4, 5, 6, 7, 8])
+        );
+        assert_eq!(parse_image_id("[1, 2]").unwrap_err().exit_code(), 11);
+        assert_eq!(parse_image_id("deadbeef").unwrap_err().exit_code(), 11);
+    }
+
+    #[test]
+    fn allowlist() {
+        let image_id = parse_image_id(IMAGE_ID).unwrap();
+        assert!(check_allowlist(None, image_id).is_ok());
+        let allowlist = ImageAllowlist::new(vec![image_id]);
+        assert!(check_allowlist(Some(&allowlist), image_id).is_ok());
+
+        let report = check_allowlist(Some(&allowlist), Digest::default()).unwrap_err();
+        assert_eq!(report.exit_code(), 15);
+        let json = report.to_json();
+        assert_eq!(json["error"]["kind"], "unlisted_image_id");
+        assert_eq!(json["image_id"], Digest::default().to_string());
+    }
+
+    #[test]
+    fn sieve() {
+        let sieve = Sieve::new(8);
+        let mut summary = SieveSummary {
+            start: 1 << 10,
+            end: 1 << 11,
+            contributor: None,
+            step_bound: DEFAULT_STEP_BOUND,
+            sieve_k: 8,
+            sieve_digest: sieve.digest(),
+            walked: 0,
+            max_glide: 0,
+            max_glide_arg: 0,
+            all_dropped: true,
+            counterexample: None,
+        };
+        assert!(check_sieve(&Journal::SieveRangeV1(summary.clone())).is_ok());
+
+        summary.sieve_digest = Digest::default();
+        let failure = check_sieve(&Journal::SieveRangeV1(summary)).unwrap_err();
+        assert_eq!(failure.exit_code(), 16);
+        assert_eq!(failure.kind(), "invalid_sieve");
+    }
+
+    #[test]
+    fn undecodable_receipt() {
+        let image_id = parse_image_id(IMAGE_ID).unwrap();
+
+        // The checked-in fixture is empty.
+        let data = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/receipt.dat")).unwrap();
+        let report = verify_receipt(image_id, &data, None);
+        assert_eq!(report.exit_code(), 12);
+
+        let report = verify_receipt(image_id, &[0; 7], Some(6));
+        assert_eq!(report.exit_code(), 12);
+        let json = report.to_json();
+        assert_eq!(json["verified"], false);
+        assert_eq!(json["error"]["kind"], "decode_receipt");
+        assert_eq!(json["image_id"], image_id.to_string());
+        assert!(json["journal"].is_null());
+    }
+
+    #[test]
+    fn empty_receipt() {
+        let receipt = SessionFlatReceipt {
+            segments: vec![],
+            journal: vec![],
+        };
+        let report = verify_receipt(Digest::default(), &receipt.encode(), None);
+        assert_eq!(report.exit_code(), 21);
+        let json = report.to_json();
+        assert_eq!(json["error"]["kind"], "receipt_format");
+        assert_eq!(json["segment_count"], 0);
+        assert_eq!(json["seal_size"], 0);
+    }
+}
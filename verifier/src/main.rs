@@ -1,44 +1,193 @@
-use serde_json;
-use clap::Parser;
-use risc0_zkvm::SessionReceipt;
-use std::path::Path;
+use clap::{Parser, Subcommand};
+use collatz_core::ImageAllowlist;
+use risc0_zkvm::sha::Digest;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use verifier::batch::{self, BatchError};
+use verifier::server::{self, Config};
+use verifier::{check_allowlist, parse_image_id, verify_file, Report};
 
 #[derive(Parser)]
 #[clap(about, version, author)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    image_id: String,  // should be a bytearray (Vec[u8]) instead?
-    receipt_file: String,  // receipt.dat
+    /// Image ID as a JSON array of eight u32s, e.g. "[1, 2, 3, 4, 5, 6, 7, 8]".
+    #[arg(required = true)]
+    image_id: Option<String>,
+
+    /// Receipt as written by the Collatz host, e.g. receipt.dat.
+    #[arg(required = true)]
+    receipt_file: Option<PathBuf>,
+
+    /// Starting value the receipt is claimed to be for; verification fails if
+    /// the journal commits to a different one.
+    #[clap(long)]
+    input_value: Option<u128>,
+
+    /// File listing the hex image IDs of published guest builds, one per
+    /// line. Receipts for any other image ID are rejected.
+    #[clap(long)]
+    allowlist: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn load_receipt(p: &Path) -> SessionReceipt {
-    let data = std::fs::read(p).unwrap();
-    risc0_zkvm::serde::from_slice(&data).unwrap()
+#[derive(Subcommand)]
+enum Command {
+    /// Verify many receipts in parallel, printing one JSON report per line.
+    ///
+    /// Exits with 0 if every receipt verified and 3 otherwise.
+    Batch {
+        /// A directory of .dat receipts, or with --manifest a JSON Lines file
+        /// of {"image_id", "receipt", "input_value"} entries.
+        path: PathBuf,
+
+        /// Image ID for every receipt in the directory.
+        #[arg(long, required_unless_present = "manifest")]
+        image_id: Option<String>,
+
+        /// Treat `path` as a manifest rather than a directory.
+        #[arg(long)]
+        manifest: bool,
+
+        /// Number of receipts to verify at once. Defaults to the number of
+        /// cores.
+        #[arg(long)]
+        jobs: Option<usize>,
+
+        /// File listing the hex image IDs to accept.
+        #[arg(long)]
+        allowlist: Option<PathBuf>,
+    },
+
+    /// Serve POST /verify and GET /metrics over HTTP.
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8001")]
+        addr: String,
+
+        /// Number of requests verified at once. Defaults to the number of
+        /// cores.
+        #[arg(long)]
+        workers: Option<usize>,
+
+        /// Largest receipt accepted, in bytes.
+        #[arg(long, default_value_t = 64 << 20)]
+        max_receipt_size: usize,
+
+        /// Image ID for raw receipts posted without one.
+        #[arg(long)]
+        image_id: Option<String>,
+
+        /// File listing the hex image IDs to accept.
+        #[arg(long)]
+        allowlist: Option<PathBuf>,
+    },
 }
 
-fn extract_result_from_receipt() {
-    //
+fn available_parallelism() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
 }
 
-/// When called from Command Line returns error code 0 if verified, otherwise panics.
+/// Verify a receipt and print a JSON report on stdout.
+///
+/// Exits with 0 if the receipt verified, otherwise with the code of the
+/// [verifier::Failure] that rejected it. The report is printed either way.
 fn main() {
-
     let args = Args::parse();
 
-    // Deserialize image_id
-    let image_id: [u32; 8] = serde_json::from_str(&args.image_id).expect("Failed to decode");
-    println!("Image ID (program hash): {:?}", image_id);
+    match args.command {
+        Some(Command::Batch {
+            path,
+            image_id,
+            manifest,
+            jobs,
+            allowlist,
+        }) => verify_batch(
+            path,
+            image_id,
+            manifest,
+            jobs,
+            allowlist.as_deref().map(allowlist_or_exit),
+        ),
+        Some(Command::Serve {
+            addr,
+            workers,
+            max_receipt_size,
+            image_id,
+            allowlist,
+        }) => {
+            let config = Config {
+                addr,
+                workers: workers.unwrap_or_else(available_parallelism),
+                max_receipt_size,
+                image_id: image_id.as_deref().map(image_id_or_exit),
+                allowlist: allowlist.as_deref().map(allowlist_or_exit),
+            };
+            if let Err(err) = server::serve(config) {
+                eprintln!("{err}");
+                process::exit(1);
+            }
+        }
+        None => {
+            let allowlist = args.allowlist.as_deref().map(allowlist_or_exit);
+            let report = match parse_image_id(&args.image_id.unwrap()) {
+                Ok(image_id) => match check_allowlist(allowlist.as_ref(), image_id) {
+                    Ok(()) => verify_file(image_id, &args.receipt_file.unwrap(), args.input_value),
+                    Err(report) => *report,
+                },
+                Err(failure) => Report::failed(failure),
+            };
 
-    // Convert image_id to a Digest accepted by receipt.verify()
-    let digest = risc0_zkvm::sha::Digest::new(image_id);
+            println!("{}", report.to_json());
+            process::exit(report.exit_code());
+        }
+    }
+}
+
+fn verify_batch(
+    path: PathBuf,
+    image_id: Option<String>,
+    manifest: bool,
+    jobs: Option<usize>,
+    allowlist: Option<ImageAllowlist>,
+) {
+    let batch_jobs = if manifest {
+        batch::jobs_from_manifest(&path)
+    } else {
+        batch::jobs_from_dir(&path, image_id_or_exit(&image_id.unwrap()))
+    }
+    .unwrap_or_else(|err: BatchError| {
+        eprintln!("{err}");
+        process::exit(2);
+    });
 
-    // Load the receipt from file
-    let receipt = load_receipt(Path::new(&args.receipt_file));
+    let threads = jobs.unwrap_or_else(available_parallelism);
+    let mut all_verified = true;
+    let mut stdout = std::io::stdout().lock();
+    batch::run(&batch_jobs, threads, allowlist.as_ref(), |job, report| {
+        all_verified &= report.failure.is_none();
+        writeln!(stdout, "{}", batch::to_json_line(job, &report)).unwrap();
+    });
+    process::exit(if all_verified { 0 } else { 3 });
+}
 
-    // Verify receipt
-    receipt.verify(image_id).unwrap();
+fn allowlist_or_exit(path: &Path) -> ImageAllowlist {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("Failed to read {}: {err}", path.display());
+        process::exit(2);
+    });
+    text.parse().unwrap_or_else(|err| {
+        eprintln!("Invalid allowlist {}: {err}", path.display());
+        process::exit(2);
+    })
+}
 
-    // Retrieve the sequence that was computed and write it to stdout
-    // TODO: this fails with ^^^^ expected `Vec<u32>`, found `Result<_, Error>`
-    // let sequence: Vec<u32> = risc0_zkvm::serde::from_slice(&receipt.journal);
-    // println!("Sequence: {:?}", sequence);
+fn image_id_or_exit(image_id: &str) -> Digest {
+    parse_image_id(image_id).unwrap_or_else(|failure| {
+        eprintln!("{failure}");
+        process::exit(failure.exit_code());
+    })
 }
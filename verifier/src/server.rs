@@ -0,0 +1,420 @@
+//! A long-running HTTP verifier, so that the API does not have to spawn a
+//! process per upload.
+//!
+//! `POST /verify` takes either the JSON `Output` uploaded by the Collatz host
+//! or raw receipt bytes, with the image ID and claimed input value in the
+//! query string. It responds with the same JSON report as the command line.
+//! `GET /metrics` serves Prometheus counters.
+
+use crate::{check_allowlist, parse_image_id, verify_receipt, Failure, Report};
+use collatz_core::ImageAllowlist;
+use risc0_zkvm::sha::Digest;
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Response, Server};
+
+pub struct Config {
+    pub addr: String,
+
+    /// Number of requests handled at once. Further requests wait in the
+    /// listener's queue.
+    pub workers: usize,
+
+    /// Largest request body accepted, in bytes.
+    pub max_receipt_size: usize,
+
+    /// Image ID for raw receipts that do not name one.
+    pub image_id: Option<Digest>,
+
+    /// If given, receipts for any other image ID are rejected.
+    pub allowlist: Option<ImageAllowlist>,
+}
+
+/// Counters served on `GET /metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    verified: AtomicU64,
+    bad_requests: AtomicU64,
+    too_large: AtomicU64,
+    rejected: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    fn record(&self, outcome: &Outcome) {
+        let counter = match outcome {
+            Outcome::Report(report) => match &report.failure {
+                None => &self.verified,
+                Some(failure) => {
+                    *self.rejected.lock().unwrap().entry(failure.kind()).or_default() += 1;
+                    return;
+                }
+            },
+            Outcome::BadRequest(_) => &self.bad_requests,
+            Outcome::TooLarge => &self.too_large,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, samples: &[(String, u64)]| {
+            out += &format!("# HELP {name} {help}\n# TYPE {name} counter\n");
+            for (labels, value) in samples {
+                out += &format!("{name}{labels} {value}\n");
+            }
+        };
+        let load = |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed))];
+
+        counter(
+            "verifier_requests_total",
+            "Verification requests received.",
+            &load(&self.requests),
+        );
+        counter(
+            "verifier_verified_total",
+            "Receipts that verified.",
+            &load(&self.verified),
+        );
+        let rejected: Vec<_> = self
+            .rejected
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(kind, value)| (format!("{{kind=\"{kind}\"}}"), *value))
+            .collect();
+        counter(
+            "verifier_rejected_total",
+            "Receipts that did not verify, by failure kind.",
+            &rejected,
+        );
+        counter(
+            "verifier_bad_requests_total",
+            "Requests that did not contain a receipt.",
+            &load(&self.bad_requests),
+        );
+        counter(
+            "verifier_too_large_total",
+            "Requests rejected for exceeding the maximum receipt size.",
+            &load(&self.too_large),
+        );
+        out
+    }
+}
+
+/// The result of a `POST /verify`.
+enum Outcome {
+    Report(Box<Report>),
+    BadRequest(String),
+    TooLarge,
+}
+
+impl Outcome {
+    fn status(&self) -> u16 {
+        match self {
+            Outcome::Report(report) if report.failure.is_none() => 200,
+            Outcome::Report(_) => 422,
+            Outcome::BadRequest(_) => 400,
+            Outcome::TooLarge => 413,
+        }
+    }
+
+    fn to_json(&self, max_receipt_size: usize) -> Json {
+        match self {
+            Outcome::Report(report) => report.to_json(),
+            Outcome::BadRequest(message) => json!({ "verified": false, "message": message }),
+            Outcome::TooLarge => json!({
+                "verified": false,
+                "message": format!("Receipt exceeds {max_receipt_size} bytes"),
+            }),
+        }
+    }
+}
+
+impl From<Failure> for Outcome {
+    fn from(failure: Failure) -> Self {
+        Outcome::Report(Box::new(Report::failed(failure)))
+    }
+}
+
+/// The fields of the Collatz host's `Output` needed to verify it.
+#[derive(Deserialize)]
+struct Upload {
+    input_value: u128,
+    proof: Vec<u8>,
+    image_id: [u32; 8],
+}
+
+/// Serve until the listener fails.
+pub fn serve(config: Config) -> io::Result<()> {
+    let server =
+        Server::http(&config.addr).map_err(io::Error::other)?;
+    eprintln!("Listening on {}", server.server_addr());
+    run(server, config, Arc::new(Metrics::default()))
+}
+
+fn run(server: Server, config: Config, metrics: Arc<Metrics>) -> io::Result<()> {
+    let server = Arc::new(server);
+    let config = Arc::new(config);
+    let workers: Vec<_> = (0..config.workers.max(1))
+        .map(|_| {
+            let (server, config, metrics) = (server.clone(), config.clone(), metrics.clone());
+            thread::spawn(move || -> io::Result<()> {
+                loop {
+                    let request = server.recv()?;
+                    if let Err(err) = handle(request, &config, &metrics) {
+                        eprintln!("Failed to respond: {err}");
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+    Ok(())
+}
+
+fn handle(mut request: tiny_http::Request, config: &Config, metrics: &Metrics) -> io::Result<()> {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let json_header = Header::from_bytes("Content-Type", "application/json").unwrap();
+
+    match (method, path) {
+        (Method::Post, "/verify") => {
+            metrics.requests.fetch_add(1, Ordering::Relaxed);
+            let outcome = match read_body(&mut request, config.max_receipt_size) {
+                Ok(body) => {
+                    let is_json = request.headers().iter().any(|header| {
+                        header.field.equiv("Content-Type")
+                            && header.value.as_str().starts_with("application/json")
+                    });
+                    verify_body(is_json, query, &body, config)
+                }
+                Err(outcome) => outcome,
+            };
+            metrics.record(&outcome);
+            let body = outcome.to_json(config.max_receipt_size).to_string();
+            request.respond(
+                Response::from_string(body)
+                    .with_status_code(outcome.status())
+                    .with_header(json_header),
+            )
+        }
+        (Method::Get, "/metrics") => request.respond(Response::from_string(metrics.render())),
+        (_, "/verify" | "/metrics") => request.respond(Response::empty(405)),
+        _ => request.respond(Response::empty(404)),
+    }
+}
+
+/// Read the request body, refusing anything over `max` bytes without reading
+/// all of it.
+fn read_body(request: &mut tiny_http::Request, max: usize) -> Result<Vec<u8>, Outcome> {
+    if request.body_length().is_some_and(|len| len > max) {
+        return Err(Outcome::TooLarge);
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| Outcome::BadRequest(err.to_string()))?;
+    if body.len() > max {
+        return Err(Outcome::TooLarge);
+    }
+    Ok(body)
+}
+
+fn verify_body(is_json: bool, query: &str, body: &[u8], config: &Config) -> Outcome {
+    if is_json {
+        return match serde_json::from_slice::<Upload>(body) {
+            Ok(upload) => {
+                if upload.proof.len() > config.max_receipt_size {
+                    return Outcome::TooLarge;
+                }
+                let image_id = Digest::new(upload.image_id);
+                if let Err(report) = check_allowlist(config.allowlist.as_ref(), image_id) {
+                    return Outcome::Report(report);
+                }
+                Outcome::Report(Box::new(verify_receipt(
+                    image_id,
+                    &upload.proof,
+                    Some(upload.input_value),
+                )))
+            }
+            Err(err) => Outcome::BadRequest(format!("Invalid upload: {err}")),
+        };
+    }
+
+    let params: BTreeMap<_, _> = query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .collect();
+    let image_id = match params.get("image_id") {
+        Some(image_id) => match decode_param(image_id).map(|image_id| parse_image_id(&image_id)) {
+            Some(Ok(image_id)) => image_id,
+            Some(Err(failure)) => return failure.into(),
+            None => return Outcome::BadRequest("Invalid image_id encoding".to_string()),
+        },
+        None => match config.image_id {
+            Some(image_id) => image_id,
+            None => return Outcome::BadRequest("Missing image_id".to_string()),
+        },
+    };
+    let input_value = match params.get("input_value").map(|n| n.parse()) {
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => return Outcome::BadRequest("Invalid input_value".to_string()),
+        None => None,
+    };
+    if let Err(report) = check_allowlist(config.allowlist.as_ref(), image_id) {
+        return Outcome::Report(report);
+    }
+    Outcome::Report(Box::new(verify_receipt(image_id, body, input_value)))
+}
+
+/// Percent-decode a query parameter. Image IDs are JSON arrays, so their
+/// brackets, commas and spaces arrive escaped.
+fn decode_param(param: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = param.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'%' if tail.len() >= 2 => {
+                let hex = std::str::from_utf8(&tail[..2]).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            }
+            b'%' => return None,
+            b'+' => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            addr: "127.0.0.1:0".to_string(),
+            workers: 2,
+            max_receipt_size: 1024,
+            image_id: None,
+            allowlist: None,
+        }
+    }
+
+    fn fixture() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/receipt.dat")).unwrap()
+    }
+
+    #[test]
+    fn raw_receipt() {
+        let query = "image_id=%5B1%2C2%2C3%2C4%2C5%2C6%2C7%2C8%5D&input_value=6";
+        let outcome = verify_body(false, query, &fixture(), &config());
+        assert_eq!(outcome.status(), 422);
+        let json = outcome.to_json(1024);
+        assert_eq!(json["error"]["kind"], "decode_receipt");
+        assert_eq!(json["image_id"], Digest::new([1, 2, 3, 4, 5, 6, 7, 8]).to_string());
+
+        assert_eq!(verify_body(false, "", &fixture(), &config()).status(), 400);
+        assert_eq!(
+            verify_body(false, "image_id=%5B1%5D", &fixture(), &config()).status(),
+            422
+        );
+        let config = Config {
+            image_id: Some(Digest::default()),
+            ..config()
+        };
+        assert_eq!(verify_body(false, "", &fixture(), &config).status(), 422);
+
+        let config = Config {
+            allowlist: Some(ImageAllowlist::new(vec![Digest::default()])),
+            ..config
+        };
+        let outcome = verify_body(false, query, &fixture(), &config);
+        assert_eq!(outcome.to_json(1024)["error"]["kind"], "unlisted_image_id");
+    }
+
+    #[test]
+    fn uploaded_output() {
+        let upload = json!({
+            "input_value": 6,
+            "contributor": null,
+            "output_sequence": [6, 3, 10, 5, 16, 8, 4, 2, 1],
+            "proof": fixture(),
+            "image_id": [1, 2, 3, 4, 5, 6, 7, 8],
+        });
+        let outcome = verify_body(true, "", upload.to_string().as_bytes(), &config());
+        assert_eq!(outcome.status(), 422);
+
+        let outcome = verify_body(true, "", b"{}", &config());
+        assert_eq!(outcome.status(), 400);
+
+        let listed = Config {
+            allowlist: Some(ImageAllowlist::new(vec![Digest::default()])),
+            ..config()
+        };
+        let outcome = verify_body(true, "", upload.to_string().as_bytes(), &listed);
+        assert_eq!(outcome.status(), 422);
+        assert_eq!(outcome.to_json(1024)["error"]["kind"], "unlisted_image_id");
+
+        let upload = json!({
+            "input_value": 6,
+            "proof": vec![0u8; 2048],
+            "image_id": [1, 2, 3, 4, 5, 6, 7, 8],
+        });
+        let config = Config {
+            max_receipt_size: 1 << 20,
+            ..config()
+        };
+        let outcome = verify_body(true, "", upload.to_string().as_bytes(), &config);
+        assert_eq!(outcome.status(), 422);
+        let config = Config {
+            max_receipt_size: 1024,
+            ..config
+        };
+        let outcome = verify_body(true, "", upload.to_string().as_bytes(), &config);
+        assert_eq!(outcome.status(), 413);
+    }
+
+    #[test]
+    fn metrics() {
+        let metrics = Metrics::default();
+        metrics.requests.fetch_add(3, Ordering::Relaxed);
+        metrics.record(&Outcome::TooLarge);
+        metrics.record(&verify_body(false, "", &fixture(), &config()));
+        metrics.record(&Failure::DecodeReceipt(String::new()).into());
+        metrics.record(&Failure::DecodeReceipt(String::new()).into());
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE verifier_requests_total counter\n"));
+        assert!(text.contains("verifier_requests_total 3\n"));
+        assert!(text.contains("verifier_verified_total 0\n"));
+        assert!(text.contains("verifier_rejected_total{kind=\"decode_receipt\"} 2\n"));
+        assert!(text.contains("verifier_bad_requests_total 1\n"));
+        assert!(text.contains("verifier_too_large_total 1\n"));
+    }
+
+    #[test]
+    fn decode_query() {
+        assert_eq!(decode_param("%5B1%2C+2%5D").as_deref(), Some("[1, 2]"));
+        assert_eq!(decode_param("%5"), None);
+        assert_eq!(decode_param("%zz"), None);
+    }
+}